@@ -0,0 +1,241 @@
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ipc::ChannelSettings;
+
+// How often (in samples) the settings are looked at
+const CHECK_INTERVAL: usize = 1024;
+
+impl ChannelSettings {
+    fn is_neutral(&self) -> bool {
+        self.balance == 0.0 && !self.mono && !self.swap
+    }
+
+    /// Gains for the left and right channel. The side turned away from is
+    /// attenuated, the other stays at full level.
+    fn balance_gains(&self) -> (f32, f32) {
+        let balance = self.balance.clamp(-1.0, 1.0);
+        ((1.0 - balance).min(1.0), (1.0 + balance).min(1.0))
+    }
+}
+
+/// Channel settings shared between the `Player` and the audio thread.
+#[derive(Default)]
+pub struct ChannelShared {
+    settings: Mutex<ChannelSettings>,
+    // Bumped on every change so the audio thread only locks when needed
+    version: AtomicU64,
+}
+
+impl ChannelShared {
+    pub fn settings(&self) -> ChannelSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut ChannelSettings)) {
+        f(&mut self.settings.lock().unwrap());
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Swaps, downmixes and balances stereo sources, in that order, so a mono
+/// mix can still be sent to one side. Other channel layouts pass through.
+pub struct ChannelMix<S> {
+    inner: S,
+    shared: Arc<ChannelShared>,
+    version: u64,
+    settings: ChannelSettings,
+    stereo: bool,
+    sample_rate: u32,
+    channel: usize,
+    // Right sample of the current frame, already processed, waiting to be
+    // handed out after the left one
+    pending: Option<i16>,
+    frame_left: usize,
+    until_check: usize,
+}
+
+impl<S: Source<Item = i16>> ChannelMix<S> {
+    pub fn new(inner: S, shared: Arc<ChannelShared>) -> Self {
+        Self {
+            inner,
+            shared,
+            version: u64::MAX,
+            settings: ChannelSettings::default(),
+            stereo: false,
+            sample_rate: 0,
+            channel: 0,
+            pending: None,
+            frame_left: 0,
+            until_check: 0,
+        }
+    }
+
+    fn check(&mut self) {
+        if self.frame_left == 0 {
+            // Sources without frames never change format
+            self.frame_left = self.inner.current_frame_len().unwrap_or(usize::MAX).max(1);
+            self.stereo = self.inner.channels() == 2;
+            self.sample_rate = self.inner.sample_rate();
+            self.channel = 0;
+        }
+        self.until_check = self.frame_left.min(CHECK_INTERVAL);
+
+        let version = self.shared.version.load(Ordering::Acquire);
+        if version != self.version {
+            self.version = version;
+            self.settings = self.shared.settings();
+        }
+    }
+
+    fn pull(&mut self) -> Option<i16> {
+        if self.until_check == 0 {
+            self.check();
+        }
+        // Counted once it's there, so an empty pull at the end of a source
+        // doesn't throw the frame count off
+        let sample = self.inner.next()?;
+        self.until_check -= 1;
+        self.frame_left -= 1;
+
+        self.channel = (self.channel + 1) % 2;
+        Some(sample)
+    }
+
+    fn process(&self, left: i16, right: i16) -> (i16, i16) {
+        let (mut left, mut right) = (left as f32, right as f32);
+        if self.settings.swap {
+            std::mem::swap(&mut left, &mut right);
+        }
+        if self.settings.mono {
+            let mid = (left + right) / 2.0;
+            (left, right) = (mid, mid);
+        }
+        let (gain_left, gain_right) = self.settings.balance_gains();
+        ((left * gain_left) as i16, (right * gain_right) as i16)
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for ChannelMix<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if let Some(right) = self.pending.take() {
+            return Some(right);
+        }
+
+        let left = self.pull()?;
+        // Pair up the samples of a frame, unless its right half is missing
+        if !self.stereo || self.settings.is_neutral() || self.channel != 1 || self.frame_left == 0 {
+            return Some(left);
+        }
+        let Some(right) = self.pull() else {
+            return Some(left);
+        };
+
+        let (left, right) = self.process(left, right);
+        self.pending = Some(right);
+        Some(left)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.inner.size_hint();
+        let pending = self.pending.is_some() as usize;
+        (lower + pending, upper.map(|upper| upper + pending))
+    }
+}
+
+impl<S: Source<Item = i16>> Source for ChannelMix<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        match self.pending {
+            // The right sample is part of the frame the inner source was in
+            Some(_) if self.frame_left > 0 => self.inner.current_frame_len().map(|len| len + 1),
+            Some(_) => Some(1),
+            None => self.inner.current_frame_len(),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        if self.pending.is_some() {
+            2
+        } else {
+            self.inner.channels()
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        if self.pending.is_some() {
+            self.sample_rate
+        } else {
+            self.inner.sample_rate()
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        // Frames start over at the new position
+        self.pending = None;
+        self.frame_left = 0;
+        self.until_check = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    /// Runs `frames` of (left, right) through a `ChannelMix` with `settings`
+    fn mix(settings: ChannelSettings, frames: &[(i16, i16)]) -> Vec<(i16, i16)> {
+        let samples: Vec<i16> = frames.iter().flat_map(|&(left, right)| [left, right]).collect();
+        let shared = Arc::new(ChannelShared::default());
+        shared.update(|current| *current = settings);
+        let output: Vec<i16> = ChannelMix::new(SamplesBuffer::new(2, 44100, samples), shared).collect();
+        output.chunks(2).map(|frame| (frame[0], frame[1])).collect()
+    }
+
+    const FRAMES: [(i16, i16); 3] = [(1000, -2000), (3000, 0), (-400, 400)];
+
+    #[test]
+    fn neutral_settings_pass_through() {
+        assert_eq!(mix(ChannelSettings::default(), &FRAMES), FRAMES);
+    }
+
+    #[test]
+    fn balance_turns_down_the_other_side() {
+        let left = ChannelSettings { balance: -0.5, ..Default::default() };
+        assert_eq!(mix(left, &FRAMES), [(1000, -1000), (3000, 0), (-400, 200)]);
+        let right = ChannelSettings { balance: 1.0, ..Default::default() };
+        assert_eq!(mix(right, &FRAMES), [(0, -2000), (0, 0), (0, 400)]);
+    }
+
+    #[test]
+    fn mono_averages_and_swap_exchanges_channels() {
+        let mono = ChannelSettings { mono: true, ..Default::default() };
+        assert_eq!(mix(mono, &FRAMES), [(-500, -500), (1500, 1500), (0, 0)]);
+        let swap = ChannelSettings { swap: true, ..Default::default() };
+        assert_eq!(mix(swap, &FRAMES), [(-2000, 1000), (0, 3000), (400, -400)]);
+        // Swapped first, so the mono mix still goes to the side balanced to
+        let all = ChannelSettings { balance: 1.0, mono: true, swap: true };
+        assert_eq!(mix(all, &FRAMES), [(0, -500), (0, 1500), (0, 0)]);
+    }
+
+    #[test]
+    fn mono_sources_pass_through() {
+        let shared = Arc::new(ChannelShared::default());
+        shared.update(|settings| settings.swap = true);
+        let samples = vec![1, 2, 3, 4];
+        let output: Vec<i16> = ChannelMix::new(SamplesBuffer::new(1, 44100, samples.clone()), shared).collect();
+        assert_eq!(output, samples);
+    }
+}
@@ -0,0 +1,604 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::ipc::Chapter;
+
+// The moov atom holds the sample tables, so it grows with the length of
+// the file; anything past this is taken as damaged
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+
+// A chapter track with more samples than this is taken as damaged too
+const MAX_CHAPTERS: usize = 10_000;
+
+// Nero chapter times count 100 ns units
+const CHPL_UNITS_PER_SECOND: f64 = 10_000_000.0;
+
+/// Chapters of an audio file, sorted by start: the chapter track or Nero
+/// `chpl` atom of MP4/M4B files, or the ID3v2 CHAP frames of MP3s. Empty
+/// when the file has none or they can't be read.
+pub fn read(path: &str) -> Vec<Chapter> {
+    let Ok(mut file) = File::open(path) else {
+        return Vec::new();
+    };
+    let mut magic = [0u8; 8];
+    if file.read_exact(&mut magic).is_err() {
+        return Vec::new();
+    }
+
+    let chapters = if &magic[..3] == b"ID3" {
+        read_id3(&mut file)
+    } else if &magic[4..] == b"ftyp" {
+        read_mp4(&mut file)
+    } else {
+        None
+    };
+
+    let mut chapters = chapters.unwrap_or_default();
+    chapters.retain(|chapter| chapter.start.is_finite() && chapter.start >= 0.0);
+    chapters.sort_by(|a, b| a.start.total_cmp(&b.start));
+    for (number, chapter) in chapters.iter_mut().enumerate() {
+        if chapter.title.is_empty() {
+            chapter.title = format!("Chapter {}", number + 1);
+        }
+    }
+    chapters
+}
+
+fn be_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn be_u64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?))
+}
+
+/// Text that is UTF-16 when it starts with a byte order mark, UTF-8
+/// otherwise, up to the first NUL
+fn decode_utf(bytes: &[u8]) -> String {
+    match bytes {
+        [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, u16::from_be_bytes),
+        [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, u16::from_le_bytes),
+        _ => {
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+        }
+    }
+}
+
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| unit([pair[0], pair[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+    String::from_utf16_lossy(&units).trim().to_string()
+}
+
+/// The boxes inside `data`, as type and contents
+fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut found = Vec::new();
+    while data.len() >= 8 {
+        let kind: [u8; 4] = data[4..8].try_into().unwrap();
+        let (size, header) = match be_u32(data).unwrap() {
+            0 => (data.len() as u64, 8),
+            1 => match be_u64(&data[8..]) {
+                Some(size) => (size, 16),
+                None => break,
+            },
+            size => (size as u64, 8),
+        };
+        if size < header || size > data.len() as u64 {
+            break;
+        }
+        found.push((kind, &data[header as usize..size as usize]));
+        data = &data[size as usize..];
+    }
+    found
+}
+
+/// The first box of type `kind` inside `data`
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data).into_iter().find(|(k, _)| k == kind).map(|(_, body)| body)
+}
+
+/// Finds the moov atom and reads its chapters. It's often at the end,
+/// after the audio, so the boxes before it are skipped over.
+fn read_mp4(file: &mut File) -> Option<Vec<Chapter>> {
+    let mut pos = 0;
+    let moov = loop {
+        file.seek(SeekFrom::Start(pos)).ok()?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8]).ok()?;
+        let (size, header_len) = match be_u32(&header)? {
+            1 => {
+                file.read_exact(&mut header[8..]).ok()?;
+                (be_u64(&header[8..])?, 16)
+            }
+            size => (size as u64, 8),
+        };
+        if &header[4..8] == b"moov" {
+            let body_len = size.checked_sub(header_len)?;
+            if body_len > MAX_MOOV_SIZE {
+                return None;
+            }
+            let mut moov = vec![0; body_len as usize];
+            file.read_exact(&mut moov).ok()?;
+            break moov;
+        }
+        // A size of 0 runs to the end of the file
+        if size < header_len {
+            return None;
+        }
+        pos += size;
+    };
+
+    read_chapter_track(file, &moov).or_else(|| read_chpl(&moov))
+}
+
+/// Nero chapters: a count, then a start time and a title for each
+fn read_chpl(moov: &[u8]) -> Option<Vec<Chapter>> {
+    let chpl = child(child(moov, b"udta")?, b"chpl")?;
+    // Version 1 has four more bytes before the count
+    let mut data = chpl.get(if chpl.first()? == &1 { 8 } else { 4 }..)?;
+    let count = *data.first()?;
+    data = &data[1..];
+
+    let mut chapters = Vec::new();
+    for _ in 0..count {
+        let start = be_u64(data)?;
+        let len = *data.get(8)? as usize;
+        let title = data.get(9..9 + len)?;
+        chapters.push(Chapter {
+            title: decode_utf(title),
+            start: start as f64 / CHPL_UNITS_PER_SECOND,
+        });
+        data = &data[9 + len..];
+    }
+    Some(chapters)
+}
+
+/// QuickTime chapters: a text track the audio track points to with a
+/// `chap` reference. Each sample is one chapter title, starting where the
+/// sample does.
+fn read_chapter_track(file: &mut File, moov: &[u8]) -> Option<Vec<Chapter>> {
+    let traks: Vec<&[u8]> = boxes(moov)
+        .into_iter()
+        .filter(|(kind, _)| kind == b"trak")
+        .map(|(_, body)| body)
+        .collect();
+
+    let referenced: Vec<u32> = traks
+        .iter()
+        .filter_map(|trak| child(child(trak, b"tref")?, b"chap"))
+        .flat_map(|chap| chap.chunks_exact(4).filter_map(be_u32))
+        .collect();
+    let trak = traks.into_iter().find(|trak| track_id(trak).is_some_and(|id| referenced.contains(&id)))?;
+
+    let mdia = child(trak, b"mdia")?;
+    let timescale = timescale(child(mdia, b"mdhd")?)?;
+    let stbl = child(child(mdia, b"minf")?, b"stbl")?;
+
+    let mut chapters = Vec::new();
+    let mut start = 0u64;
+    let durations = sample_durations(child(stbl, b"stts")?)?;
+    for (offset, duration) in sample_offsets(stbl)?.into_iter().zip(durations) {
+        chapters.push(Chapter {
+            title: read_text_sample(file, offset).unwrap_or_default(),
+            start: start as f64 / timescale as f64,
+        });
+        start += duration as u64;
+    }
+    Some(chapters)
+}
+
+/// The full box version decides between 32 and 64-bit times in front of
+/// the field wanted
+fn versioned_field(full_box: &[u8], v0: usize, v1: usize) -> Option<u32> {
+    be_u32(full_box.get(if *full_box.first()? == 1 { v1 } else { v0 }..)?)
+}
+
+fn track_id(trak: &[u8]) -> Option<u32> {
+    versioned_field(child(trak, b"tkhd")?, 12, 20)
+}
+
+fn timescale(mdhd: &[u8]) -> Option<u32> {
+    versioned_field(mdhd, 12, 20).filter(|&timescale| timescale > 0)
+}
+
+/// Durations of each sample from the time-to-sample table
+fn sample_durations(stts: &[u8]) -> Option<impl Iterator<Item = u32> + '_> {
+    let count = be_u32(stts.get(4..)?)? as usize;
+    let entries = stts.get(8..)?.chunks_exact(8).take(count);
+    Some(entries.flat_map(|entry| {
+        let (samples, duration) = (be_u32(entry).unwrap(), be_u32(&entry[4..]).unwrap());
+        std::iter::repeat_n(duration, samples as usize)
+    }))
+}
+
+/// File offsets of each sample, from the chunk offsets, the samples in
+/// each chunk and the sample sizes
+fn sample_offsets(stbl: &[u8]) -> Option<Vec<u64>> {
+    let chunks: Vec<u64> = match (child(stbl, b"stco"), child(stbl, b"co64")) {
+        (Some(stco), _) => stco.get(8..)?.chunks_exact(4).filter_map(be_u32).map(u64::from).collect(),
+        (None, Some(co64)) => co64.get(8..)?.chunks_exact(8).filter_map(be_u64).collect(),
+        (None, None) => return None,
+    };
+
+    let stsz = child(stbl, b"stsz")?;
+    let fixed_size = be_u32(stsz.get(4..)?)?;
+    let sample_count = (be_u32(stsz.get(8..)?)? as usize).min(MAX_CHAPTERS);
+    let size = |sample: usize| match fixed_size {
+        0 => be_u32(stsz.get(12 + sample * 4..)?),
+        size => Some(size),
+    };
+
+    // (first chunk, samples per chunk) runs, chunks numbered from 1
+    let stsc: Vec<(usize, usize)> = child(stbl, b"stsc")?
+        .get(8..)?
+        .chunks_exact(12)
+        .map(|entry| (be_u32(entry).unwrap() as usize, be_u32(&entry[4..]).unwrap() as usize))
+        .collect();
+
+    let mut offsets = Vec::new();
+    for (index, &chunk_offset) in chunks.iter().enumerate() {
+        let per_chunk = stsc
+            .iter()
+            .take_while(|(first, _)| *first <= index + 1)
+            .last()
+            .map_or(0, |&(_, samples)| samples);
+        let mut offset = chunk_offset;
+        for _ in 0..per_chunk.min(sample_count - offsets.len()) {
+            offsets.push(offset);
+            offset += size(offsets.len() - 1)? as u64;
+        }
+        if offsets.len() == sample_count {
+            break;
+        }
+    }
+    Some(offsets)
+}
+
+/// A text sample: a 16-bit length, then the text
+fn read_text_sample(file: &mut File, offset: u64) -> Option<String> {
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let mut len = [0u8; 2];
+    file.read_exact(&mut len).ok()?;
+    let mut text = vec![0; u16::from_be_bytes(len) as usize];
+    file.read_exact(&mut text).ok()?;
+    Some(decode_utf(&text))
+}
+
+/// Four bytes of seven bits each
+fn synchsafe(bytes: &[u8]) -> Option<u32> {
+    Some(bytes.get(..4)?.iter().fold(0, |value, &b| (value << 7) | (b & 0x7F) as u32))
+}
+
+/// Undoes unsynchronisation: a zero byte was put after every 0xFF
+fn resync(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if !(b == 0 && i > 0 && data[i - 1] == 0xFF) {
+            out.push(b);
+        }
+    }
+    out
+}
+
+/// The frames in `data` as ID and contents. Compressed and encrypted
+/// frames are left out; chapter frames never need either.
+fn id3_frames(mut data: &[u8], version: u8) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut frames = Vec::new();
+    // Padding starts with a zero byte where the next frame ID would be
+    while data.len() >= 10 && data[0] != 0 {
+        let id: [u8; 4] = data[..4].try_into().unwrap();
+        let size = match version {
+            4 => synchsafe(&data[4..]),
+            _ => be_u32(&data[4..]),
+        }
+        .unwrap() as usize;
+        let Some(mut body) = data.get(10..10 + size) else {
+            break;
+        };
+        let flags = data[9];
+        data = &data[10 + size..];
+
+        let body = match version {
+            4 => {
+                if flags & 0x0C != 0 {
+                    continue;
+                }
+                // Data length indicator
+                if flags & 0x01 != 0 {
+                    body = body.get(4..).unwrap_or_default();
+                }
+                if flags & 0x02 != 0 {
+                    resync(body)
+                } else {
+                    body.to_vec()
+                }
+            }
+            _ => {
+                if flags & 0xC0 != 0 {
+                    continue;
+                }
+                // Group identifier
+                if flags & 0x20 != 0 {
+                    body = body.get(1..).unwrap_or_default();
+                }
+                body.to_vec()
+            }
+        };
+        frames.push((id, body));
+    }
+    frames
+}
+
+/// A text frame: an encoding byte, then the text
+fn id3_text(frame: &[u8]) -> String {
+    let Some((&encoding, text)) = frame.split_first() else {
+        return String::new();
+    };
+    match encoding {
+        // Latin-1
+        0 => text.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect::<String>().trim().to_string(),
+        1 => decode_utf(text),
+        2 => decode_utf16(text, u16::from_be_bytes),
+        _ => decode_utf(text),
+    }
+}
+
+/// A NUL-terminated element ID and what follows it
+fn element_id(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = data.iter().position(|&b| b == 0)?;
+    Some((&data[..end], &data[end + 1..]))
+}
+
+/// CHAP frames give an ID, a start time in milliseconds and a title
+/// subframe. When there is a top-level CTOC, only the chapters it lists
+/// count.
+fn read_id3(file: &mut File) -> Option<Vec<Chapter>> {
+    file.seek(SeekFrom::Start(0)).ok()?;
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header).ok()?;
+    // ID3v2.2 has no chapter frames
+    let version = header[3];
+    if !(3..=4).contains(&version) {
+        return None;
+    }
+    let flags = header[5];
+    // The size can't be trusted to allocate for, so read what the file has
+    let size = (synchsafe(&header[6..])? as u64).min(file.metadata().ok()?.len().saturating_sub(10));
+    let mut tag = Vec::new();
+    file.take(size).read_to_end(&mut tag).ok()?;
+    if version == 3 && flags & 0x80 != 0 {
+        tag = resync(&tag);
+    }
+
+    let mut body = &tag[..];
+    if flags & 0x40 != 0 {
+        let extended = match version {
+            4 => synchsafe(body)? as usize,
+            _ => be_u32(body)? as usize + 4,
+        };
+        body = body.get(extended..)?;
+    }
+
+    let mut chapters = HashMap::new();
+    let mut listed: Option<Vec<Vec<u8>>> = None;
+    for (id, frame) in id3_frames(body, version) {
+        match &id {
+            b"CHAP" => {
+                let Some((element, rest)) = element_id(&frame) else {
+                    continue;
+                };
+                let Some(start) = be_u32(rest) else {
+                    continue;
+                };
+                let title = rest
+                    .get(16..)
+                    .map(|subframes| id3_frames(subframes, version))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|(id, _)| id == b"TIT2")
+                    .map(|(_, text)| id3_text(&text))
+                    .unwrap_or_default();
+                chapters.insert(element.to_vec(), Chapter {
+                    title,
+                    start: start as f64 / 1000.0,
+                });
+            }
+            b"CTOC" => {
+                let Some((_, rest)) = element_id(&frame) else {
+                    continue;
+                };
+                let (Some(&toc_flags), Some(&count)) = (rest.first(), rest.get(1)) else {
+                    continue;
+                };
+                if toc_flags & 0x02 == 0 {
+                    continue;
+                }
+                let mut children = Vec::new();
+                let mut entries = rest.get(2..).unwrap_or_default();
+                for _ in 0..count {
+                    let Some((child, rest)) = element_id(entries) else {
+                        break;
+                    };
+                    children.push(child.to_vec());
+                    entries = rest;
+                }
+                listed = Some(children);
+            }
+            _ => {}
+        }
+    }
+
+    Some(match listed {
+        Some(children) => children.iter().filter_map(|id| chapters.remove(id)).collect(),
+        None => chapters.into_values().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_bytes(bytes: &[u8]) -> Vec<Chapter> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("book");
+        std::fs::write(&path, bytes).unwrap();
+        read(path.to_str().unwrap())
+    }
+
+    fn starts_and_titles(chapters: &[Chapter]) -> Vec<(f64, &str)> {
+        chapters.iter().map(|c| (c.start, c.title.as_str())).collect()
+    }
+
+    /// An ID3v2.3 frame
+    fn frame(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend((body.len() as u32).to_be_bytes());
+        frame.extend([0, 0]);
+        frame.extend(body);
+        frame
+    }
+
+    fn chap(element: &str, start_ms: u32, title: Option<&str>) -> Vec<u8> {
+        let mut body = element.as_bytes().to_vec();
+        body.push(0);
+        body.extend(start_ms.to_be_bytes());
+        // End time and byte offsets, unused
+        body.extend([0xFF; 12]);
+        if let Some(title) = title {
+            let mut text = vec![0];
+            text.extend(title.as_bytes());
+            body.extend(frame(b"TIT2", &text));
+        }
+        frame(b"CHAP", &body)
+    }
+
+    /// An ID3v2.3 tag holding `frames`, followed by some audio, with the
+    /// tag size given as `size`
+    fn id3(frames: &[Vec<u8>], size: Option<u32>) -> Vec<u8> {
+        let mut body: Vec<u8> = frames.concat();
+        body.extend([0; 16]);
+        let size = size.unwrap_or(body.len() as u32);
+        let synchsafe: Vec<u8> = (0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8).collect();
+
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        tag.extend(synchsafe);
+        tag.extend(body);
+        tag.extend([0xFF, 0xFB, 0x90, 0x00]);
+        tag
+    }
+
+    #[test]
+    fn id3_chapters_follow_the_table_of_contents() {
+        let mut toc = b"toc\0".to_vec();
+        // Top-level and ordered, two entries
+        toc.extend([0x03, 2]);
+        toc.extend(b"ch2\0ch1\0");
+        let tag = id3(&[
+            chap("ch1", 0, Some("Opening")),
+            chap("ch2", 754_500, None),
+            // Not listed, so not a chapter
+            chap("extra", 30_000, Some("Bonus")),
+            frame(b"CTOC", &toc),
+        ], None);
+
+        let chapters = read_bytes(&tag);
+        assert_eq!(starts_and_titles(&chapters), [(0.0, "Opening"), (754.5, "Chapter 2")]);
+    }
+
+    #[test]
+    fn id3_chapters_without_a_table_of_contents_all_count() {
+        let tag = id3(&[chap("b", 60_000, Some("Two")), chap("a", 0, Some("One"))], None);
+        assert_eq!(starts_and_titles(&read_bytes(&tag)), [(0.0, "One"), (60.0, "Two")]);
+    }
+
+    #[test]
+    fn id3_tag_sizes_past_the_end_of_the_file_are_cut_short() {
+        // Claims the largest size there is
+        let tag = id3(&[chap("a", 0, Some("One"))], Some(0x0FFF_FFFF));
+        assert_eq!(starts_and_titles(&read_bytes(&tag)), [(0.0, "One")]);
+    }
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend(kind);
+        data.extend(body);
+        data
+    }
+
+    /// A full box: version, flags, then the fields
+    fn full_box(kind: &[u8; 4], version: u8, fields: &[u32]) -> Vec<u8> {
+        let mut body = vec![version, 0, 0, 0];
+        body.extend(fields.iter().flat_map(|field| field.to_be_bytes()));
+        mp4_box(kind, &body)
+    }
+
+    fn ftyp() -> Vec<u8> {
+        mp4_box(b"ftyp", b"M4B \0\0\0\0M4B isom")
+    }
+
+    #[test]
+    fn nero_chapters_are_read_from_the_chpl_atom() {
+        let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start, title) in [(0u64, "Intro"), (3_125 * CHPL_UNITS_PER_SECOND as u64 / 10, "Part One")] {
+            chpl.extend(start.to_be_bytes());
+            chpl.push(title.len() as u8);
+            chpl.extend(title.as_bytes());
+        }
+        let moov = mp4_box(b"moov", &mp4_box(b"udta", &mp4_box(b"chpl", &chpl)));
+
+        let mut file = ftyp();
+        file.extend(mp4_box(b"mdat", &[0; 64]));
+        file.extend(moov);
+        assert_eq!(starts_and_titles(&read_bytes(&file)), [(0.0, "Intro"), (312.5, "Part One")]);
+    }
+
+    #[test]
+    fn chapter_tracks_give_a_title_for_each_sample() {
+        let titles = ["Prologue", "The Road", "Epilogue"];
+        let samples: Vec<Vec<u8>> = titles
+            .iter()
+            .map(|title| {
+                let mut sample = (title.len() as u16).to_be_bytes().to_vec();
+                sample.extend(title.as_bytes());
+                sample
+            })
+            .collect();
+
+        let mut file = ftyp();
+        // The titles are the contents of the mdat box
+        let chunk_offset = (file.len() + 8) as u32;
+        file.extend(mp4_box(b"mdat", &samples.concat()));
+
+        let audio = [
+            full_box(b"tkhd", 0, &[0, 0, 1]),
+            mp4_box(b"tref", &mp4_box(b"chap", &2u32.to_be_bytes())),
+        ]
+        .concat();
+        let mut sizes = vec![0, titles.len() as u32];
+        sizes.extend(samples.iter().map(|s| s.len() as u32));
+        let stbl = [
+            // Two samples of 60 s, then one of 30 s, at a 1 kHz timescale
+            full_box(b"stts", 0, &[2, 2, 60_000, 1, 30_000]),
+            full_box(b"stsc", 0, &[1, 1, 3, 1]),
+            full_box(b"stsz", 0, &sizes),
+            full_box(b"stco", 0, &[1, chunk_offset]),
+        ]
+        .concat();
+        let mdia = [
+            full_box(b"mdhd", 0, &[0, 0, 1000, 0]),
+            mp4_box(b"minf", &mp4_box(b"stbl", &stbl)),
+        ]
+        .concat();
+        let text = [full_box(b"tkhd", 0, &[0, 0, 2]), mp4_box(b"mdia", &mdia)].concat();
+        let moov = [mp4_box(b"trak", &audio), mp4_box(b"trak", &text)].concat();
+        file.extend(mp4_box(b"moov", &moov));
+
+        let chapters = read_bytes(&file);
+        assert_eq!(starts_and_titles(&chapters), [(0.0, "Prologue"), (60.0, "The Road"), (120.0, "Epilogue")]);
+    }
+}
@@ -1,7 +1,11 @@
 use anyhow::{Context, Result};
 use std::fs;
 
-use crate::ipc::{Command, IpcClient, Response, PlaybackState};
+use crate::ipc::{Command, IpcClient, Response, PlaybackState, ReplayGainMode, SilenceMode, SleepTimerMode};
+use crate::loudness::{self, AnalysisJob, AnalysisResult, GainCache};
+use crate::playlist;
+use crate::stream;
+use crate::volume;
 
 pub async fn send_command(command: Command) -> Result<()> {
     match IpcClient::send_command(command).await {
@@ -25,6 +29,109 @@ pub async fn send_command(command: Command) -> Result<()> {
     }
 }
 
+/// Parses a seek argument: `1:23`, `1:02:03` or `83` for an absolute
+/// position, with a leading `+` or `-` for an offset from the current one.
+pub fn parse_seek(arg: &str) -> Result<Command> {
+    let arg = arg.trim();
+    let (sign, time) = match arg.chars().next() {
+        Some('+') => (Some(1.0), &arg[1..]),
+        Some('-') => (Some(-1.0), &arg[1..]),
+        _ => (None, arg),
+    };
+
+    let seconds = parse_time(time)
+        .with_context(|| format!("Invalid seek position: {}", arg))?;
+
+    Ok(match sign {
+        Some(sign) => Command::SeekRelative { offset: sign * seconds },
+        None => Command::Seek { position: seconds },
+    })
+}
+
+/// Parses a position in a track: `1:23`, `1:02:03` or `83`.
+pub fn parse_position(arg: &str) -> Result<f64> {
+    parse_time(arg.trim()).with_context(|| format!("Invalid position: {}", arg))
+}
+
+/// Parses a sleep timer argument: a number with an `h`, `m` or `s` suffix
+/// (minutes without one), `track`, `playlist`, or `off` to cancel.
+pub fn parse_sleep(arg: &str) -> Result<Command> {
+    let arg = arg.trim().to_lowercase();
+    let mode = match arg.as_str() {
+        "off" | "cancel" => return Ok(Command::CancelSleepTimer),
+        "track" | "end-of-track" => SleepTimerMode::EndOfTrack,
+        "playlist" | "end-of-playlist" => SleepTimerMode::EndOfPlaylist,
+        _ => {
+            let (number, per_minute) = match arg.chars().last() {
+                Some('h') => (&arg[..arg.len() - 1], 60.0),
+                Some('m') => (&arg[..arg.len() - 1], 1.0),
+                Some('s') => (&arg[..arg.len() - 1], 1.0 / 60.0),
+                _ => (arg.as_str(), 1.0),
+            };
+            let value: f64 = number
+                .parse()
+                .with_context(|| format!("Invalid sleep time: {}", arg))?;
+            SleepTimerMode::Minutes(value * per_minute)
+        }
+    };
+    Ok(Command::SetSleepTimer { mode })
+}
+
+fn format_time(seconds: f64) -> String {
+    format!("{}:{:04.1}", seconds as u64 / 60, seconds % 60.0)
+}
+
+fn parse_time(time: &str) -> Result<f64> {
+    let mut seconds = 0.0;
+    for part in time.split(':') {
+        let value: f64 = part.parse()?;
+        if value < 0.0 {
+            anyhow::bail!("negative time component");
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Ok(seconds)
+}
+
+/// Measures loudness in the foreground and stores it in the gain cache.
+pub fn analyze(paths: &[String], force: bool) -> Result<()> {
+    let files: Vec<String> = paths.iter().flat_map(|p| playlist::collect_audio_files(p)).collect();
+    if files.is_empty() {
+        anyhow::bail!("No audio files found");
+    }
+
+    let cache = std::sync::Mutex::new(GainCache::open_default()?);
+    let job = AnalysisJob {
+        files,
+        skip_tagged: false,
+        force,
+    };
+
+    let mut measured = 0;
+    loudness::run_job(job, &cache, |file, result| {
+        let name = std::path::Path::new(file)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(file);
+        match result {
+            AnalysisResult::Measured(gain) => {
+                measured += 1;
+                println!(
+                    "  {:>6.1} LUFS  {:>5.1} dBTP  {}",
+                    gain.track_loudness,
+                    20.0 * gain.track_peak.log10(),
+                    name
+                );
+            }
+            AnalysisResult::Silent => println!("  silent               {}", name),
+            AnalysisResult::Failed(e) => eprintln!("? {}: {}", name, e),
+        }
+    })?;
+
+    println!("? Analyzed {} tracks", measured);
+    Ok(())
+}
+
 pub async fn show_status() -> Result<()> {
     match IpcClient::send_command(Command::GetStatus).await {
         Ok(Response::Status(status)) => {
@@ -48,20 +155,92 @@ pub async fn show_status() -> Result<()> {
             println!("  {} State:    {}", state_emoji, state_str);
             
             if let Some(track) = status.current_track {
-                let filename = std::path::Path::new(&track)
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or(&track);
+                let filename = if stream::is_url(&track) {
+                    &track
+                } else {
+                    std::path::Path::new(&track)
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .unwrap_or(&track)
+                };
                 println!("  ? Track:    {}", filename);
             } else {
                 println!("  ? Track:    None");
             }
+            if let Some(title) = &status.stream_title {
+                println!("  ? On air:   {}", title);
+            }
             
             if status.duration > 0.0 {
                 println!("  ? Time:     {:.0}s / {:.0}s", status.position, status.duration);
             }
+            if status.speed != 1.0 {
+                println!("  ? Speed:    {:.2}x ({:.0}s left)", status.speed, status.remaining);
+            }
             
-            println!("  ?? Volume:   {}%", status.volume);
+            let volume_note = match volume::level_to_db(status.volume) {
+                _ if status.muted => " (muted)".to_string(),
+                Some(db) if db > 0.0 => format!(" (+{:.1} dB boost)", db),
+                _ => String::new(),
+            };
+            println!("  ?? Volume:   {}%{}", status.volume, volume_note);
+
+            if status.crossfade > 0.0 {
+                println!("  ? Crossfade: {:.1}s", status.crossfade);
+            }
+            let channels = status.channels;
+            if channels.balance != 0.0 || channels.mono || channels.swap {
+                let mut notes = Vec::new();
+                if channels.balance != 0.0 {
+                    let side = if channels.balance < 0.0 { "L" } else { "R" };
+                    notes.push(format!("balance {} {:.0}%", side, channels.balance.abs() * 100.0));
+                }
+                if channels.mono {
+                    notes.push("mono".to_string());
+                }
+                if channels.swap {
+                    notes.push("swapped".to_string());
+                }
+                println!("  ? Channels: {}", notes.join(", "));
+            }
+            let silence = status.silence;
+            match silence.mode {
+                SilenceMode::Off => {}
+                SilenceMode::Trim => println!("  ? Silence:  trim below {:.0} dB", silence.threshold_db),
+                SilenceMode::Skip => println!(
+                    "  ? Silence:  skip below {:.0} dB after {:.1}s",
+                    silence.threshold_db, silence.min_seconds
+                ),
+            }
+            match (status.loop_a, status.loop_b) {
+                (Some(a), Some(b)) => println!("  ? Loop:     {} – {}", format_time(a), format_time(b)),
+                (Some(a), None) => println!("  ? Loop:     from {}, end not set", format_time(a)),
+                _ => {}
+            }
+            if let Some(index) = status.chapter {
+                let title = status.chapters.get(index).map_or("", |chapter| chapter.title.as_str());
+                println!("  ? Chapter:  {} of {}, {}", index + 1, status.chapters.len(), title);
+            }
+            if status.decode_errors > 0 {
+                println!("  ? Damaged:  {} part(s) of this file skipped", status.decode_errors);
+            }
+            if status.audiobook.enabled {
+                println!("  ? Resume:   tracks of {:.0} min or more", status.audiobook.min_minutes);
+            }
+            if let Some(timer) = status.sleep_timer {
+                let when = match timer.mode {
+                    SleepTimerMode::Minutes(_) => "pause",
+                    SleepTimerMode::EndOfTrack => "stop at end of track",
+                    SleepTimerMode::EndOfPlaylist => "stop at end of playlist",
+                };
+                match timer.remaining {
+                    Some(left) => println!("  ? Sleep:    {} in {}:{:02}", when, left as u64 / 60, left as u64 % 60),
+                    None => println!("  ? Sleep:    {}", when),
+                }
+            }
+            if status.replaygain != ReplayGainMode::Off {
+                println!("  ? ReplayGain: {:?}", status.replaygain);
+            }
             println!("  ?? Playlist: {} tracks", status.playlist_length);
             
             if let Some(index) = status.current_index {
@@ -121,6 +300,88 @@ pub async fn show_playlist() -> Result<()> {
     }
 }
 
+pub async fn show_chapters() -> Result<()> {
+    match IpcClient::send_command(Command::GetStatus).await {
+        Ok(Response::Status(status)) => {
+            if status.chapters.is_empty() {
+                println!("The current track has no chapters");
+                return Ok(());
+            }
+
+            for (i, chapter) in status.chapters.iter().enumerate() {
+                let marker = if status.chapter == Some(i) { "*" } else { " " };
+                println!("{} {:>3}. {:>8}  {}", marker, i + 1, format_time(chapter.start), chapter.title);
+            }
+            Ok(())
+        }
+        Ok(_) => {
+            eprintln!("? Unexpected response");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("? Failed to get chapters: {}", e);
+            eprintln!("  Make sure the daemon is running: musicplayer daemon start");
+            std::process::exit(1);
+        }
+    }
+}
+
+pub async fn show_eq() -> Result<()> {
+    match IpcClient::send_command(Command::GetEq).await {
+        Ok(Response::Equalizer(eq)) => {
+            let state = if eq.enabled { "on" } else { "off" };
+            let preset = eq.preset.as_deref().unwrap_or("custom");
+            println!("Equalizer: {} ({})", state, preset);
+            println!();
+
+            for (i, band) in eq.bands.iter().enumerate() {
+                println!("  {:>2}. {:>6}  {:+5.1} dB", i, band.label, band.gain);
+            }
+
+            println!();
+            println!("Presets: {}", eq.presets.join(", "));
+            Ok(())
+        }
+        Ok(_) => {
+            eprintln!("? Unexpected response");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("? Failed to get equalizer: {}", e);
+            eprintln!("  Make sure the daemon is running: musicplayer daemon start");
+            std::process::exit(1);
+        }
+    }
+}
+
+pub async fn list_output_devices() -> Result<()> {
+    match IpcClient::send_command(Command::ListOutputDevices).await {
+        Ok(Response::OutputDevices(list)) => {
+            let marker = |selected: bool| if selected { "*" } else { " " };
+            let default = list.default.as_deref().unwrap_or("unknown");
+            println!("{} default ({})", marker(list.selected.is_none()), default);
+
+            for device in &list.devices {
+                println!("{} {}", marker(list.selected.as_ref() == Some(device)), device);
+            }
+            Ok(())
+        }
+        Ok(Response::Error(e)) => {
+            eprintln!("? Error: {}", e);
+            std::process::exit(1);
+        }
+        Ok(_) => {
+            eprintln!("? Unexpected response");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("? Failed to list output devices: {}", e);
+            eprintln!("  Make sure the daemon is running: musicplayer daemon start");
+            std::process::exit(1);
+        }
+    }
+}
+
 pub async fn stop_daemon() -> Result<()> {
     let pid_file = get_pid_file()?;
     
@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ipc::{AudiobookSettings, ChannelSettings, SilenceSettings};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where the daemon keeps its files: settings in the config directory,
+/// caches and saved positions in the data directory.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+}
+
+impl Storage {
+    /// The user's directories for musicplayer
+    pub fn user() -> Result<Self> {
+        let dirs = directories::ProjectDirs::from("", "", "musicplayer")
+            .context("Failed to get project directories")?;
+
+        Ok(Self {
+            config_dir: dirs.config_dir().to_path_buf(),
+            data_dir: dirs.data_dir().to_path_buf(),
+        })
+    }
+
+    /// Both kinds of files in `dir`
+    #[cfg(test)]
+    pub fn in_dir(dir: &Path) -> Self {
+        Self {
+            config_dir: dir.to_path_buf(),
+            data_dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn config_file(&self) -> Result<PathBuf> {
+        fs::create_dir_all(&self.config_dir)?;
+        Ok(self.config_dir.join("config.json"))
+    }
+
+    /// `name` in the data directory, which is created if needed
+    pub fn data_file(&self, name: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.data_dir)?;
+        Ok(self.data_dir.join(name))
+    }
+}
+
+/// Daemon settings kept between runs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Output device name, `None` for the system default
+    pub output_device: Option<String>,
+    /// Balance, mono and channel swap
+    pub channels: ChannelSettings,
+    /// Trimming or skipping silence in tracks
+    pub silence: SilenceSettings,
+    /// Resuming long tracks where they were left
+    pub audiobook: AudiobookSettings,
+}
+
+impl DaemonConfig {
+    pub fn load(file: &Path) -> Result<Self> {
+        if !file.exists() {
+            return Ok(Self::default());
+        }
+
+        let json = fs::read_to_string(file)?;
+        serde_json::from_str(&json).context("Failed to parse daemon config")
+    }
+
+    pub fn save(&self, file: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(file, json).context("Failed to write daemon config")
+    }
+}
@@ -0,0 +1,560 @@
+use anyhow::{Context, Result};
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::Duration;
+
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::playlist::AUDIO_EXTENSIONS;
+
+// Cue sheet times count CD frames, 75 to the second
+const FRAMES_PER_SECOND: u64 = 75;
+
+// Track numbers from here on mark the lead-out in a FLAC CUESHEET block
+const LEAD_OUT: u32 = 170;
+
+/// One track of a cue sheet: a stretch of a larger audio file.
+#[derive(Debug, Clone)]
+pub struct CueTrack {
+    pub file: String,
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub album: Option<String>,
+    /// Start and end in CD frames; no end means the end of the file
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+/// Rounded up, so a seek lands on the first sample of the frame rather
+/// than the last one before it.
+fn frames_to_duration(frames: u64) -> Duration {
+    Duration::from_nanos((frames * 1_000_000_000).div_ceil(FRAMES_PER_SECOND))
+}
+
+/// `mm:ss:ff` to CD frames
+fn parse_time(time: &str) -> Option<u64> {
+    let mut parts = time.split(':').map(|part| part.trim().parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    Some((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames)
+}
+
+/// The argument of a cue command, with quotes removed
+fn unquote(text: &str) -> String {
+    let text = text.trim();
+    match text.strip_prefix('"') {
+        Some(rest) => rest.split('"').next().unwrap_or_default().to_string(),
+        None => text.to_string(),
+    }
+}
+
+/// Finds the audio file a `FILE` line refers to. Rips are often converted
+/// after the sheet was written, so another extension of the same name will
+/// do as well.
+fn locate(dir: &Path, name: &str) -> String {
+    let path = dir.join(name);
+    if !path.exists() {
+        for ext in AUDIO_EXTENSIONS {
+            let candidate = path.with_extension(ext);
+            if candidate.exists() {
+                return candidate.to_string_lossy().into_owned();
+            }
+        }
+    }
+    path.to_string_lossy().into_owned()
+}
+
+/// Each track runs up to the next one in the same file.
+fn link(tracks: &mut [CueTrack]) {
+    for i in 1..tracks.len() {
+        let same_file = tracks[i - 1].file == tracks[i].file;
+        tracks[i - 1].end = same_file.then_some(tracks[i].start);
+    }
+}
+
+/// Reads the audio tracks of a cue sheet. Files are looked up relative to
+/// `dir`.
+pub fn parse(text: &str, dir: &Path) -> Vec<CueTrack> {
+    let mut tracks: Vec<CueTrack> = Vec::new();
+    let mut album = None;
+    let mut album_performer = None;
+    let mut file = None;
+    // Whether the current track takes the lines that follow
+    let mut in_track = false;
+    let mut has_index_one = false;
+
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                // The file type comes last, after a possibly unquoted name
+                let rest = rest.trim();
+                let name = if rest.starts_with('"') {
+                    unquote(rest)
+                } else {
+                    rest.rsplit_once(char::is_whitespace).map_or(rest, |(name, _)| name).to_string()
+                };
+                file = Some(locate(dir, &name));
+                in_track = false;
+            }
+            "TRACK" => {
+                let mut args = rest.split_whitespace();
+                let number = args.next().and_then(|n| n.parse().ok());
+                let audio = args.next().is_some_and(|kind| kind.eq_ignore_ascii_case("AUDIO"));
+                in_track = false;
+                if let (Some(number), Some(file), true) = (number, &file, audio) {
+                    tracks.push(CueTrack {
+                        file: file.clone(),
+                        number,
+                        title: None,
+                        performer: None,
+                        album: None,
+                        start: 0,
+                        end: None,
+                    });
+                    in_track = true;
+                    has_index_one = false;
+                }
+            }
+            "TITLE" => match tracks.last_mut() {
+                Some(track) if in_track => track.title = Some(unquote(rest)),
+                _ => album = Some(unquote(rest)),
+            },
+            "PERFORMER" => match tracks.last_mut() {
+                Some(track) if in_track => track.performer = Some(unquote(rest)),
+                _ => album_performer = Some(unquote(rest)),
+            },
+            "INDEX" if in_track => {
+                let mut args = rest.split_whitespace();
+                let index: Option<u32> = args.next().and_then(|n| n.parse().ok());
+                let Some(time) = args.next().and_then(parse_time) else {
+                    continue;
+                };
+                let track = tracks.last_mut().unwrap();
+                // Tracks start at INDEX 01; the pregap before it stays with
+                // the previous track so the album plays through unchanged
+                match index {
+                    Some(1) => {
+                        track.start = time;
+                        has_index_one = true;
+                    }
+                    Some(0) if !has_index_one => track.start = time,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for track in &mut tracks {
+        track.album = album.clone();
+        if track.performer.is_none() {
+            track.performer = album_performer.clone();
+        }
+    }
+    link(&mut tracks);
+    tracks
+}
+
+/// Reads a `.cue` file. Sheets that aren't UTF-8 are taken as Latin-1,
+/// which is what most older rippers wrote.
+pub fn read_sheet(path: &Path) -> Result<Vec<CueTrack>> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read cue sheet: {}", path.display()))?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    let text = text.trim_start_matches('\u{feff}');
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(parse(text, dir))
+}
+
+/// The tracks of a cue sheet stored inside a FLAC file, either as a
+/// `CUESHEET` tag (with titles) or as a CUESHEET block (offsets only).
+/// `None` unless it splits the file into at least two tracks.
+pub fn read_embedded(path: &str) -> Option<Vec<CueTrack>> {
+    let file = File::open(path).ok()?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension("flac");
+    let mut probed = symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+        .ok()?;
+
+    let sheet = probed.format.metadata().current().and_then(|revision| {
+        revision
+            .tags()
+            .iter()
+            .find(|tag| tag.key.eq_ignore_ascii_case("CUESHEET"))
+            .map(|tag| tag.value.to_string())
+    });
+
+    let mut tracks = match sheet {
+        Some(sheet) => {
+            let mut tracks = parse(&sheet, Path::new(""));
+            // The sheet names the file it was made for, which is this one
+            for track in &mut tracks {
+                track.file = path.to_string();
+            }
+            link(&mut tracks);
+            tracks
+        }
+        None => {
+            let format = probed.format;
+            let sample_rate = format.default_track()?.codec_params.sample_rate? as u64;
+            let mut tracks: Vec<CueTrack> = Vec::new();
+            for cue in format.cues() {
+                let start = cue.start_ts * FRAMES_PER_SECOND / sample_rate;
+                if cue.index >= LEAD_OUT {
+                    if let Some(last) = tracks.last_mut() {
+                        last.end = Some(start);
+                    }
+                    continue;
+                }
+                if let Some(last) = tracks.last_mut() {
+                    last.end = Some(start);
+                }
+                tracks.push(CueTrack {
+                    file: path.to_string(),
+                    number: cue.index,
+                    title: None,
+                    performer: None,
+                    album: None,
+                    start,
+                    end: None,
+                });
+            }
+            tracks
+        }
+    };
+
+    tracks.retain(|track| track.end.is_none_or(|end| end > track.start));
+    (tracks.len() >= 2).then_some(tracks)
+}
+
+/// Playlist entry for track `number` of the cue sheet at `sheet`, which
+/// is a `.cue` file or a FLAC file with the sheet embedded.
+pub fn track_path(sheet: &str, number: u32) -> String {
+    format!("{}#{}", sheet, number)
+}
+
+/// The cue track a playlist entry stands for, `None` for plain files.
+pub fn resolve(path: &str) -> Option<CueTrack> {
+    if Path::new(path).exists() {
+        return None;
+    }
+    let (sheet, number) = path.rsplit_once('#')?;
+    let number: u32 = number.parse().ok()?;
+
+    let tracks = if is_sheet_file(sheet) {
+        read_sheet(Path::new(sheet)).ok()?
+    } else {
+        read_embedded(sheet)?
+    };
+    tracks.into_iter().find(|track| track.number == number)
+}
+
+/// The audio file a playlist entry plays from: the entry itself for plain
+/// files, the file the track is cut from for cue tracks. `None` when the
+/// track can't be found.
+pub fn audio_file(path: &str) -> Option<String> {
+    if Path::new(path).exists() {
+        return Some(path.to_string());
+    }
+    match path.rsplit_once('#') {
+        Some((sheet, _)) if is_sheet_file(sheet) => resolve(path).map(|track| track.file),
+        // Embedded sheets describe the file they're in
+        Some((file, _)) => Path::new(file).exists().then(|| file.to_string()),
+        None => None,
+    }
+}
+
+fn is_sheet_file(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// Plays one cue track out of the source for the whole file. Positions
+/// are relative to the start of the track, and the source ends on the
+/// sample where the next track begins.
+pub struct Segment<S> {
+    inner: S,
+    start: u64,
+    end: Option<u64>,
+    // Samples left before the end of the track
+    left: Option<u64>,
+}
+
+impl<S: Source<Item = i16>> Segment<S> {
+    pub fn new(mut inner: S, track: &CueTrack) -> Result<Self, SeekError> {
+        if track.start > 0 {
+            inner.try_seek(frames_to_duration(track.start))?;
+        }
+        let mut segment = Self {
+            inner,
+            start: track.start,
+            end: track.end,
+            left: None,
+        };
+        segment.left = segment.samples_left(Duration::ZERO);
+        Ok(segment)
+    }
+
+    /// Samples from `position` into the track up to its end
+    fn samples_left(&self, position: Duration) -> Option<u64> {
+        let sample_rate = self.inner.sample_rate() as u64;
+        let channels = self.inner.channels() as u64;
+        // Counted in whole sample frames from the start of the file, the way
+        // the decoder seeks
+        let from = self.start * sample_rate / FRAMES_PER_SECOND
+            + (position.as_nanos() * sample_rate as u128 / 1_000_000_000) as u64;
+        self.end
+            .map(|end| (end * sample_rate / FRAMES_PER_SECOND).saturating_sub(from) * channels)
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Segment<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        match &mut self.left {
+            Some(0) => None,
+            Some(left) => {
+                *left -= 1;
+                self.inner.next()
+            }
+            None => self.inner.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.inner.size_hint();
+        match self.left {
+            Some(left) => {
+                let left = left as usize;
+                (lower.min(left), Some(upper.map_or(left, |upper| upper.min(left))))
+            }
+            None => (lower, upper),
+        }
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Segment<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        // The end of the track is a frame boundary as well
+        match (self.inner.current_frame_len(), self.left) {
+            (Some(len), Some(left)) => Some(len.min(left as usize)),
+            (None, Some(left)) => Some(left as usize),
+            (len, None) => len,
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let start = frames_to_duration(self.start);
+        match self.end {
+            Some(end) => Some(frames_to_duration(end.saturating_sub(self.start))),
+            None => self.inner.total_duration().map(|total| total.saturating_sub(start)),
+        }
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(frames_to_duration(self.start) + pos)?;
+        self.left = self.samples_left(pos);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const SHEET: &str = r#"REM GENRE Jazz
+PERFORMER "The Band"
+TITLE "Live"
+FILE "side a.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Opener"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second"
+    PERFORMER "Guest"
+    INDEX 00 03:58:50
+    INDEX 01 04:00:00
+  TRACK 03 AUDIO
+    TITLE "Hidden"
+    INDEX 00 07:30:00
+FILE "side b.flac" WAVE
+  TRACK 04 DATA
+    INDEX 01 00:00:00
+  TRACK 05 AUDIO
+    TITLE "Closer"
+    INDEX 01 00:02:37
+"#;
+
+    #[test]
+    fn tracks_are_cut_from_each_file_of_the_sheet() {
+        let tracks = parse(SHEET, Path::new("/music"));
+
+        let numbers: Vec<u32> = tracks.iter().map(|t| t.number).collect();
+        assert_eq!(numbers, [1, 2, 3, 5]);
+        assert_eq!(tracks[0].file, "/music/side a.flac");
+        assert_eq!(tracks[3].file, "/music/side b.flac");
+        assert_eq!(tracks[3].title.as_deref(), Some("Closer"));
+        assert_eq!(tracks[3].start, 2 * 75 + 37);
+        assert!(tracks.iter().all(|t| t.album.as_deref() == Some("Live")));
+        assert_eq!(tracks[0].performer.as_deref(), Some("The Band"));
+        assert_eq!(tracks[1].performer.as_deref(), Some("Guest"));
+    }
+
+    #[test]
+    fn tracks_start_at_index_one_and_keep_the_pregap_before_it() {
+        let tracks = parse(SHEET, Path::new("/music"));
+
+        // The pregap of track 2 plays at the end of track 1
+        assert_eq!(tracks[0].end, Some(4 * 60 * 75));
+        assert_eq!(tracks[1].start, 4 * 60 * 75);
+        // Without an INDEX 01, INDEX 00 is all there is
+        assert_eq!(tracks[2].start, (7 * 60 + 30) * 75);
+    }
+
+    #[test]
+    fn the_last_track_of_a_file_runs_to_its_end() {
+        let tracks = parse(SHEET, Path::new("/music"));
+        assert_eq!(tracks[1].end, Some((7 * 60 + 30) * 75));
+        assert_eq!(tracks[2].end, None);
+        assert_eq!(tracks[3].end, None);
+    }
+
+    /// The start of a 16-bit stereo 44.1 kHz FLAC file: its metadata
+    /// blocks and one frame header, which is all the probe looks at.
+    fn flac(blocks: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut stream_info = Vec::new();
+        stream_info.extend(4096u16.to_be_bytes());
+        stream_info.extend(4096u16.to_be_bytes());
+        stream_info.extend([0; 6]);
+        // 44100 Hz (20 bits), 2 channels (3 bits), 16 bits (5 bits), no
+        // sample count (36 bits)
+        let packed: u64 = (44100 << 44) | (1 << 41) | (15 << 36);
+        stream_info.extend(packed.to_be_bytes());
+        stream_info.extend([0; 16]);
+
+        let mut file = b"fLaC".to_vec();
+        let all: Vec<(u8, Vec<u8>)> = std::iter::once((0, stream_info)).chain(blocks.iter().cloned()).collect();
+        for (i, (kind, data)) in all.iter().enumerate() {
+            let last = if i + 1 == all.len() { 0x80 } else { 0 };
+            file.push(last | kind);
+            file.extend(&(data.len() as u32).to_be_bytes()[1..]);
+            file.extend(data);
+        }
+
+        // Fixed 4096-sample blocks at 44.1 kHz, stereo, 16 bits, frame 0
+        let header = [0xFF, 0xF8, 0xC9, 0x18, 0x00];
+        let crc = header.iter().fold(0u8, |crc, &byte| {
+            (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+        });
+        file.extend(header);
+        file.push(crc);
+        file.extend([0; 16]);
+        file
+    }
+
+    /// A CUESHEET metadata block with tracks starting at the given samples
+    fn cuesheet_block(tracks: &[(u8, u64)]) -> Vec<u8> {
+        let mut block = vec![0; 128 + 8 + 1 + 258];
+        block.push(tracks.len() as u8);
+        for &(number, offset) in tracks {
+            block.extend(offset.to_be_bytes());
+            block.push(number);
+            block.extend([0; 12 + 14]);
+            // One index point at the start of the track
+            block.push(1);
+            block.extend(0u64.to_be_bytes());
+            block.extend([1, 0, 0, 0]);
+        }
+        block
+    }
+
+    #[test]
+    fn embedded_cuesheet_blocks_split_the_flac_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("album.flac");
+        let path = path.to_str().unwrap();
+        let cuesheet = cuesheet_block(&[(1, 0), (2, 44100 * 90), (3, 44100 * 200), (LEAD_OUT as u8, 44100 * 300)]);
+        std::fs::write(path, flac(&[(5, cuesheet)])).unwrap();
+
+        let tracks = read_embedded(path).unwrap();
+        let bounds: Vec<(u32, u64, Option<u64>)> = tracks.iter().map(|t| (t.number, t.start, t.end)).collect();
+        // The lead-out ends the last track
+        assert_eq!(bounds, [(1, 0, Some(90 * 75)), (2, 90 * 75, Some(200 * 75)), (3, 200 * 75, Some(300 * 75))]);
+        assert!(tracks.iter().all(|t| t.file == path));
+
+        assert_eq!(resolve(&track_path(path, 2)).unwrap().start, 90 * 75);
+        assert_eq!(audio_file(&track_path(path, 2)).as_deref(), Some(path));
+    }
+
+    #[test]
+    fn flac_files_without_a_cuesheet_are_single_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("single.flac");
+        std::fs::write(&path, flac(&[])).unwrap();
+        assert!(read_embedded(path.to_str().unwrap()).is_none());
+    }
+
+    // 100 samples to the CD frame
+    const SAMPLE_RATE: u32 = 7500;
+
+    fn segment(start: u64, end: Option<u64>) -> Segment<SamplesBuffer<i16>> {
+        // Each sample holds its own position in the file
+        let samples: Vec<i16> = (0..SAMPLE_RATE as i16 * 4).collect();
+        let source = SamplesBuffer::new(1, SAMPLE_RATE, samples);
+        let track = CueTrack {
+            file: String::new(),
+            number: 1,
+            title: None,
+            performer: None,
+            album: None,
+            start,
+            end,
+        };
+        Segment::new(source, &track).unwrap()
+    }
+
+    #[test]
+    fn segments_play_from_the_start_to_the_end_of_their_track() {
+        let played: Vec<i16> = segment(75, Some(225)).collect();
+        assert_eq!(played.first(), Some(&7500));
+        assert_eq!(played.last(), Some(&(3 * 7500 - 1)));
+
+        let last = segment(225, None);
+        assert_eq!(last.total_duration(), Some(Duration::from_secs(1)));
+        assert_eq!(last.count(), 7500);
+    }
+
+    #[test]
+    fn segment_positions_count_from_the_start_of_the_track() {
+        let mut segment = segment(75, Some(225));
+        assert_eq!(segment.total_duration(), Some(Duration::from_secs(2)));
+
+        segment.try_seek(Duration::from_millis(500)).unwrap();
+        assert_eq!(segment.next(), Some(7500 + 3750));
+        // What's left is the rest of the track, not of the file
+        assert_eq!(segment.count(), 3 * 7500 - 7500 - 3750 - 1);
+    }
+}
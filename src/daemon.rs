@@ -1,39 +1,81 @@
 use anyhow::{Context, Result};
+use std::collections::HashSet;
 use std::fs;
 use std::path::PathBuf;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 use std::sync::Arc;
-// use tracing::{info, error};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
 
-use crate::ipc::{Command, IpcServer, PlayerStatus, PlaybackState, Response};
-use crate::player::Player;
-use crate::playlist::Playlist;
+use crate::config::{DaemonConfig, Storage};
+use crate::cue;
+use crate::ipc::{self, Command, IpcServer, OutputDeviceList, PlaybackState, PlayerStatus, Response, SleepTimerMode};
+use crate::loudness::{self, AnalysisJob};
+use crate::output::{self, BackendSpec};
+use crate::player::{Player, PlayerEvent, PlayerSnapshot, PreparedTrack};
+use crate::playlist::{self, Playlist};
+use crate::resume::{PendingSave, ResumeStore};
+use crate::sleep_timer::{SleepAction, SleepTimer, MAX_MINUTES};
+use crate::stream;
+
+// How often the sleep timer is looked at while it runs
+const SLEEP_TIMER_TICK: Duration = Duration::from_millis(250);
+
+// How often the position of a long track is saved while it plays
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(15);
 
 pub struct Daemon {
     player: Arc<Player>,
     playlist: Arc<Mutex<Playlist>>,
     ipc_server: IpcServer,
-    last_manual_command: std::sync::Mutex<std::time::Instant>,
+    analysis_tx: std::sync::mpsc::Sender<AnalysisJob>,
+    config: std::sync::Mutex<DaemonConfig>,
+    storage: Storage,
+    events: mpsc::UnboundedReceiver<PlayerEvent>,
+    sleep_timer: std::sync::Mutex<Option<SleepTimer>>,
+    resume: std::sync::Mutex<ResumeStore>,
 }
 
 impl Daemon {
-    pub async fn new() -> Result<Self> {
-        let (player, _event_rx) = Player::new()?;
+    /// Plays through `backend`, or the output device from the config when
+    /// not given. Clients connect on `listen`; settings, caches and resume
+    /// positions are kept in `storage`.
+    pub async fn new(backend: Option<BackendSpec>, listen: &str, storage: Storage) -> Result<Self> {
+        let config = storage.config_file().and_then(|file| DaemonConfig::load(&file)).unwrap_or_else(|e| {
+            error!("Daemon config unavailable: {}", e);
+            DaemonConfig::default()
+        });
+        let backend = backend.unwrap_or_else(|| BackendSpec::Device(config.output_device.clone()));
+        let (player, events) = Player::new(&backend, &storage)?;
+        let channels = config.channels;
+        player.set_channels(Some(channels.balance), Some(channels.mono), Some(channels.swap)).await?;
+        let silence = config.silence;
+        player.set_silence(silence.mode, Some(silence.threshold_db), Some(silence.min_seconds)).await?;
         let playlist = Arc::new(Mutex::new(Playlist::new()));
-        let ipc_server = IpcServer::new().await?;
+        let ipc_server = IpcServer::bind(listen).await?;
+        let analysis_tx = loudness::spawn_worker(player.gain_cache());
+        let resume = storage.data_file("positions.json").map(ResumeStore::open).unwrap_or_else(|e| {
+            error!("Resume positions unavailable: {}", e);
+            ResumeStore::default()
+        });
 
         Ok(Self {
             player: Arc::new(player),
             playlist,
             ipc_server,
-            last_manual_command: std::sync::Mutex::new(std::time::Instant::now() - std::time::Duration::from_secs(10)), // Initialize to past
+            analysis_tx,
+            config: std::sync::Mutex::new(config),
+            storage,
+            events,
+            sleep_timer: std::sync::Mutex::new(None),
+            resume: std::sync::Mutex::new(resume),
         })
     }
 
     pub async fn run(&mut self) -> Result<()> {
         // info!("Daemon started");
-
-        let mut next_track_check = tokio::time::interval(tokio::time::Duration::from_millis(500));
+        let mut sleep_tick = tokio::time::interval(SLEEP_TIMER_TICK);
+        let mut resume_tick = tokio::time::interval(RESUME_SAVE_INTERVAL);
 
         loop {
             // Accept incoming connections (non-blocking)
@@ -60,49 +102,290 @@ impl Daemon {
                         }
                     }
                 }
-                _ = next_track_check.tick() => {
-                    // Check if current track ended and play next (less frequently)
-                    // Only auto-play if it's been at least 2 seconds since last manual command
-                    let time_since_manual = self.last_manual_command.lock().unwrap().elapsed();
-                    if time_since_manual > std::time::Duration::from_secs(2) &&
-                       self.player.is_empty() && self.player.get_state() == PlaybackState::Playing {
-                        if let Some(next_track) = self.playlist.lock().await.next() {
-                            // info!("Auto-playing next track: {}", next_track);
-                            if let Err(e) = self.player.load_track(next_track) {
-                                // error!("Failed to load next track: {}", e);
-                            }
-                        }
+                Some(event) = self.events.recv() => {
+                    self.handle_event(event).await;
+                }
+                _ = sleep_tick.tick() => {
+                    self.check_sleep_timer().await;
+                }
+                _ = resume_tick.tick() => {
+                    if self.player.snapshot().state == PlaybackState::Playing {
+                        self.save_position().await;
                     }
                 }
             }
         }
     }
 
-    async fn handle_command(&self, command: Command) -> Response {
-        // Update last manual command timestamp for commands that change tracks
-        match command {
-            Command::Play { .. } | Command::Next | Command::Previous | Command::Stop => {
-                *self.last_manual_command.lock().unwrap() = std::time::Instant::now();
+    async fn handle_event(&self, event: PlayerEvent) {
+        match event {
+            PlayerEvent::TrackFinished { path } => self.track_finished(path).await,
+            PlayerEvent::TrackStarted { path, metadata, duration } => {
+                let title = metadata.title.as_deref().unwrap_or(&path);
+                match metadata.artist {
+                    Some(artist) => info!("Now playing: {} - {} ({:.0}s)", artist, title, duration),
+                    None => info!("Now playing: {} ({:.0}s)", title, duration),
+                }
+            }
+            PlayerEvent::StateChanged(state) => debug!("Playback state: {:?}", state),
+            PlayerEvent::VolumeChanged { level, muted } => debug!("Volume: {}{}", level, if muted { " (muted)" } else { "" }),
+            PlayerEvent::Seeked { position } => debug!("Seeked to {:.1}s", position),
+            PlayerEvent::DecodeError { path, message, count } => warn!("{} in {} ({} so far)", message, path, count),
+            PlayerEvent::StreamTitle { path, title } => info!("Now playing on {}: {}", path, title),
+        }
+    }
+
+    /// Moves the playlist along when the current track has played out.
+    async fn track_finished(&self, path: String) {
+        // Finished tracks that were already replaced don't move the playlist
+        if self.player.snapshot().current_track.as_deref() != Some(path.as_str()) {
+            return;
+        }
+
+        // Played to the end, so it starts over next time
+        let save = self.resume.lock().unwrap().forget(&path);
+        self.save_positions(save).await;
+
+        let last = self.playlist.lock().await.peek_next().is_none();
+        let expired = self.sleep_timer.lock().unwrap().as_mut().is_some_and(|timer| {
+            timer.track_ended(last);
+            timer.expired()
+        });
+        if expired {
+            // Stop right here, the playlist staying on the finished track
+            self.check_sleep_timer().await;
+            return;
+        }
+
+        // The queued track took over without a gap
+        if matches!(self.player.poll_transition().await, Ok(Some(_))) {
+            self.playlist.lock().await.next();
+            self.preload_next().await;
+        } else if self.play_next().await.is_err() {
+            // Nothing was queued in time and loading the next entry failed,
+            // or this was the end of the playlist
+            if let Err(e) = self.player.stop().await {
+                error!("Failed to stop: {}", e);
+            }
+        }
+    }
+
+    /// Fades out as the sleep timer runs down, and pauses or stops once it
+    /// has run out. The volume is back to normal afterwards.
+    async fn check_sleep_timer(&self) {
+        if self.sleep_timer.lock().unwrap().is_none() {
+            return;
+        }
+        let last_track = self.playlist.lock().await.peek_next().is_none();
+        let track_left = track_left(&self.player.snapshot());
+
+        let (fade, action) = {
+            let mut timer = self.sleep_timer.lock().unwrap();
+            let Some(running) = timer.as_mut() else {
+                return;
+            };
+            match running.remaining(track_left, last_track) {
+                Some(left) if left.is_zero() => {
+                    let action = running.action();
+                    *timer = None;
+                    (Some(1.0), Some(action))
+                }
+                Some(left) => (running.fade(left), None),
+                None => (None, None),
+            }
+        };
+
+        if let Some(action) = action {
+            info!("Sleep timer ran out");
+            self.save_position().await;
+            let result = match action {
+                SleepAction::Pause => self.player.pause().await,
+                SleepAction::Stop => self.player.stop().await,
+            };
+            if let Err(e) = result {
+                error!("Failed to stop for the sleep timer: {}", e);
             }
-            _ => {}
+        }
+        if let Some(gain) = fade {
+            if let Err(e) = self.player.set_fade(gain).await {
+                error!("Failed to fade out: {}", e);
+            }
+        }
+    }
+
+    /// Whether a track `duration` seconds long is resumed where it was
+    /// left. Unknown lengths count as short.
+    fn resumes(&self, duration: f64) -> bool {
+        let audiobook = self.config.lock().unwrap().audiobook;
+        audiobook.enabled && duration >= audiobook.min_minutes * 60.0
+    }
+
+    /// Remembers where the current track is, if it's one that gets resumed.
+    async fn save_position(&self) {
+        let player = self.player.snapshot();
+        let Some(path) = &player.current_track else {
+            return;
+        };
+        if !self.resumes(player.duration) {
+            return;
+        }
+        let save = self.resume.lock().unwrap().remember(path, player.position(), player.duration);
+        self.save_positions(save).await;
+    }
+
+    /// Writes changed resume positions out on the blocking pool
+    async fn save_positions(&self, save: Result<Option<PendingSave>>) {
+        let written = match save {
+            Ok(Some(save)) => tokio::task::spawn_blocking(move || save.write())
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|written| written),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            error!("Failed to save resume positions: {:#}", e);
+        }
+    }
+
+    /// Moves on to the next playlist entry, skipping up to five that fail
+    /// to load.
+    async fn play_next(&self) -> Result<()> {
+        let mut playlist = self.playlist.lock().await;
+        // Try up to 5 tracks to find one that loads successfully
+        for _ in 0..5 {
+            if let Some(next_track) = playlist.next() {
+                drop(playlist);
+                match self.load_track(next_track.clone()).await {
+                    Ok(_) => {
+                        // info!("Playing next: {}", next_track);
+                        self.preload_next().await;
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        warn!("Failed to load {}: {:#}, trying the next track", next_track, e);
+                        playlist = self.playlist.lock().await;
+                        continue;
+                    }
+                }
+            } else {
+                drop(playlist);
+                break;
+            }
+        }
+        anyhow::bail!("No playable next track found")
+    }
+
+    /// Follows the player into a preloaded track once its audio has started,
+    /// and queues up the one after it.
+    async fn sync_transition(&self) {
+        if matches!(self.player.poll_transition().await, Ok(Some(_))) {
+            self.playlist.lock().await.next();
+            self.preload_next().await;
+        }
+    }
+
+    /// Decodes the upcoming playlist entry so it plays without a gap.
+    async fn preload_next(&self) {
+        if self.player.snapshot().current_track.is_none() {
+            return;
         }
 
+        let next_track = self.playlist.lock().await.peek_next();
+        match next_track {
+            // Streams connect once they're reached rather than buffering
+            // behind the current track
+            Some(path) if !stream::is_url(&path) => {
+                let queued = match self.open_resumed(path).await {
+                    Ok(track) => self.player.queue_next(track).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = queued {
+                    error!("Failed to preload next track: {}", e);
+                }
+            }
+            _ => {
+                if let Err(e) = self.player.clear_next().await {
+                    error!("Failed to clear next track: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Opens and probes `path` on the blocking pool, so a large file doesn't
+    /// hold up the IPC loop.
+    async fn open_track(&self, path: String) -> Result<PreparedTrack> {
+        let loader = self.player.loader();
+        tokio::task::spawn_blocking(move || loader.open(&path)).await?
+    }
+
+    /// Opens `path` for playing from where it was left, or from the start.
+    async fn open_resumed(&self, path: String) -> Result<PreparedTrack> {
+        let mut track = self.open_track(path.clone()).await?;
+        if self.resumes(track.duration()) {
+            let position = self.resume.lock().unwrap().position(&path);
+            if let Some(position) = position {
+                match track.start_at(position) {
+                    Ok(_) => info!("Resuming {} at {:.0}s", path, position),
+                    Err(e) => warn!("Failed to resume {}: {}", path, e),
+                }
+            }
+        }
+        Ok(track)
+    }
+
+    /// Plays `path` in place of the current track, whose position is saved
+    /// first.
+    async fn load_track(&self, path: String) -> Result<()> {
+        self.save_position().await;
+        let track = self.open_resumed(path).await?;
+        self.player.load_track(track).await
+    }
+
+    /// Moves playback to another output device and remembers the choice.
+    async fn switch_output(&self, name: Option<String>) -> Result<()> {
+        let current = match self.player.snapshot().current_track {
+            Some(path) => Some(self.open_track(path).await?),
+            None => None,
+        };
+        self.player.set_output_device(name.clone(), current).await?;
+        self.preload_next().await;
+
+        self.update_config(|config| config.output_device = name);
+        Ok(())
+    }
+
+    /// Changes the daemon config and writes it out. Failing to save is
+    /// logged, the setting still applies until the daemon exits.
+    fn update_config(&self, f: impl FnOnce(&mut DaemonConfig)) {
+        let mut config = self.config.lock().unwrap();
+        f(&mut config);
+        if let Err(e) = self.storage.config_file().and_then(|file| config.save(&file)) {
+            error!("Failed to save daemon config: {}", e);
+        }
+    }
+
+    async fn handle_command(&self, command: Command) -> Response {
+        // Catch up with a gapless transition before looking at the playlist
+        self.sync_transition().await;
+
         match command {
             Command::Play { path } => {
                 if let Some(path) = path {
                     // Play specific file
-                    match self.player.load_track(path.clone()) {
+                    match self.load_track(path.clone()).await {
                         Ok(_) => {
                             // info!("Playing: {}", path);
+                            self.preload_next().await;
                             Response::Ok
                         }
                         Err(e) => Response::Error(format!("Failed to play: {}", e)),
                     }
                 } else {
                     // Resume current track or start first track from playlist
-                    if self.player.get_current_track().is_some() {
+                    if self.player.snapshot().current_track.is_some() {
                         // Resume if there's a current track
-                        match self.player.play() {
+                        match self.player.play().await {
                         Ok(_) => {
                             // info!("Resumed playback");
                             Response::Ok
@@ -120,9 +403,10 @@ impl Daemon {
                             // next() will return first track if current_index is None
                             if let Some(first_track) = playlist.current().or_else(|| playlist.next()) {
                                 drop(playlist);
-                                match self.player.load_track(first_track.clone()) {
+                                match self.load_track(first_track.clone()).await {
                                     Ok(_) => {
                                         // info!("Playing first track: {}", first_track);
+                                        self.preload_next().await;
                                         Response::Ok
                                     }
                                     Err(e) => Response::Error(format!("Failed to play first track: {}", e)),
@@ -135,53 +419,40 @@ impl Daemon {
                     }
                 }
             }
-            Command::Pause => match self.player.pause() {
-                Ok(_) => {
-                    // info!("Paused");
-                    Response::Ok
-                }
-                Err(e) => Response::Error(format!("Failed to pause: {}", e)),
-            },
-            Command::Stop => match self.player.stop() {
-                Ok(_) => {
-                    // info!("Stopped");
-                    Response::Ok
+            Command::Pause => {
+                self.save_position().await;
+                match self.player.pause().await {
+                    Ok(_) => {
+                        // info!("Paused");
+                        Response::Ok
+                    }
+                    Err(e) => Response::Error(format!("Failed to pause: {}", e)),
                 }
-                Err(e) => Response::Error(format!("Failed to stop: {}", e)),
-            },
-            Command::Next => {
-                let mut playlist = self.playlist.lock().await;
-                // Try up to 5 tracks to find one that loads successfully
-                for _ in 0..5 {
-                    if let Some(next_track) = playlist.next() {
-                        drop(playlist);
-                        match self.player.load_track(next_track.clone()) {
-                            Ok(_) => {
-                                // info!("Playing next: {}", next_track);
-                                return Response::Ok;
-                            }
-                            Err(e) => {
-                                // error!("Failed to load track {}: {}, trying next", next_track, e);
-                                playlist = self.playlist.lock().await;
-                                continue;
-                            }
-                        }
-                    } else {
-                        drop(playlist);
-                        break;
+            }
+            Command::Stop => {
+                self.save_position().await;
+                match self.player.stop().await {
+                    Ok(_) => {
+                        // info!("Stopped");
+                        Response::Ok
                     }
+                    Err(e) => Response::Error(format!("Failed to stop: {}", e)),
                 }
-                Response::Error("No playable next track found".to_string())
             }
+            Command::Next => match self.play_next().await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.to_string()),
+            },
             Command::Previous => {
                 let mut playlist = self.playlist.lock().await;
                 // Try up to 5 tracks to find one that loads successfully
                 for _ in 0..5 {
                     if let Some(prev_track) = playlist.previous() {
                         drop(playlist);
-                        match self.player.load_track(prev_track.clone()) {
+                        match self.load_track(prev_track.clone()).await {
                             Ok(_) => {
                                 // info!("Playing previous: {}", prev_track);
+                                self.preload_next().await;
                                 return Response::Ok;
                             }
                             Err(e) => {
@@ -197,36 +468,233 @@ impl Daemon {
                 }
                 Response::Error("No playable previous track found".to_string())
             }
-            Command::SetVolume { level } => match self.player.set_volume(level) {
+            Command::SetVolume { level } => match self.player.set_volume(level).await {
                 Ok(_) => {
                     // info!("Volume set to {}", level);
                     Response::Ok
                 }
                 Err(e) => Response::Error(format!("Failed to set volume: {}", e)),
             },
-            Command::AddTracks { paths } => {
-                let mut playlist = self.playlist.lock().await;
-                match playlist.add_tracks(paths.clone()) {
+            Command::ToggleMute => match self.player.toggle_mute().await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to toggle mute: {}", e)),
+            },
+            Command::SetVolumeBoost { enabled } => match self.player.set_volume_boost(enabled).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to set volume boost: {}", e)),
+            },
+            Command::Seek { position } => match self.player.seek(position).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to seek: {}", e)),
+            },
+            Command::SeekRelative { offset } => match self.player.seek_relative(offset).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to seek: {}", e)),
+            },
+            Command::SetLoopA { position } => match self.player.set_loop_a(position).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to set loop start: {}", e)),
+            },
+            Command::SetLoopB { position } => match self.player.set_loop_b(position).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to set loop end: {}", e)),
+            },
+            Command::ClearLoop => match self.player.clear_loop().await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to clear loop: {}", e)),
+            },
+            Command::NextChapter => match self.player.next_chapter().await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to skip to next chapter: {}", e)),
+            },
+            Command::PrevChapter => match self.player.prev_chapter().await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to go back a chapter: {}", e)),
+            },
+            Command::SetSpeed { factor } => match self.player.set_speed(factor).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to set speed: {}", e)),
+            },
+            Command::SetSleepTimer { mode } => {
+                if matches!(mode, SleepTimerMode::Minutes(minutes) if minutes.is_nan() || minutes <= 0.0 || minutes > MAX_MINUTES) {
+                    return Response::Error("Sleep timer must be between 0 and 24 hours".to_string());
+                }
+                *self.sleep_timer.lock().unwrap() = Some(SleepTimer::new(mode));
+                // A timer replaced halfway through its fade starts over at full volume
+                match self.player.set_fade(1.0).await {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Error(format!("Failed to set sleep timer: {}", e)),
+                }
+            }
+            Command::CancelSleepTimer => {
+                *self.sleep_timer.lock().unwrap() = None;
+                match self.player.set_fade(1.0).await {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Error(format!("Failed to cancel sleep timer: {}", e)),
+                }
+            }
+            Command::SetAudiobookMode { enabled, min_minutes } => {
+                if min_minutes.is_some_and(|minutes| !minutes.is_finite() || minutes < 0.0) {
+                    return Response::Error("Invalid minimum track length".to_string());
+                }
+                // The current track may stop counting as long, so save it first
+                self.save_position().await;
+                self.update_config(|config| {
+                    config.audiobook.enabled = enabled.unwrap_or(config.audiobook.enabled);
+                    config.audiobook.min_minutes = min_minutes.unwrap_or(config.audiobook.min_minutes);
+                });
+                Response::Ok
+            }
+            Command::SetCrossfade { seconds, curve, fade_on_skip } => {
+                match self.player.set_crossfade(seconds, curve, fade_on_skip).await {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Error(format!("Failed to set crossfade: {}", e)),
+                }
+            }
+            Command::SetReplayGain { mode, preamp, prevent_clipping } => {
+                match self.player.set_replaygain(mode, preamp, prevent_clipping).await {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Error(format!("Failed to set ReplayGain: {}", e)),
+                }
+            }
+            Command::SetChannels { balance, mono, swap } => match self.player.set_channels(balance, mono, swap).await {
+                Ok(_) => {
+                    let channels = self.player.snapshot().channels;
+                    self.update_config(|config| config.channels = channels);
+                    Response::Ok
+                }
+                Err(e) => Response::Error(format!("Failed to set channels: {}", e)),
+            },
+            Command::SetSilence { mode, threshold_db, min_seconds } => {
+                match self.player.set_silence(mode, threshold_db, min_seconds).await {
                     Ok(_) => {
-                        // info!("Added {} tracks", paths.len());
+                        let silence = self.player.snapshot().silence;
+                        self.update_config(|config| config.silence = silence);
+                        Response::Ok
+                    }
+                    Err(e) => Response::Error(format!("Failed to set silence handling: {}", e)),
+                }
+            }
+            Command::SetEqBand { band, gain } => match self.player.set_eq_band(band, gain).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to set equalizer band: {}", e)),
+            },
+            Command::SetEqPreset { name } => match self.player.set_eq_preset(name).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to load preset: {}", e)),
+            },
+            Command::SaveEqPreset { name } => match self.player.save_eq_preset(name).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to save preset: {}", e)),
+            },
+            Command::SetEqEnabled { enabled } => match self.player.set_eq_enabled(enabled).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to switch equalizer: {}", e)),
+            },
+            Command::GetEq => Response::Equalizer(self.player.snapshot().eq),
+            Command::ListOutputDevices => match output::list_devices() {
+                Ok(devices) => Response::OutputDevices(OutputDeviceList {
+                    devices,
+                    selected: self.player.snapshot().output_device,
+                    default: output::default_device(),
+                }),
+                Err(e) => Response::Error(format!("Failed to list output devices: {}", e)),
+            },
+            Command::SetOutputDevice { name } => match self.switch_output(name).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(format!("Failed to switch output device: {}", e)),
+            },
+            Command::AddTracks { paths } => {
+                // Looking through directories and FLAC files for cue sheets
+                // takes a while, so it's done before the playlist is locked
+                let collected = tokio::task::spawn_blocking(move || {
+                    let collected = paths.iter().map(|path| playlist::collect_tracks(path)).collect::<Result<Vec<_>>>()?;
+                    // Cue tracks are measured as the file they're cut from,
+                    // once for the whole album
+                    let mut files = Vec::new();
+                    let mut seen = HashSet::new();
+                    for entry in collected.iter().flatten().filter(|entry| !stream::is_url(entry)) {
+                        match cue::audio_file(entry) {
+                            Some(file) if seen.insert(file.clone()) => files.push(file),
+                            _ => {}
+                        }
+                    }
+                    anyhow::Ok((collected, files))
+                })
+                .await;
+                match collected.map_err(anyhow::Error::from).and_then(|collected| collected) {
+                    Ok((collected, files)) => {
+                        let mut playlist = self.playlist.lock().await;
+                        for entries in collected {
+                            playlist.add_entries(entries);
+                        }
+                        drop(playlist);
+                        // Measure untagged files so normalization covers them too
+                        let _ = self.analysis_tx.send(AnalysisJob {
+                            files,
+                            skip_tagged: true,
+                            force: false,
+                        });
+                        self.preload_next().await;
                         Response::Ok
                     }
                     Err(e) => Response::Error(format!("Failed to add tracks: {}", e)),
                 }
             }
+            Command::Analyze { paths } => {
+                // A large library takes a while to walk, like for AddTracks
+                let files = tokio::task::spawn_blocking(move || {
+                    paths.iter().flat_map(|p| playlist::collect_audio_files(p)).collect()
+                })
+                .await;
+                let files = match files {
+                    Ok(files) => files,
+                    Err(e) => return Response::Error(format!("Failed to start analysis: {}", e)),
+                };
+                let job = AnalysisJob {
+                    files,
+                    skip_tagged: false,
+                    force: false,
+                };
+                match self.analysis_tx.send(job) {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Error(format!("Failed to start analysis: {}", e)),
+                }
+            }
             Command::GetStatus => {
                 let playlist = self.playlist.lock().await;
+                let player = self.player.snapshot();
+                let sleep_timer = self.sleep_timer.lock().unwrap().as_ref().map(|timer| {
+                    timer.status(timer.remaining(track_left(&player), playlist.peek_next().is_none()))
+                });
                 let status = PlayerStatus {
-                    state: self.player.get_state(),
-                    current_track: self.player.get_current_track(),
-                    position: self.player.get_position(),
-                    duration: self.player.get_duration(),
-                    volume: self.player.get_volume(),
+                    position: player.position(),
+                    remaining: player.remaining(),
+                    chapter: player.chapter(),
+                    chapters: player.chapters.to_vec(),
+                    decode_errors: player.decode_errors(),
+                    stream_title: player.stream_title(),
+                    state: player.state,
+                    current_track: player.current_track,
+                    duration: player.duration,
+                    speed: player.speed,
+                    volume: player.volume,
+                    muted: player.muted,
+                    volume_boost: player.volume_boost,
                     playlist_length: playlist.len(),
                     current_index: playlist.current_index(),
+                    crossfade: player.crossfade.seconds,
+                    replaygain: player.replaygain.mode,
+                    channels: player.channels,
+                    silence: player.silence,
+                    sleep_timer,
+                    loop_a: player.loop_a,
+                    loop_b: player.loop_b,
+                    audiobook: self.config.lock().unwrap().audiobook,
                 };
                 Response::Status(status)
             }
+            Command::GetVisualization => Response::Visualization(self.player.visualization()),
             Command::GetPlaylist => {
                 let playlist = self.playlist.lock().await;
                 Response::Playlist(playlist.get_tracks())
@@ -234,10 +702,15 @@ impl Daemon {
             Command::ClearPlaylist => {
                 let mut playlist = self.playlist.lock().await;
                 playlist.clear();
+                drop(playlist);
+                if let Err(e) = self.player.clear_next().await {
+                    return Response::Error(format!("Failed to clear playlist: {}", e));
+                }
                 // info!("Playlist cleared");
                 Response::Ok
             }
             Command::Shutdown => {
+                self.save_position().await;
                 // info!("Shutting down daemon");
                 std::process::exit(0);
             }
@@ -245,6 +718,12 @@ impl Daemon {
     }
 }
 
+/// What's left of the current track, `None` when nothing with a known
+/// length is playing.
+fn track_left(player: &PlayerSnapshot) -> Option<Duration> {
+    (player.current_track.is_some() && player.duration > 0.0).then(|| Duration::from_secs_f64(player.remaining()))
+}
+
 fn get_pid_file() -> Result<PathBuf> {
     let dirs = directories::ProjectDirs::from("", "", "musicplayer")
         .context("Failed to get project directories")?;
@@ -255,7 +734,7 @@ fn get_pid_file() -> Result<PathBuf> {
     Ok(data_dir.join("daemon.pid"))
 }
 
-pub async fn start() -> Result<()> {
+pub async fn start(backend: Option<BackendSpec>) -> Result<()> {
     // Check if daemon is already running
     let pid_file = get_pid_file()?;
 
@@ -279,7 +758,7 @@ pub async fn start() -> Result<()> {
     // info!("Starting daemon (PID: {})...", pid);
 
     // Create and run daemon
-    let mut daemon = Daemon::new().await?;
+    let mut daemon = Daemon::new(backend, ipc::get_socket_addr(), Storage::user()?).await?;
 
     // info!("Daemon started successfully");
 
@@ -309,3 +788,269 @@ fn is_process_running(pid: i32) -> bool {
         false
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::RenderSteps;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    // Commands render a little while they wait for the output
+    const TOLERANCE: f64 = 0.05;
+
+    /// A daemon playing into a stepped null backend, with its files and
+    /// tracks in a temporary directory.
+    struct Harness {
+        daemon: Daemon,
+        steps: RenderSteps,
+        tracks: Vec<String>,
+        _dir: TempDir,
+    }
+
+    impl Harness {
+        /// Starts a daemon with tracks of the given lengths in its playlist
+        async fn start(lengths: &[f64]) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let tracks: Vec<String> = lengths
+                .iter()
+                .enumerate()
+                .map(|(i, &seconds)| {
+                    let path = dir.path().join(format!("track{}.wav", i + 1));
+                    write_tone(&path, seconds);
+                    path.to_str().unwrap().to_string()
+                })
+                .collect();
+
+            let steps = RenderSteps::default();
+            let daemon = Daemon::new(Some(BackendSpec::Stepped(steps.clone())), "127.0.0.1:0", Storage::in_dir(dir.path()))
+                .await
+                .unwrap();
+            let mut harness = Self { daemon, steps, tracks, _dir: dir };
+            let paths = harness.tracks.clone();
+            harness.command(Command::AddTracks { paths }).await;
+            harness
+        }
+
+        /// Runs `command`, which has to succeed. Stopping and seeking wait
+        /// for the output to get there, so it keeps rendering meanwhile.
+        async fn command(&mut self, command: Command) {
+            let response = self.daemon.handle_command(command);
+            tokio::pin!(response);
+            let response = loop {
+                tokio::select! {
+                    response = &mut response => break response,
+                    _ = render(&self.steps, 0.005) => {}
+                }
+            };
+            assert!(matches!(response, Response::Ok), "{:?}", response);
+        }
+
+        /// Plays `seconds` of audio, then lets the daemon react to what
+        /// happened meanwhile.
+        async fn render(&mut self, seconds: f64) {
+            render(&self.steps, seconds).await;
+            while let Ok(event) = self.daemon.events.try_recv() {
+                self.daemon.handle_event(event).await;
+            }
+        }
+
+        fn player(&self) -> PlayerSnapshot {
+            self.daemon.player.snapshot()
+        }
+
+        async fn playlist_index(&self) -> Option<usize> {
+            self.daemon.playlist.lock().await.current_index()
+        }
+    }
+
+    async fn render(steps: &RenderSteps, seconds: f64) {
+        let steps = steps.clone();
+        tokio::task::spawn_blocking(move || steps.advance(Duration::from_secs_f64(seconds)))
+            .await
+            .unwrap();
+    }
+
+    /// Writes `seconds` of a 440 Hz tone as 16-bit stereo WAV at 44.1 kHz
+    fn write_tone(path: &Path, seconds: f64) {
+        let frames = (seconds * 44100.0) as u32;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + frames * 4).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&44100u32.to_le_bytes());
+        wav.extend_from_slice(&(44100u32 * 4).to_le_bytes());
+        wav.extend_from_slice(&4u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(frames * 4).to_le_bytes());
+        for frame in 0..frames {
+            let sample = ((frame as f64 * 440.0 * std::f64::consts::TAU / 44100.0).sin() * 8000.0) as i16;
+            wav.extend_from_slice(&sample.to_le_bytes());
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+        fs::write(path, wav).unwrap();
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < TOLERANCE, "at {:.3}s, expected {:.3}s", actual, expected);
+    }
+
+    #[tokio::test]
+    async fn play_starts_the_first_track() {
+        let mut harness = Harness::start(&[5.0, 5.0]).await;
+        harness.command(Command::Play { path: None }).await;
+
+        let player = harness.player();
+        assert_eq!(player.state, PlaybackState::Playing);
+        assert_eq!(player.current_track.as_ref(), Some(&harness.tracks[0]));
+        assert_eq!(harness.playlist_index().await, Some(0));
+
+        harness.render(1.0).await;
+        assert_near(harness.player().position(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn next_plays_the_following_track() {
+        let mut harness = Harness::start(&[5.0, 5.0]).await;
+        harness.command(Command::Play { path: None }).await;
+        harness.render(1.0).await;
+
+        harness.command(Command::Next).await;
+        assert_eq!(harness.player().current_track.as_ref(), Some(&harness.tracks[1]));
+        assert_eq!(harness.playlist_index().await, Some(1));
+
+        harness.render(0.5).await;
+        assert_near(harness.player().position(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn fading_skip_plays_the_track_skipped_to() {
+        let mut harness = Harness::start(&[5.0, 1.0, 5.0]).await;
+        harness
+            .command(Command::SetCrossfade { seconds: 0.0, curve: None, fade_on_skip: Some(true) })
+            .await;
+        harness.command(Command::Play { path: None }).await;
+        harness.render(1.0).await;
+
+        // Preloads the third track while the fade to the second is pending
+        harness.command(Command::Next).await;
+        assert_eq!(harness.player().current_track.as_ref(), Some(&harness.tracks[1]));
+
+        // The second track plays out before the third follows
+        harness.render(0.5).await;
+        assert_eq!(harness.player().current_track.as_ref(), Some(&harness.tracks[1]));
+        harness.render(1.0).await;
+        let player = harness.player();
+        assert_eq!(player.current_track.as_ref(), Some(&harness.tracks[2]));
+        assert_eq!(harness.playlist_index().await, Some(2));
+        assert_near(player.position(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn seek_moves_the_position() {
+        let mut harness = Harness::start(&[5.0]).await;
+        harness.command(Command::Play { path: None }).await;
+        harness.render(0.5).await;
+
+        harness.command(Command::Seek { position: 3.0 }).await;
+        assert_near(harness.player().position(), 3.0);
+
+        harness.render(1.0).await;
+        assert_near(harness.player().position(), 4.0);
+    }
+
+    #[tokio::test]
+    async fn position_holds_while_paused() {
+        let mut harness = Harness::start(&[5.0]).await;
+        harness.command(Command::Play { path: None }).await;
+        harness.render(1.0).await;
+
+        harness.command(Command::Pause).await;
+        // The sink looks at its pause flag every few milliseconds
+        harness.render(0.05).await;
+        let paused = harness.player().position();
+        assert_near(paused, 1.0);
+        harness.render(1.0).await;
+        assert_eq!(harness.player().position(), paused);
+
+        harness.command(Command::Play { path: None }).await;
+        harness.render(0.5).await;
+        assert_near(harness.player().position(), paused + 0.5);
+    }
+
+    #[tokio::test]
+    async fn finished_track_moves_on_to_the_next() {
+        let mut harness = Harness::start(&[1.0, 5.0]).await;
+        harness.command(Command::Play { path: None }).await;
+
+        harness.render(1.5).await;
+        let player = harness.player();
+        assert_eq!(player.state, PlaybackState::Playing);
+        assert_eq!(player.current_track.as_ref(), Some(&harness.tracks[1]));
+        assert_eq!(harness.playlist_index().await, Some(1));
+        assert_near(player.position(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn unloadable_track_is_skipped_when_moving_on() {
+        let mut harness = Harness::start(&[1.0, 5.0, 5.0]).await;
+        fs::remove_file(&harness.tracks[1]).unwrap();
+        harness.command(Command::Play { path: None }).await;
+
+        harness.render(1.5).await;
+        let player = harness.player();
+        assert_eq!(player.state, PlaybackState::Playing);
+        assert_eq!(player.current_track.as_ref(), Some(&harness.tracks[2]));
+        assert_eq!(harness.playlist_index().await, Some(2));
+
+        // Started once the first one's end was handled, and carries on
+        let started = harness.player().position();
+        harness.render(1.0).await;
+        let player = harness.player();
+        assert_eq!(player.current_track.as_ref(), Some(&harness.tracks[2]));
+        assert_near(player.position(), started + 1.0);
+    }
+
+    #[tokio::test]
+    async fn playlist_moves_on_when_the_audio_crosses_over() {
+        let mut harness = Harness::start(&[1.0, 5.0]).await;
+        harness.command(Command::Play { path: None }).await;
+
+        harness.render(0.9).await;
+        assert_eq!(harness.player().current_track.as_ref(), Some(&harness.tracks[0]));
+        assert_eq!(harness.playlist_index().await, Some(0));
+
+        // Preloaded, so the second track starts the sample the first ends
+        harness.render(0.2).await;
+        let player = harness.player();
+        assert_eq!(player.current_track.as_ref(), Some(&harness.tracks[1]));
+        assert_eq!(harness.playlist_index().await, Some(1));
+        assert_near(player.position(), 0.1);
+    }
+
+    #[tokio::test]
+    async fn last_track_finishing_stops() {
+        let mut harness = Harness::start(&[1.0]).await;
+        harness.command(Command::Play { path: None }).await;
+
+        harness.render(1.5).await;
+        assert_eq!(harness.player().state, PlaybackState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn end_of_track_timer_stops_before_the_next_track() {
+        let mut harness = Harness::start(&[1.0, 5.0]).await;
+        harness.command(Command::SetSleepTimer { mode: SleepTimerMode::EndOfTrack }).await;
+        harness.command(Command::Play { path: None }).await;
+
+        harness.render(1.5).await;
+        assert_eq!(harness.player().state, PlaybackState::Stopped);
+        assert_eq!(harness.playlist_index().await, Some(0));
+        assert!(harness.daemon.sleep_timer.lock().unwrap().is_none());
+    }
+}
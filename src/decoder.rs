@@ -0,0 +1,586 @@
+use anyhow::{Context, Result};
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{
+    Decoder, DecoderOptions, CODEC_TYPE_MP1, CODEC_TYPE_MP2, CODEC_TYPE_MP3, CODEC_TYPE_NULL,
+};
+use symphonia::core::errors::{Error, SeekErrorKind};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions, ReadOnlySource};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
+use tracing::warn;
+
+use crate::mp3;
+use crate::stream::HttpStream;
+
+// Read-ahead for decoding straight from disk
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// Undecodable packets or unreadable stretches in a row before the rest of
+// the file is given up on
+const MAX_CONSECUTIVE_ERRORS: usize = 100;
+
+/// Descriptive tags of a track, as far as the file has them.
+#[derive(Debug, Clone, Default)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+impl TrackMetadata {
+    fn apply(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let slot = match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => &mut self.title,
+                Some(StandardTagKey::Artist) => &mut self.artist,
+                Some(StandardTagKey::Album) => &mut self.album,
+                _ => continue,
+            };
+            if slot.is_none() {
+                *slot = Some(tag.value.to_string());
+            }
+        }
+    }
+}
+
+/// Called with a description of each packet that had to be skipped
+pub type ErrorCallback = Box<dyn FnMut(&str) + Send>;
+
+/// Decodes any container and codec symphonia knows about. The format is
+/// detected from the file contents; the extension is only a hint.
+pub struct SymphoniaSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    path: String,
+    // Interleaved samples of the current packet, played from `pos` to `len`
+    buffer: Option<SampleBuffer<i16>>,
+    pos: usize,
+    len: usize,
+    channels: u16,
+    sample_rate: u32,
+    total_duration: Option<Duration>,
+    metadata: TrackMetadata,
+    on_error: Option<ErrorCallback>,
+    // Timestamp a seek landed before; earlier samples are dropped
+    seek_target: Option<u64>,
+    // Errors in a row, and in total since the file was opened
+    errors: usize,
+    total_errors: usize,
+    ended: bool,
+}
+
+// Size and modification time, which tell whether a file changed since
+type FileStamp = (u64, Option<SystemTime>);
+
+/// MP3 lengths found by counting frames, so opening a file again to
+/// preload, loop or resume it doesn't read it all through once more.
+/// Entries go stale when the file changes.
+#[derive(Default)]
+pub struct ScanCache {
+    durations: HashMap<String, (FileStamp, Option<Duration>)>,
+}
+
+impl ScanCache {
+    fn lookup(&self, path: &str, stamp: FileStamp) -> Option<Option<Duration>> {
+        self.durations
+            .get(path)
+            .filter(|(cached, _)| *cached == stamp)
+            .map(|&(_, duration)| duration)
+    }
+
+    fn insert(&mut self, path: &str, stamp: FileStamp, duration: Option<Duration>) {
+        self.durations.insert(path.to_string(), (stamp, duration));
+    }
+}
+
+impl SymphoniaSource {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_with(path, &Mutex::default())
+    }
+
+    /// Like `open`, looking up and keeping MP3 frame counts in `scans`
+    pub fn open_with(path: &str, scans: &Mutex<ScanCache>) -> Result<Self> {
+        let file = File::open(path)
+            .context(format!("Failed to open audio file: {}", path))?;
+        let stream = MediaSourceStream::new(
+            Box::new(file),
+            MediaSourceStreamOptions { buffer_len: READ_BUFFER_SIZE },
+        );
+
+        let mut hint = Hint::new();
+        if let Some(ext) = Path::new(path).extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+        let mut source = Self::probe(stream, &hint, path)?;
+
+        // Without a Xing/VBRI header symphonia guesses the length of an MP3
+        // from its first frames, so count them all instead
+        let codec = source.decoder.codec_params().codec;
+        if [CODEC_TYPE_MP1, CODEC_TYPE_MP2, CODEC_TYPE_MP3].contains(&codec) {
+            let file = File::open(path)
+                .context(format!("Failed to open audio file: {}", path))?;
+            let stamp = file.metadata().ok().map(|meta| (meta.len(), meta.modified().ok()));
+            // Not locked while scanning, so other opens don't wait on it
+            let cached = stamp.and_then(|stamp| scans.lock().unwrap().lookup(path, stamp));
+            let scanned = match cached {
+                Some(duration) => duration,
+                None => match mp3::scan_duration(BufReader::with_capacity(READ_BUFFER_SIZE, file)) {
+                    Ok(duration) => {
+                        if let Some(stamp) = stamp {
+                            scans.lock().unwrap().insert(path, stamp, duration);
+                        }
+                        duration
+                    }
+                    Err(e) => {
+                        warn!("Failed to scan MP3 frames of {}: {}", path, e);
+                        None
+                    }
+                },
+            };
+            if let Some(duration) = scanned {
+                source.total_duration = Some(duration);
+            }
+        }
+        Ok(source)
+    }
+
+    /// Decodes an HTTP stream, which can't seek and has no duration. The
+    /// station name stands in for the title.
+    pub fn open_stream(url: &str, stream: HttpStream) -> Result<Self> {
+        let mut hint = Hint::new();
+        if let Some(ext) = stream.extension() {
+            hint.with_extension(ext);
+        }
+        let name = stream.name().map(str::to_string);
+
+        let stream = MediaSourceStream::new(Box::new(ReadOnlySource::new(stream)), Default::default());
+        let mut source = Self::probe(stream, &hint, url)?;
+        if source.metadata.title.is_none() {
+            source.metadata.title = name;
+        }
+        Ok(source)
+    }
+
+    fn probe(stream: MediaSourceStream, hint: &Hint, path: &str) -> Result<Self> {
+        // Gapless trims encoder delay and padding, so queued tracks join exactly
+        let options = FormatOptions { enable_gapless: true, ..Default::default() };
+        let mut probed = symphonia::default::get_probe()
+            .format(hint, stream, &options, &MetadataOptions::default())
+            .context("Unrecognized audio format")?;
+
+        // Tags in front of the container (ID3v2) end up with the probe, the
+        // rest with the format reader
+        let mut metadata = TrackMetadata::default();
+        if let Some(revision) = probed.format.metadata().current() {
+            metadata.apply(revision);
+        }
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            metadata.apply(revision);
+        }
+        let format = probed.format;
+
+        let track = format
+            .default_track()
+            .filter(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .or_else(|| format.tracks().iter().find(|t| t.codec_params.codec != CODEC_TYPE_NULL))
+            .context("No audio track found")?;
+        let params = &track.codec_params;
+
+        let decoder = symphonia::default::get_codecs()
+            .make(params, &DecoderOptions::default())
+            .context("Unsupported audio codec")?;
+
+        let total_duration = params.n_frames.and_then(|frames| {
+            let time = match params.time_base {
+                Some(time_base) => time_base.calc_time(frames),
+                None => Time::from(frames as f64 / params.sample_rate? as f64),
+            };
+            Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+        });
+
+        let mut source = Self {
+            track_id: track.id,
+            channels: params.channels.map_or(2, |c| c.count() as u16),
+            sample_rate: params.sample_rate.unwrap_or(44100),
+            total_duration,
+            metadata,
+            on_error: None,
+            format,
+            decoder,
+            path: path.to_string(),
+            buffer: None,
+            pos: 0,
+            len: 0,
+            seek_target: None,
+            errors: 0,
+            total_errors: 0,
+            ended: false,
+        };
+
+        // Decode ahead so the format is known before the first sample is pulled
+        source.load_packet();
+        if source.len == 0 {
+            anyhow::bail!("Failed to decode audio file");
+        }
+        Ok(source)
+    }
+
+    pub fn metadata(&self) -> &TrackMetadata {
+        &self.metadata
+    }
+
+    /// Installs a callback for packets that fail to decode, instead of
+    /// logging them.
+    pub fn on_decode_error(&mut self, callback: ErrorCallback) {
+        self.on_error = Some(callback);
+    }
+
+    /// Damaged packets and stretches of the file skipped so far
+    pub fn decode_errors(&self) -> usize {
+        self.total_errors
+    }
+
+    /// Reports a damaged part of the file and moves past it, giving up when
+    /// nothing decodes any more.
+    fn skip_damaged(&mut self, message: String) {
+        match &mut self.on_error {
+            Some(callback) => callback(&message),
+            None => warn!("{} in {}", message, self.path),
+        }
+        self.errors += 1;
+        self.total_errors += 1;
+        self.ended = self.errors >= MAX_CONSECUTIVE_ERRORS;
+    }
+
+    /// Decodes packets until one yields samples. Damaged data is reported
+    /// and skipped, the reader resyncing on the packet after it; the buffer
+    /// stays empty at the end of the stream.
+    fn load_packet(&mut self) {
+        self.pos = 0;
+        self.len = 0;
+
+        while !self.ended {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::ResetRequired) => {
+                    // The track list changed (chained Ogg streams)
+                    if let Err(e) = self.reset_decoder() {
+                        warn!("Failed to restart decoding {}: {}", self.path, e);
+                        self.ended = true;
+                    }
+                    continue;
+                }
+                Err(Error::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    self.ended = true;
+                    continue;
+                }
+                Err(Error::DecodeError(e)) => {
+                    // A broken frame or page header; the reader searches for
+                    // the next one on the following call
+                    self.skip_damaged(format!("Skipped damaged data: {}", e));
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to read {}: {}", self.path, e);
+                    self.ended = true;
+                    continue;
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(e)) => {
+                    self.skip_damaged(format!("Skipped undecodable packet at {}: {}", packet.ts(), e));
+                    continue;
+                }
+                Err(Error::IoError(e)) => {
+                    self.skip_damaged(format!("Skipped truncated packet at {}: {}", packet.ts(), e));
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to decode {}: {}", self.path, e);
+                    self.ended = true;
+                    continue;
+                }
+            };
+            self.errors = 0;
+
+            let frames = decoded.frames();
+            let spec = *decoded.spec();
+            let channels = spec.channels.count();
+
+            // After a seek, drop what comes before the requested position
+            let mut skip = 0;
+            if let Some(target) = self.seek_target {
+                if packet.ts() + frames as u64 <= target {
+                    continue;
+                }
+                skip = target.saturating_sub(packet.ts()) as usize;
+                self.seek_target = None;
+            }
+            if frames == 0 || skip >= frames {
+                continue;
+            }
+
+            let needed = decoded.capacity() * channels;
+            if !matches!(&self.buffer, Some(b) if b.capacity() >= needed) {
+                self.buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+            }
+            let buffer = self.buffer.as_mut().unwrap();
+            buffer.copy_interleaved_ref(decoded);
+
+            self.channels = channels as u16;
+            self.sample_rate = spec.rate;
+            self.pos = skip * channels;
+            self.len = buffer.len();
+            return;
+        }
+    }
+
+    fn reset_decoder(&mut self) -> Result<()> {
+        let track = self
+            .format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .context("No audio track found")?;
+        self.track_id = track.id;
+        self.decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .context("Unsupported audio codec")?;
+        Ok(())
+    }
+}
+
+impl Iterator for SymphoniaSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.pos >= self.len {
+            return None;
+        }
+        let sample = self.buffer.as_ref()?.samples()[self.pos];
+
+        self.pos += 1;
+        // Move on eagerly so `current_frame_len` never reports an empty frame
+        if self.pos >= self.len {
+            self.load_packet();
+        }
+
+        Some(sample)
+    }
+}
+
+impl Source for SymphoniaSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        (self.pos < self.len).then(|| self.len - self.pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let to = SeekTo::Time {
+            time: Time::from(pos.as_secs_f64()),
+            track_id: Some(self.track_id),
+        };
+        match self.format.seek(SeekMode::Accurate, to) {
+            Ok(seeked) => {
+                self.seek_target = Some(seeked.required_ts);
+                self.ended = false;
+            }
+            Err(Error::SeekError(SeekErrorKind::OutOfRange)) => {
+                // Past the end: let the track finish
+                self.ended = true;
+            }
+            Err(e) => return Err(SeekError::Other(Box::new(e))),
+        }
+
+        // Decoder state belongs to the old position
+        self.decoder.reset();
+        self.errors = 0;
+        self.load_packet();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MPEG-1 Layer III, 44.1 kHz stereo
+    const FRAME_SAMPLES: u64 = 1152;
+    const SIDE_INFO_LEN: usize = 32;
+
+    /// One frame of silence at `kbps`; all-zero side info and main data
+    /// decode to nothing but zeros.
+    fn frame(kbps: u32) -> Vec<u8> {
+        let index = match kbps {
+            64 => 5,
+            128 => 9,
+            320 => 14,
+            _ => unreachable!(),
+        };
+        let mut frame = vec![0; (144_000 * kbps / 44100) as usize];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, index << 4, 0x00]);
+        frame
+    }
+
+    /// A VBR file of `bitrates.len()` frames, led by a Xing header that
+    /// counts them, like encoders write it.
+    fn vbr_file(bitrates: &[u32]) -> Vec<u8> {
+        let mut xing = frame(128);
+        let tag = 4 + SIDE_INFO_LEN;
+        xing[tag..tag + 4].copy_from_slice(b"Xing");
+        // Only the frame count is given
+        xing[tag + 4..tag + 8].copy_from_slice(&1u32.to_be_bytes());
+        xing[tag + 8..tag + 12].copy_from_slice(&(bitrates.len() as u32).to_be_bytes());
+
+        let mut file = xing;
+        for &kbps in bitrates {
+            file.extend(frame(kbps));
+        }
+        file
+    }
+
+    #[test]
+    fn vbr_mp3_duration_comes_from_the_xing_header() {
+        // Bitrates vary, so the size of the file doesn't tell the length
+        let bitrates: Vec<u32> = (0..200).map(|i| [320, 64, 64, 128][i % 4]).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vbr.mp3");
+        std::fs::write(&path, vbr_file(&bitrates)).unwrap();
+
+        let source = SymphoniaSource::open(path.to_str().unwrap()).unwrap();
+        let expected = (bitrates.len() as u64 * FRAME_SAMPLES) as f64 / 44100.0;
+        assert_eq!(source.sample_rate(), 44100);
+        assert_eq!(source.channels(), 2);
+        let duration = source.total_duration().unwrap().as_secs_f64();
+        assert!((duration - expected).abs() < 0.001, "{}s instead of {}s", duration, expected);
+
+        // And that is how much audio there is
+        let samples = source.count() as u64;
+        assert_eq!(samples, bitrates.len() as u64 * FRAME_SAMPLES * 2);
+    }
+
+    /// `count` frames of silence, those at `damaged` with a big_values
+    /// field above the 288 allowed, which fails to decode
+    fn damaged_file(count: usize, damaged: impl Fn(usize) -> bool) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| {
+                let mut frame = frame(128);
+                if damaged(i) {
+                    // 4 bytes into the side info, which follows the header
+                    frame[8..10].copy_from_slice(&[0xFF, 0x80]);
+                }
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn damaged_frames_are_skipped_and_counted() {
+        let mut file = damaged_file(40, |i| i == 10 || i == 25);
+        // Bytes that aren't a frame at all, which the reader resyncs past
+        let junk = 20 * frame(128).len();
+        file.splice(junk..junk, [0x55; 700]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("damaged.mp3");
+        std::fs::write(&path, file).unwrap();
+
+        let mut source = SymphoniaSource::open(path.to_str().unwrap()).unwrap();
+        let reported = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let messages = std::sync::Arc::clone(&reported);
+        source.on_decode_error(Box::new(move |message| messages.lock().unwrap().push(message.to_string())));
+
+        // Everything but the two damaged frames plays
+        let samples = source.by_ref().count() as u64;
+        assert_eq!(samples, 38 * FRAME_SAMPLES * 2);
+        assert_eq!(source.decode_errors(), 2);
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), 2);
+        assert!(reported[0].contains("undecodable"), "{}", reported[0]);
+    }
+
+    #[test]
+    fn decoding_gives_up_on_a_run_of_damage() {
+        let file = damaged_file(MAX_CONSECUTIVE_ERRORS + 20, |i| i >= 5);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("damaged.mp3");
+        std::fs::write(&path, file).unwrap();
+
+        let mut source = SymphoniaSource::open(path.to_str().unwrap()).unwrap();
+        source.on_decode_error(Box::new(|_| {}));
+        let samples = source.by_ref().count() as u64;
+        assert_eq!(samples, 5 * FRAME_SAMPLES * 2);
+        assert_eq!(source.decode_errors(), MAX_CONSECUTIVE_ERRORS);
+    }
+
+    #[test]
+    fn frame_scans_are_cached_until_the_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("headerless.mp3");
+        let path_str = path.to_str().unwrap();
+        std::fs::write(&path, [64, 320].repeat(50).iter().flat_map(|&kbps| frame(kbps)).collect::<Vec<_>>()).unwrap();
+
+        let scans = Mutex::default();
+        let scanned = SymphoniaSource::open_with(path_str, &scans).unwrap().total_duration();
+        assert_eq!(scans.lock().unwrap().durations.len(), 1);
+
+        // Opened again, the length comes from the cache rather than the file
+        let stamp = scans.lock().unwrap().durations[path_str].0;
+        scans.lock().unwrap().insert(path_str, stamp, Some(Duration::from_secs(42)));
+        let source = SymphoniaSource::open_with(path_str, &scans).unwrap();
+        assert_eq!(source.total_duration(), Some(Duration::from_secs(42)));
+
+        // A changed file is counted again
+        std::fs::write(&path, [64, 320].repeat(60).iter().flat_map(|&kbps| frame(kbps)).collect::<Vec<_>>()).unwrap();
+        let source = SymphoniaSource::open_with(path_str, &scans).unwrap();
+        assert_ne!(source.total_duration(), scanned);
+        assert_ne!(source.total_duration(), Some(Duration::from_secs(42)));
+    }
+
+    #[test]
+    fn headerless_vbr_mp3_duration_comes_from_a_frame_scan() {
+        // Cheap frames first, so extrapolating from the start of the file
+        // would overshoot
+        let bitrates: Vec<u32> = (0..200).map(|i| if i < 20 { 64 } else { 320 }).collect();
+        let file: Vec<u8> = bitrates.iter().flat_map(|&kbps| frame(kbps)).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("headerless.mp3");
+        std::fs::write(&path, file).unwrap();
+
+        let source = SymphoniaSource::open(path.to_str().unwrap()).unwrap();
+        let expected = (bitrates.len() as u64 * FRAME_SAMPLES) as f64 / 44100.0;
+        let duration = source.total_duration().unwrap().as_secs_f64();
+        assert!((duration - expected).abs() < 0.001, "{}s instead of {}s", duration, expected);
+
+        let samples = source.count() as u64;
+        assert_eq!(samples, bitrates.len() as u64 * FRAME_SAMPLES * 2);
+    }
+}
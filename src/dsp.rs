@@ -0,0 +1,147 @@
+/// Second order IIR section in direct form I.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    /// Coefficients normalized so that a0 is 1
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, ..Default::default() }
+    }
+
+    /// Swaps in new coefficients but keeps the filter history, so a running
+    /// signal doesn't click
+    pub fn set_coefficients(&mut self, other: &Biquad) {
+        self.b = other.b;
+        self.a = other.a;
+    }
+
+    pub fn reset(&mut self) {
+        self.x = [0.0; 2];
+        self.y = [0.0; 2];
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+
+    /// Gain in dB of a sine at `frequency` once the filter has settled
+    #[cfg(test)]
+    pub fn gain_db(&self, sample_rate: u32, frequency: f64) -> f64 {
+        let w = 2.0 * std::f64::consts::PI * frequency / sample_rate as f64;
+        // |c0 + c1 e^-jw + c2 e^-2jw|
+        let magnitude = |c: [f64; 3]| {
+            let re = c[0] + c[1] * w.cos() + c[2] * (2.0 * w).cos();
+            let im = c[1] * w.sin() + c[2] * (2.0 * w).sin();
+            re.hypot(im)
+        };
+        20.0 * (magnitude(self.b) / magnitude([1.0, self.a[0], self.a[1]])).log10()
+    }
+
+    /// RBJ peaking filter
+    pub fn peaking(sample_rate: u32, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f64::consts::PI * frequency / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+
+        let a0 = 1.0 + alpha / a;
+        Self::new(
+            [(1.0 + alpha * a) / a0, -2.0 * cos / a0, (1.0 - alpha * a) / a0],
+            [-2.0 * cos / a0, (1.0 - alpha / a) / a0],
+        )
+    }
+
+    /// RBJ low shelf with a slope of 1
+    pub fn low_shelf(sample_rate: u32, frequency: f64, gain_db: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f64::consts::PI * frequency / sample_rate as f64;
+        let cos = w0.cos();
+        // 2 * sqrt(A) * alpha, with alpha = sin(w0) / 2 * sqrt(2) for S = 1
+        let beta = a.sqrt() * w0.sin() * std::f64::consts::SQRT_2;
+
+        let a0 = (a + 1.0) + (a - 1.0) * cos + beta;
+        Self::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + beta) / a0,
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos) / a0,
+                a * ((a + 1.0) - (a - 1.0) * cos - beta) / a0,
+            ],
+            [
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos) / a0,
+                ((a + 1.0) + (a - 1.0) * cos - beta) / a0,
+            ],
+        )
+    }
+
+    /// RBJ high shelf with a slope of 1
+    pub fn high_shelf(sample_rate: u32, frequency: f64, gain_db: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f64::consts::PI * frequency / sample_rate as f64;
+        let cos = w0.cos();
+        let beta = a.sqrt() * w0.sin() * std::f64::consts::SQRT_2;
+
+        let a0 = (a + 1.0) - (a - 1.0) * cos + beta;
+        Self::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + beta) / a0,
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos) / a0,
+                a * ((a + 1.0) + (a - 1.0) * cos - beta) / a0,
+            ],
+            [
+                2.0 * ((a - 1.0) - (a + 1.0) * cos) / a0,
+                ((a + 1.0) - (a - 1.0) * cos - beta) / a0,
+            ],
+        )
+    }
+}
+
+/// In-place radix-2 FFT. Both slices must have the same power-of-two length.
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two() && im.len() == n);
+
+    // Bit-reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f32::consts::PI / len as f32;
+        let (w_im, w_re) = angle.sin_cos();
+        for start in (0..n).step_by(len) {
+            let (mut cur_re, mut cur_im) = (1.0f32, 0.0f32);
+            for k in 0..len / 2 {
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cur_re - im[b] * cur_im;
+                let t_im = re[b] * cur_im + im[b] * cur_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+                (cur_re, cur_im) = (cur_re * w_re - cur_im * w_im, cur_re * w_im + cur_im * w_re);
+            }
+        }
+        len <<= 1;
+    }
+}
@@ -0,0 +1,439 @@
+use anyhow::{Context, Result};
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::dsp::Biquad;
+use crate::ipc::{EqBandStatus, EqStatus};
+
+pub const MAX_GAIN_DB: f32 = 12.0;
+
+// Octave spacing of the peaking bands
+const PEAKING_Q: f64 = 1.41;
+
+// Longest run of samples before looking for new settings
+const CHECK_INTERVAL: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum BandKind {
+    LowShelf,
+    Peaking,
+    HighShelf,
+}
+
+struct BandSpec {
+    kind: BandKind,
+    frequency: f64,
+    label: &'static str,
+}
+
+const fn band(kind: BandKind, frequency: f64, label: &'static str) -> BandSpec {
+    BandSpec { kind, frequency, label }
+}
+
+pub const BAND_COUNT: usize = 12;
+
+/// Ten octave-spaced peaking bands framed by bass and treble shelves.
+const BANDS: [BandSpec; BAND_COUNT] = [
+    band(BandKind::LowShelf, 100.0, "Bass"),
+    band(BandKind::Peaking, 31.0, "31"),
+    band(BandKind::Peaking, 62.0, "62"),
+    band(BandKind::Peaking, 125.0, "125"),
+    band(BandKind::Peaking, 250.0, "250"),
+    band(BandKind::Peaking, 500.0, "500"),
+    band(BandKind::Peaking, 1000.0, "1k"),
+    band(BandKind::Peaking, 2000.0, "2k"),
+    band(BandKind::Peaking, 4000.0, "4k"),
+    band(BandKind::Peaking, 8000.0, "8k"),
+    band(BandKind::Peaking, 16000.0, "16k"),
+    band(BandKind::HighShelf, 10000.0, "Treble"),
+];
+
+type Gains = [f32; BAND_COUNT];
+
+const BUILTIN_PRESETS: &[(&str, Gains)] = &[
+    ("flat", [0.0; BAND_COUNT]),
+    ("rock", [0.0, 5.0, 4.0, 3.0, 1.0, -1.0, -1.0, 1.0, 3.0, 4.0, 5.0, 0.0]),
+    ("pop", [0.0, -1.0, -1.0, 0.0, 2.0, 4.0, 4.0, 2.0, 0.0, -1.0, -1.0, 0.0]),
+    ("jazz", [0.0, 3.0, 2.0, 1.0, 2.0, -1.0, -1.0, 0.0, 1.0, 2.0, 3.0, 0.0]),
+    ("classical", [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -2.0, -3.0, -3.0, -5.0, 0.0]),
+    ("electronic", [0.0, 5.0, 4.0, 1.0, 0.0, -2.0, 2.0, 1.0, 1.0, 4.0, 5.0, 0.0]),
+    ("bass_boost", [6.0, 3.0, 2.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+    ("treble_boost", [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 3.0, 6.0]),
+    ("vocal", [0.0, -2.0, -2.0, -1.0, 1.0, 3.0, 4.0, 4.0, 3.0, 1.0, 0.0, 0.0]),
+];
+
+#[derive(Debug, Clone)]
+pub struct EqSettings {
+    pub enabled: bool,
+    pub gains: Gains,
+    /// Name of the preset the gains were loaded from, until a band is changed
+    pub preset: Option<String>,
+}
+
+impl Default for EqSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            gains: [0.0; BAND_COUNT],
+            preset: Some("flat".to_string()),
+        }
+    }
+}
+
+impl EqSettings {
+    fn is_flat(&self) -> bool {
+        !self.enabled || self.gains.iter().all(|g| *g == 0.0)
+    }
+}
+
+/// Equalizer state shared between the `Player` and the audio thread.
+#[derive(Default)]
+pub struct EqShared {
+    settings: Mutex<EqSettings>,
+    // Bumped on every change so the audio thread only locks when needed
+    version: AtomicU64,
+}
+
+impl EqShared {
+    pub fn settings(&self) -> EqSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut EqSettings)) {
+        f(&mut self.settings.lock().unwrap());
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn status(&self, presets: &EqPresets) -> EqStatus {
+        let settings = self.settings();
+        EqStatus {
+            enabled: settings.enabled,
+            bands: BANDS
+                .iter()
+                .zip(settings.gains)
+                .map(|(spec, gain)| EqBandStatus {
+                    label: spec.label.to_string(),
+                    frequency: spec.frequency as f32,
+                    gain,
+                })
+                .collect(),
+            preset: settings.preset,
+            presets: presets.names(),
+        }
+    }
+}
+
+/// Built-in presets plus the ones saved by the user in the data directory.
+#[derive(Debug, Default)]
+pub struct EqPresets {
+    file: Option<PathBuf>,
+    user: BTreeMap<String, Vec<f32>>,
+}
+
+impl EqPresets {
+    pub fn open(file: PathBuf) -> Result<Self> {
+        let user = if file.exists() {
+            let json = fs::read_to_string(&file)?;
+            serde_json::from_str(&json).context("Failed to parse equalizer presets")?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self { file: Some(file), user })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        BUILTIN_PRESETS
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(self.user.keys().cloned())
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Gains> {
+        if let Some((_, gains)) = BUILTIN_PRESETS.iter().find(|(n, _)| *n == name) {
+            return Some(*gains);
+        }
+
+        // Files written by an older layout may have fewer bands
+        let saved = self.user.get(name)?;
+        let mut gains = [0.0; BAND_COUNT];
+        gains.iter_mut().zip(saved).for_each(|(g, s)| *g = s.clamp(-MAX_GAIN_DB, MAX_GAIN_DB));
+        Some(gains)
+    }
+
+    pub fn save(&mut self, name: &str, gains: &Gains) -> Result<()> {
+        if BUILTIN_PRESETS.iter().any(|(n, _)| *n == name) {
+            anyhow::bail!("'{}' is a built-in preset", name);
+        }
+        self.user.insert(name.to_string(), gains.to_vec());
+
+        if let Some(file) = &self.file {
+            let json = serde_json::to_string_pretty(&self.user)?;
+            fs::write(file, json).context("Failed to write equalizer presets")?;
+        }
+        Ok(())
+    }
+}
+
+fn design(spec: &BandSpec, sample_rate: u32, gain: f32) -> Option<Biquad> {
+    // Bands this close to Nyquist can't be realized at this rate
+    if gain == 0.0 || spec.frequency >= sample_rate as f64 * 0.45 {
+        return None;
+    }
+    let gain = gain as f64;
+    Some(match spec.kind {
+        BandKind::LowShelf => Biquad::low_shelf(sample_rate, spec.frequency, gain),
+        BandKind::Peaking => Biquad::peaking(sample_rate, spec.frequency, PEAKING_Q, gain),
+        BandKind::HighShelf => Biquad::high_shelf(sample_rate, spec.frequency, gain),
+    })
+}
+
+/// Applies the shared equalizer settings to a source. The stream format is
+/// picked up at frame boundaries and settings at least every
+/// `CHECK_INTERVAL` samples, so changes are heard without restarting the track.
+pub struct Equalizer<S> {
+    inner: S,
+    shared: Arc<EqShared>,
+    version: u64,
+    bypass: bool,
+    // Per channel, per band
+    filters: Vec<[Option<Biquad>; BAND_COUNT]>,
+    channel: usize,
+    sample_rate: u32,
+    frame_left: usize,
+    until_check: usize,
+}
+
+impl<S: Source<Item = i16>> Equalizer<S> {
+    pub fn new(inner: S, shared: Arc<EqShared>) -> Self {
+        Self {
+            inner,
+            shared,
+            version: u64::MAX,
+            bypass: true,
+            filters: Vec::new(),
+            channel: 0,
+            sample_rate: 0,
+            frame_left: 0,
+            until_check: 0,
+        }
+    }
+
+    fn check(&mut self) {
+        let channels = self.inner.channels() as usize;
+        let sample_rate = self.inner.sample_rate();
+
+        let mut format_changed = false;
+        if self.frame_left == 0 {
+            // Sources without frames never change format
+            self.frame_left = self.inner.current_frame_len().unwrap_or(usize::MAX).max(1);
+            self.channel = 0;
+            format_changed = channels != self.filters.len() || sample_rate != self.sample_rate;
+        }
+        self.until_check = self.frame_left.min(CHECK_INTERVAL);
+
+        let version = self.shared.version.load(Ordering::Acquire);
+        if !format_changed && version == self.version {
+            return;
+        }
+
+        let settings = self.shared.settings();
+        self.version = version;
+        self.sample_rate = sample_rate;
+        self.bypass = settings.is_flat();
+
+        if format_changed {
+            self.filters = vec![[None; BAND_COUNT]; channels];
+        }
+        for (index, spec) in BANDS.iter().enumerate() {
+            let designed = design(spec, sample_rate, settings.gains[index]).filter(|_| !self.bypass);
+            for channel in self.filters.iter_mut() {
+                channel[index] = match (channel[index], designed) {
+                    (Some(mut filter), Some(new)) => {
+                        filter.set_coefficients(&new);
+                        Some(filter)
+                    }
+                    (_, designed) => designed,
+                };
+            }
+        }
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Equalizer<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.until_check == 0 {
+            self.check();
+        }
+        // Counted once it's there, so an empty pull at the end of a source
+        // doesn't move the channel out of step
+        let sample = self.inner.next()?;
+        self.until_check -= 1;
+        self.frame_left -= 1;
+
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.filters.len().max(1);
+        if self.bypass {
+            return Some(sample);
+        }
+
+        let filtered = self.filters[channel]
+            .iter_mut()
+            .flatten()
+            .fold(sample as f64, |x, filter| filter.process(x));
+        Some(filtered.clamp(i16::MIN as f64, i16::MAX as f64) as i16)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Equalizer<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        // Frames start over at the new position and the old signal is gone
+        self.frame_left = 0;
+        self.until_check = 0;
+        self.filters.iter_mut().flatten().flatten().for_each(Biquad::reset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn assert_close(measured: f64, expected: f64, tolerance: f64) {
+        assert!((measured - expected).abs() <= tolerance, "{} instead of {}", measured, expected);
+    }
+
+    #[test]
+    fn bands_apply_their_gain_at_their_frequency() {
+        for spec in &BANDS {
+            for gain in [-MAX_GAIN_DB, 6.0, MAX_GAIN_DB] {
+                let filter = design(spec, SAMPLE_RATE, gain).unwrap();
+                let measured = filter.gain_db(SAMPLE_RATE, spec.frequency);
+                // Shelves are halfway there at their corner frequency
+                let expected = match spec.kind {
+                    BandKind::Peaking => gain as f64,
+                    BandKind::LowShelf | BandKind::HighShelf => gain as f64 / 2.0,
+                };
+                assert_close(measured, expected, 0.01);
+            }
+        }
+
+        let bass = design(&BANDS[0], SAMPLE_RATE, 6.0).unwrap();
+        assert_close(bass.gain_db(SAMPLE_RATE, 5.0), 6.0, 0.1);
+        assert_close(bass.gain_db(SAMPLE_RATE, 5000.0), 0.0, 0.1);
+        let treble = design(&BANDS[BAND_COUNT - 1], SAMPLE_RATE, 6.0).unwrap();
+        assert_close(treble.gain_db(SAMPLE_RATE, 20000.0), 6.0, 0.5);
+        assert_close(treble.gain_db(SAMPLE_RATE, 100.0), 0.0, 0.1);
+    }
+
+    #[test]
+    fn bands_near_nyquist_are_left_out() {
+        let top = &BANDS[BAND_COUNT - 2];
+        assert!(design(top, 32000, 6.0).is_none());
+        assert!(design(top, 44100, 6.0).is_some());
+    }
+
+    fn noise(len: usize) -> Vec<i16> {
+        (0..len as i32).map(|n| (n.wrapping_mul(48271) % 65_536 - 32_768) as i16).collect()
+    }
+
+    #[test]
+    fn flat_or_disabled_equalizer_passes_samples_through() {
+        let input = noise(10_000);
+        let shared = Arc::new(EqShared::default());
+        let source = SamplesBuffer::new(2, SAMPLE_RATE, input.clone());
+        assert!(Equalizer::new(source, Arc::clone(&shared)).collect::<Vec<_>>() == input);
+
+        shared.update(|settings| {
+            settings.gains = BUILTIN_PRESETS[1].1;
+            settings.enabled = false;
+        });
+        let source = SamplesBuffer::new(2, SAMPLE_RATE, input.clone());
+        assert!(Equalizer::new(source, shared).collect::<Vec<_>>() == input);
+    }
+
+    /// Peak level in dB of the last tenth of a second of `samples`
+    fn level_db(samples: &[i16]) -> f64 {
+        let tail = &samples[samples.len() - SAMPLE_RATE as usize / 10..];
+        let peak = tail.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        20.0 * (peak as f64 / 8000.0).log10()
+    }
+
+    #[test]
+    fn gain_changes_apply_to_the_running_stream() {
+        let tone: Vec<i16> = (0..SAMPLE_RATE as usize * 2)
+            .map(|n| (8000.0 * (2.0 * std::f64::consts::PI * 1000.0 * n as f64 / SAMPLE_RATE as f64).sin()) as i16)
+            .collect();
+        let shared = Arc::new(EqShared::default());
+        shared.update(|settings| settings.gains[6] = 6.0);
+        let mut eq = Equalizer::new(SamplesBuffer::new(1, SAMPLE_RATE, tone), Arc::clone(&shared));
+
+        let boosted: Vec<i16> = eq.by_ref().take(SAMPLE_RATE as usize).collect();
+        assert_close(level_db(&boosted), 6.0, 0.1);
+
+        shared.update(|settings| settings.gains[6] = -6.0);
+        let cut: Vec<i16> = eq.collect();
+        assert_close(level_db(&cut), -6.0, 0.1);
+    }
+
+    #[test]
+    fn user_presets_are_saved_and_padded_to_all_bands() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("presets.json");
+        // Written when there were only ten bands
+        fs::write(&file, r#"{"old": [1, 2, 3, 4, 5, 6, 7, 8, 9, 20]}"#).unwrap();
+
+        let mut presets = EqPresets::open(file.clone()).unwrap();
+        let old = presets.get("old").unwrap();
+        assert_eq!(old[..9], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(old[9..], [MAX_GAIN_DB, 0.0, 0.0]);
+        assert!(presets.get("missing").is_none());
+
+        let mine = [2.0; BAND_COUNT];
+        presets.save("mine", &mine).unwrap();
+        let presets = EqPresets::open(file).unwrap();
+        assert_eq!(presets.get("mine"), Some(mine));
+        assert!(presets.names().ends_with(&["mine".to_string(), "old".to_string()]));
+    }
+
+    #[test]
+    fn built_in_presets_cannot_be_overwritten() {
+        let mut presets = EqPresets::default();
+        assert!(presets.save("rock", &[0.0; BAND_COUNT]).is_err());
+        assert_eq!(presets.get("rock"), Some(BUILTIN_PRESETS[1].1));
+    }
+}
@@ -78,7 +78,7 @@ pub fn start_gui_with_daemon() {
             std::thread::spawn(move || {
                 let rt = tokio::runtime::Runtime::new().unwrap();
                 rt.block_on(async {
-                    if let Err(e) = crate::daemon::start().await {
+                    if let Err(e) = crate::daemon::start(None).await {
                         eprintln!("❌ Daemon thread failed: {}", e);
                     }
                 });
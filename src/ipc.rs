@@ -12,9 +12,70 @@ pub enum Command {
     Stop,
     Next,
     Previous,
+    /// 0–100, or up to 150 with volume boost enabled. Unmutes.
     SetVolume { level: u8 },
+    /// Mute, or unmute back to the previous volume
+    ToggleMute,
+    /// Allow volume levels above 100, up to +12 dB
+    SetVolumeBoost { enabled: bool },
+    Seek { position: f64 },
+    SeekRelative { offset: f64 },
+    /// Start of an A–B loop, the current position when not given
+    SetLoopA { position: Option<f64> },
+    /// End of the A–B loop; playback repeats between A and B from then on
+    SetLoopB { position: Option<f64> },
+    ClearLoop,
+    /// Jump to the start of the next chapter of the current track
+    NextChapter,
+    /// Back to the start of the current chapter, or the one before it when
+    /// that's where playback is
+    PrevChapter,
+    SetSpeed { factor: f32 },
+    /// Fade out and pause or stop when the timer runs out
+    SetSleepTimer { mode: SleepTimerMode },
+    CancelSleepTimer,
+    /// Remember where long tracks were left and resume them there; fields
+    /// left out keep their value
+    SetAudiobookMode {
+        enabled: Option<bool>,
+        min_minutes: Option<f64>,
+    },
+    SetCrossfade {
+        seconds: f64,
+        curve: Option<CrossfadeCurve>,
+        fade_on_skip: Option<bool>,
+    },
+    SetReplayGain {
+        mode: ReplayGainMode,
+        preamp: Option<f32>,
+        prevent_clipping: Option<bool>,
+    },
+    /// Output channel processing; fields left out keep their value
+    SetChannels {
+        balance: Option<f32>,
+        mono: Option<bool>,
+        swap: Option<bool>,
+    },
+    /// How silence in tracks is handled; fields left out keep their value
+    SetSilence {
+        mode: SilenceMode,
+        threshold_db: Option<f32>,
+        min_seconds: Option<f32>,
+    },
+    SetEqBand { band: usize, gain: f32 },
+    SetEqPreset { name: String },
+    SaveEqPreset { name: String },
+    SetEqEnabled { enabled: bool },
+    GetEq,
+    ListOutputDevices,
+    /// Move playback to another device, `None` for the system default
+    SetOutputDevice { name: Option<String> },
     AddTracks { paths: Vec<String> },
+    /// Measure loudness of files or directories in the background
+    Analyze { paths: Vec<String> },
     GetStatus,
+    /// Spectrum and output levels of what is playing
+    GetVisualization,
     GetPlaylist,
     ClearPlaylist,
     Shutdown,
@@ -25,6 +86,9 @@ pub enum Response {
     Ok,
     Status(PlayerStatus),
     Playlist(Vec<String>),
+    Equalizer(EqStatus),
+    OutputDevices(OutputDeviceList),
+    Visualization(Visualization),
     Error(String),
 }
 
@@ -34,9 +98,138 @@ pub struct PlayerStatus {
     pub current_track: Option<String>,
     pub position: f64,
     pub duration: f64,
+    /// Time left in the track at the current speed
+    pub remaining: f64,
+    pub speed: f32,
     pub volume: u8,
+    pub muted: bool,
+    pub volume_boost: bool,
     pub playlist_length: usize,
     pub current_index: Option<usize>,
+    pub crossfade: f64,
+    pub replaygain: ReplayGainMode,
+    pub channels: ChannelSettings,
+    pub silence: SilenceSettings,
+    pub sleep_timer: Option<SleepTimerStatus>,
+    /// A–B loop points in seconds; the loop runs once both are set
+    pub loop_a: Option<f64>,
+    pub loop_b: Option<f64>,
+    /// Chapters of the current track, and the one playing
+    pub chapters: Vec<Chapter>,
+    pub chapter: Option<usize>,
+    pub audiobook: AudiobookSettings,
+    /// Damaged parts of the current track skipped so far
+    pub decode_errors: usize,
+    /// What a radio stream says it's playing
+    pub stream_title: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    /// Start in seconds from the beginning of the track
+    pub start: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct AudiobookSettings {
+    /// Resume tracks where they were left
+    pub enabled: bool,
+    /// Only tracks at least this long are resumed
+    pub min_minutes: f64,
+}
+
+impl Default for AudiobookSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_minutes: 20.0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SleepTimerMode {
+    /// Pause after this many minutes
+    Minutes(f64),
+    /// Stop when the current track ends
+    EndOfTrack,
+    /// Stop when the last track of the playlist ends
+    EndOfPlaylist,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct SleepTimerStatus {
+    pub mode: SleepTimerMode,
+    /// Seconds until the timer goes off, `None` until the last track plays
+    pub remaining: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(default)]
+pub struct ChannelSettings {
+    /// -1.0 (left only) to 1.0 (right only)
+    pub balance: f32,
+    /// Downmix stereo to mono on both channels
+    pub mono: bool,
+    /// Swap left and right
+    pub swap: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct SilenceSettings {
+    pub mode: SilenceMode,
+    /// Frames that stay below this level count as silent, in dBFS
+    pub threshold_db: f32,
+    /// Shorter silences in the middle of a track are left alone
+    pub min_seconds: f32,
+}
+
+impl Default for SilenceSettings {
+    fn default() -> Self {
+        Self {
+            mode: SilenceMode::Off,
+            threshold_db: -50.0,
+            min_seconds: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Visualization {
+    /// Spectrum bars from low to high frequencies, 0.0–1.0
+    pub bands: Vec<f32>,
+    /// Left and right peak level in dBFS
+    pub peak: [f32; 2],
+    /// Left and right RMS level in dBFS
+    pub rms: [f32; 2],
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EqStatus {
+    pub enabled: bool,
+    pub bands: Vec<EqBandStatus>,
+    /// Preset the current gains came from, `None` once edited
+    pub preset: Option<String>,
+    pub presets: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EqBandStatus {
+    pub label: String,
+    pub frequency: f32,
+    pub gain: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutputDeviceList {
+    pub devices: Vec<String>,
+    /// Device chosen for playback, `None` when following the system default
+    pub selected: Option<String>,
+    /// The system default device
+    pub default: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -46,6 +239,30 @@ pub enum PlaybackState {
     Stopped,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum CrossfadeCurve {
+    Linear,
+    EqualPower,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    /// Album gain when tagged, track gain otherwise
+    Auto,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SilenceMode {
+    Off,
+    /// Drop silence at the start and end of tracks
+    Trim,
+    /// Cut long silences anywhere in a track as well, for podcasts
+    Skip,
+}
+
 pub fn get_socket_addr() -> &'static str {
     SOCKET_ADDR
 }
@@ -55,9 +272,8 @@ pub struct IpcServer {
 }
 
 impl IpcServer {
-    pub async fn new() -> Result<Self> {
-        let addr = get_socket_addr();
-
+    /// Listens on `addr`, normally `get_socket_addr()`
+    pub async fn bind(addr: &str) -> Result<Self> {
         let listener = TcpListener::bind(addr)
             .await
             .context("Failed to bind IPC socket")?;
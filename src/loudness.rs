@@ -0,0 +1,551 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::config::Storage;
+use crate::dsp::Biquad;
+use crate::player;
+use crate::replaygain::{self, ReplayGainInfo};
+
+/// Loudness that a gain of 0 dB corresponds to (ReplayGain 2.0)
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+// BS.1770 gating parameters
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+// Blocks are 400 ms long and start every 100 ms
+const SUBBLOCKS_PER_BLOCK: usize = 4;
+
+// True peak is measured on a 4x oversampled signal
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+fn power_to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn lufs_to_power(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// The two stages of the BS.1770 K-weighting filter, derived for any
+/// sample rate rather than only the 48 kHz table in the standard.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    // High shelf modelling the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    // RLB high-pass
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, highpass]
+}
+
+/// Windowed-sinc interpolation filter, split into one set of taps per
+/// oversampling phase.
+fn interpolation_phases() -> [[f64; TAPS_PER_PHASE]; OVERSAMPLING] {
+    let taps = OVERSAMPLING * TAPS_PER_PHASE;
+    let center = (taps - 1) as f64 / 2.0;
+
+    let mut phases = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+    for n in 0..taps {
+        let t = (n as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if t == 0.0 {
+            1.0
+        } else {
+            (std::f64::consts::PI * t).sin() / (std::f64::consts::PI * t)
+        };
+        let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * n as f64 / (taps - 1) as f64).cos();
+        phases[n % OVERSAMPLING][n / OVERSAMPLING] = sinc * window;
+    }
+
+    // Unity gain for every phase
+    for phase in phases.iter_mut() {
+        let sum: f64 = phase.iter().sum();
+        phase.iter_mut().for_each(|tap| *tap /= sum);
+    }
+    phases
+}
+
+struct ChannelState {
+    filters: [Biquad; 2],
+    weight: f64,
+    // Sum of squares of the K-weighted signal in the running sub-block
+    energy: f64,
+    history: [f64; TAPS_PER_PHASE],
+    history_pos: usize,
+}
+
+/// Integrated loudness and true peak meter following EBU R128 / BS.1770-4.
+pub struct LoudnessMeter {
+    channels: Vec<ChannelState>,
+    phases: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    subblock_len: usize,
+    subblock_pos: usize,
+    // Weighted mean square of each 100 ms sub-block
+    subblocks: Vec<f64>,
+    // Mean square of every complete 400 ms block
+    blocks: Vec<f64>,
+    true_peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let channels = (0..channels as usize)
+            .map(|index| ChannelState {
+                filters: k_weighting(sample_rate),
+                weight: channel_weight(index, channels),
+                energy: 0.0,
+                history: [0.0; TAPS_PER_PHASE],
+                history_pos: 0,
+            })
+            .collect();
+
+        Self {
+            channels,
+            phases: interpolation_phases(),
+            subblock_len: (sample_rate as usize / 10).max(1),
+            subblock_pos: 0,
+            subblocks: Vec::new(),
+            blocks: Vec::new(),
+            true_peak: 0.0,
+        }
+    }
+
+    /// Feeds one frame, a sample per channel, in the -1.0..=1.0 range.
+    pub fn push_frame(&mut self, frame: &[f64]) {
+        for (state, &sample) in self.channels.iter_mut().zip(frame) {
+            let filtered = state.filters.iter_mut().fold(sample, |x, f| f.process(x));
+            state.energy += filtered * filtered;
+
+            state.history[state.history_pos] = sample;
+            state.history_pos = (state.history_pos + 1) % TAPS_PER_PHASE;
+            for phase in &self.phases {
+                let interpolated: f64 = phase
+                    .iter()
+                    .enumerate()
+                    .map(|(k, tap)| tap * state.history[(state.history_pos + TAPS_PER_PHASE - 1 - k) % TAPS_PER_PHASE])
+                    .sum();
+                self.true_peak = self.true_peak.max(interpolated.abs());
+            }
+            self.true_peak = self.true_peak.max(sample.abs());
+        }
+
+        self.subblock_pos += 1;
+        if self.subblock_pos == self.subblock_len {
+            self.finish_subblock();
+        }
+    }
+
+    fn finish_subblock(&mut self) {
+        let len = self.subblock_len as f64;
+        let power = self
+            .channels
+            .iter_mut()
+            .map(|state| state.weight * std::mem::take(&mut state.energy) / len)
+            .sum();
+        self.subblock_pos = 0;
+        self.subblocks.push(power);
+
+        if self.subblocks.len() >= SUBBLOCKS_PER_BLOCK {
+            let recent = &self.subblocks[self.subblocks.len() - SUBBLOCKS_PER_BLOCK..];
+            self.blocks.push(recent.iter().sum::<f64>() / SUBBLOCKS_PER_BLOCK as f64);
+        }
+    }
+
+    /// Gated loudness in LUFS, `None` for silence or anything shorter than
+    /// one block
+    pub fn integrated(&self) -> Option<f64> {
+        gated_loudness(&self.blocks)
+    }
+
+    /// Highest inter-sample peak as linear amplitude
+    pub fn true_peak(&self) -> f64 {
+        self.true_peak
+    }
+
+    pub fn into_blocks(self) -> Vec<f64> {
+        self.blocks
+    }
+}
+
+// Surround channels are weighted +1.5 dB and LFE is ignored (5.1 layout)
+fn channel_weight(index: usize, channels: u16) -> f64 {
+    match (channels, index) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+fn gated_loudness(blocks: &[f64]) -> Option<f64> {
+    let absolute = lufs_to_power(ABSOLUTE_GATE);
+    let above_absolute: Vec<f64> = blocks.iter().copied().filter(|p| *p > absolute).collect();
+    if above_absolute.is_empty() {
+        return None;
+    }
+
+    let mean = above_absolute.iter().sum::<f64>() / above_absolute.len() as f64;
+    let relative = lufs_to_power(power_to_lufs(mean) + RELATIVE_GATE);
+    let gated: Vec<f64> = above_absolute.into_iter().filter(|p| *p > relative).collect();
+    if gated.is_empty() {
+        return None;
+    }
+
+    Some(power_to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+/// Measurement of a single file.
+pub struct TrackLoudness {
+    pub integrated: Option<f64>,
+    pub true_peak: f64,
+    blocks: Vec<f64>,
+}
+
+/// Decodes `path` with the player's decoders and measures it.
+pub fn measure(path: &str) -> Result<TrackLoudness> {
+    let (mut source, _) = player::open_source(path)?;
+
+    let channels = source.channels();
+    let mut meter = LoudnessMeter::new(source.sample_rate(), channels);
+    let mut frame = vec![0.0; channels as usize];
+    let mut filled = 0;
+
+    for sample in source.by_ref() {
+        frame[filled] = sample as f64 / 32768.0;
+        filled += 1;
+        if filled == frame.len() {
+            meter.push_frame(&frame);
+            filled = 0;
+        }
+    }
+
+    Ok(TrackLoudness {
+        integrated: meter.integrated(),
+        true_peak: meter.true_peak(),
+        blocks: meter.into_blocks(),
+    })
+}
+
+/// Measured loudness of one file, valid as long as the file is unchanged.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CachedGain {
+    modified: u64,
+    size: u64,
+    pub track_loudness: f64,
+    pub track_peak: f64,
+    pub album_loudness: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl CachedGain {
+    pub fn replaygain(&self) -> ReplayGainInfo {
+        ReplayGainInfo {
+            track_gain: Some((REFERENCE_LUFS - self.track_loudness) as f32),
+            track_peak: Some(self.track_peak as f32),
+            album_gain: self.album_loudness.map(|l| (REFERENCE_LUFS - l) as f32),
+            album_peak: self.album_peak.map(|p| p as f32),
+        }
+    }
+}
+
+// Modification time and size identify the version of a file we measured
+fn file_stamp(path: &str) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some((modified, metadata.len()))
+}
+
+/// Loudness measurements stored as JSON in the data directory. Both the CLI
+/// and the daemon write to it, so it picks up changes made on disk.
+#[derive(Debug, Default)]
+pub struct GainCache {
+    file: Option<PathBuf>,
+    entries: HashMap<String, CachedGain>,
+    loaded: Option<SystemTime>,
+}
+
+impl GainCache {
+    pub fn open_default() -> Result<Self> {
+        Ok(Self::open(Storage::user()?.data_file("loudness.json")?))
+    }
+
+    pub fn open(file: PathBuf) -> Self {
+        let mut cache = Self {
+            file: Some(file),
+            ..Default::default()
+        };
+        cache.refresh();
+        cache
+    }
+
+    fn refresh(&mut self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let Ok(modified) = fs::metadata(file).and_then(|m| m.modified()) else {
+            return;
+        };
+        if self.loaded.is_some_and(|loaded| loaded >= modified) {
+            return;
+        }
+
+        match fs::read_to_string(file).map(|json| serde_json::from_str::<HashMap<String, CachedGain>>(&json)) {
+            Ok(Ok(entries)) => self.entries.extend(entries),
+            Ok(Err(e)) => error!("Ignoring corrupt loudness cache: {}", e),
+            Err(e) => error!("Failed to read loudness cache: {}", e),
+        }
+        self.loaded = Some(modified);
+    }
+
+    /// The measurement for `path`, unless the file changed since
+    pub fn lookup(&mut self, path: &str) -> Option<CachedGain> {
+        self.refresh();
+        let entry = self.entries.get(path)?;
+        (file_stamp(path)? == (entry.modified, entry.size)).then_some(*entry)
+    }
+
+    /// Adds measurements and writes the cache back to disk.
+    pub fn store(&mut self, entries: impl IntoIterator<Item = (String, CachedGain)>) -> Result<()> {
+        // Keep whatever another process saved in the meantime
+        self.refresh();
+        self.entries.extend(entries);
+
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let json = serde_json::to_string(&self.entries)?;
+        fs::write(file, json).context("Failed to write loudness cache")?;
+        self.loaded = fs::metadata(file).and_then(|m| m.modified()).ok();
+        Ok(())
+    }
+}
+
+/// A batch of files to measure. Files sharing a directory are treated as
+/// one album.
+pub struct AnalysisJob {
+    pub files: Vec<String>,
+    /// Leave out files that already carry ReplayGain tags
+    pub skip_tagged: bool,
+    /// Measure again even if the cache is up to date
+    pub force: bool,
+}
+
+/// Outcome for one file, passed to the progress callback of `run_job`.
+pub enum AnalysisResult<'a> {
+    Measured(&'a CachedGain),
+    Silent,
+    Failed(&'a anyhow::Error),
+}
+
+pub fn run_job(job: AnalysisJob, cache: &Mutex<GainCache>, mut report: impl FnMut(&str, AnalysisResult)) -> Result<()> {
+    let mut albums: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for file in job.files {
+        let dir = Path::new(&file).parent().map(Path::to_path_buf).unwrap_or_default();
+        albums.entry(dir).or_default().push(file);
+    }
+
+    for files in albums.into_values() {
+        let pending = |file: &String| {
+            (job.force || cache.lock().unwrap().lookup(file).is_none())
+                && !(job.skip_tagged && replaygain::read_tags(file).has_gain())
+        };
+        // Album values need every track of the album, so measure all of
+        // them as soon as one is missing
+        if !files.iter().any(pending) {
+            continue;
+        }
+
+        let mut measured = Vec::new();
+        for file in files {
+            let Some(stamp) = file_stamp(&file) else {
+                continue;
+            };
+            match measure(&file) {
+                Ok(loudness) => measured.push((file, stamp, loudness)),
+                Err(e) => report(&file, AnalysisResult::Failed(&e)),
+            }
+        }
+
+        let album_blocks: Vec<f64> = measured.iter().flat_map(|(_, _, l)| l.blocks.iter().copied()).collect();
+        let album_loudness = gated_loudness(&album_blocks);
+        let album_peak = measured.iter().map(|(_, _, l)| l.true_peak).reduce(f64::max);
+
+        let mut entries = Vec::new();
+        for (file, (modified, size), loudness) in measured {
+            let Some(track_loudness) = loudness.integrated else {
+                report(&file, AnalysisResult::Silent);
+                continue;
+            };
+            let entry = CachedGain {
+                modified,
+                size,
+                track_loudness,
+                track_peak: loudness.true_peak,
+                album_loudness,
+                album_peak,
+            };
+            report(&file, AnalysisResult::Measured(&entry));
+            entries.push((file, entry));
+        }
+
+        cache.lock().unwrap().store(entries)?;
+    }
+
+    Ok(())
+}
+
+/// Starts the thread that measures files in the background for the daemon.
+pub fn spawn_worker(cache: Arc<Mutex<GainCache>>) -> mpsc::Sender<AnalysisJob> {
+    let (tx, rx) = mpsc::channel::<AnalysisJob>();
+
+    std::thread::spawn(move || {
+        for job in rx {
+            let result = run_job(job, &cache, |file, result| {
+                if let AnalysisResult::Failed(e) = result {
+                    error!("Failed to analyze {}: {}", file, e);
+                }
+            });
+            if let Err(e) = result {
+                error!("Loudness analysis failed: {}", e);
+            }
+        }
+    });
+
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// Meters `seconds` of a sine at `frequency` with `amplitude` (peak)
+    /// on every channel
+    fn meter_tone(meter: &mut LoudnessMeter, channels: usize, frequency: f64, amplitude: f64, seconds: f64) {
+        for n in 0..(seconds * SAMPLE_RATE as f64) as usize {
+            let phase = 2.0 * std::f64::consts::PI * frequency * n as f64 / SAMPLE_RATE as f64;
+            let sample = amplitude * (phase + std::f64::consts::FRAC_PI_4).sin();
+            meter.push_frame(&vec![sample; channels]);
+        }
+    }
+
+    fn meter_silence(meter: &mut LoudnessMeter, channels: usize, seconds: f64) {
+        for _ in 0..(seconds * SAMPLE_RATE as f64) as usize {
+            meter.push_frame(&vec![0.0; channels]);
+        }
+    }
+
+    fn db(amplitude: f64) -> f64 {
+        20.0 * amplitude.log10()
+    }
+
+    fn assert_close(measured: f64, expected: f64, tolerance: f64) {
+        assert!((measured - expected).abs() <= tolerance, "{} instead of {}", measured, expected);
+    }
+
+    #[test]
+    fn full_scale_sine_in_one_channel_measures_its_rms_level() {
+        // 0 dBFS peak is -3.01 dBFS RMS, and K-weighting is flat at 1 kHz
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE, 1);
+        meter_tone(&mut meter, 1, 1000.0, 1.0, 5.0);
+        assert_close(meter.integrated().unwrap(), -3.01, 0.1);
+    }
+
+    #[test]
+    fn stereo_sine_at_minus_23_dbfs_measures_minus_23_lufs() {
+        // EBU Tech 3341, case 1
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE, 2);
+        meter_tone(&mut meter, 2, 1000.0, 10f64.powf(-23.0 / 20.0), 20.0);
+        assert_close(meter.integrated().unwrap(), -23.0, 0.1);
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // A quarter of the sample rate, sampled 45 degrees off its peaks
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE, 1);
+        meter_tone(&mut meter, 1, SAMPLE_RATE as f64 / 4.0, 0.5, 1.0);
+        assert_close(db(meter.true_peak()), db(0.5), 0.5);
+    }
+
+    #[test]
+    fn silence_is_gated_out() {
+        let mut silent = LoudnessMeter::new(SAMPLE_RATE, 2);
+        meter_silence(&mut silent, 2, 5.0);
+        assert_eq!(silent.integrated(), None);
+
+        // Silence and quiet parts don't pull the tone down
+        let mut tone = LoudnessMeter::new(SAMPLE_RATE, 2);
+        meter_tone(&mut tone, 2, 1000.0, 0.1, 10.0);
+        let mut gapped = LoudnessMeter::new(SAMPLE_RATE, 2);
+        meter_silence(&mut gapped, 2, 10.0);
+        meter_tone(&mut gapped, 2, 1000.0, 0.1, 10.0);
+        meter_tone(&mut gapped, 2, 1000.0, 0.001, 10.0);
+        meter_silence(&mut gapped, 2, 10.0);
+        // Only the blocks overlapping the edges of the tone count in part
+        assert_close(gapped.integrated().unwrap(), tone.integrated().unwrap(), 0.2);
+    }
+
+    fn gain(path: &str, loudness: f64) -> (String, CachedGain) {
+        let (modified, size) = file_stamp(path).unwrap();
+        let entry = CachedGain {
+            modified,
+            size,
+            track_loudness: loudness,
+            track_peak: 0.9,
+            album_loudness: Some(-10.0),
+            album_peak: Some(0.95),
+        };
+        (path.to_string(), entry)
+    }
+
+    #[test]
+    fn cached_gains_are_read_back_until_the_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let track = dir.path().join("track.flac");
+        std::fs::write(&track, b"audio").unwrap();
+        let track = track.to_str().unwrap();
+        let file = dir.path().join("loudness.json");
+
+        GainCache::open(file.clone()).store([gain(track, -12.0)]).unwrap();
+
+        let mut cache = GainCache::open(file.clone());
+        let cached = cache.lookup(track).unwrap();
+        assert_eq!(cached.track_loudness, -12.0);
+        let info = cached.replaygain();
+        assert_eq!(info.track_gain, Some(-6.0));
+        assert_eq!(info.album_gain, Some(-8.0));
+
+        std::fs::write(track, b"retagged audio").unwrap();
+        assert!(cache.lookup(track).is_none());
+    }
+}
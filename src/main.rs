@@ -3,12 +3,31 @@ use clap::{Parser, Subcommand};
 use tracing_subscriber;
 
 mod daemon;
+mod decoder;
+mod dsp;
+mod equalizer;
 mod ipc;
+mod loudness;
+mod mp3;
+mod output;
 mod player;
 mod playlist;
+mod replaygain;
+mod resume;
+mod silence;
+mod sleep_timer;
+mod stream;
+mod channel_mix;
+mod chapters;
 mod cli;
+mod config;
+mod cue;
 mod tui;
+mod volume;
 mod theme;
+mod time_stretch;
+mod track_queue;
+mod visualizer;
 #[cfg(not(target_os = "windows"))]
 mod gui;
 
@@ -37,17 +56,173 @@ enum Commands {
     Stop,
     Next,
     Prev,
-    Volume { level: u8 },
+    /// Volume level, 0–100 (up to 150 with boost)
+    Volume {
+        level: Option<u8>,
+        /// Allow levels above 100, up to +12 dB, with a limiter against clipping
+        #[arg(long)]
+        boost: Option<bool>,
+    },
+    /// Mute, or unmute back to the previous volume
+    Mute,
+    /// Seek to a position (1:23, 83) or relative to the current one (+10, -5)
+    Seek {
+        #[arg(allow_hyphen_values = true)]
+        position: String,
+    },
+    /// Playback speed, e.g. 1.25 or 2 (pitch is preserved)
+    Speed { factor: f32 },
+    /// Overlap consecutive tracks by this many seconds (0 disables)
+    Crossfade {
+        seconds: f64,
+        #[arg(long, value_enum)]
+        curve: Option<ipc::CrossfadeCurve>,
+        /// Fade quickly on next/prev instead of cutting
+        #[arg(long)]
+        fade_on_skip: Option<bool>,
+    },
+    /// Normalize loudness using ReplayGain tags
+    Replaygain {
+        #[arg(value_enum)]
+        mode: ipc::ReplayGainMode,
+        /// Extra gain in dB on top of the tagged value
+        #[arg(long, allow_hyphen_values = true)]
+        preamp: Option<f32>,
+        /// Keep the tagged peak below full scale
+        #[arg(long)]
+        prevent_clipping: Option<bool>,
+    },
+    /// Repeat a passage of the current track
+    Loop {
+        #[command(subcommand)]
+        action: LoopAction,
+    },
+    /// Chapters of the current track (audiobooks, podcasts)
+    Chapter {
+        #[command(subcommand)]
+        action: ChapterAction,
+    },
+    /// Resume long tracks where they were left
+    Audiobook {
+        #[command(subcommand)]
+        action: AudiobookAction,
+    },
+    /// Fade out and stop after a while: 30m, 1h, 90s, `track` (end of the
+    /// current track), `playlist` (end of the playlist) or `off`
+    Sleep { when: String },
+    /// Stereo balance, mono downmix and channel swap (remembered by the daemon)
+    Channels {
+        /// -1 (left only) to 1 (right only), 0 for center
+        #[arg(long, allow_hyphen_values = true)]
+        balance: Option<f32>,
+        /// Play both channels mixed together on each side
+        #[arg(long)]
+        mono: Option<bool>,
+        /// Swap left and right
+        #[arg(long)]
+        swap: Option<bool>,
+    },
+    /// Trim silence at the start and end of tracks, or skip long silences
+    /// anywhere (remembered by the daemon)
+    Silence {
+        #[arg(value_enum)]
+        mode: ipc::SilenceMode,
+        /// Level below which audio counts as silent, in dBFS
+        #[arg(long, allow_hyphen_values = true)]
+        threshold: Option<f32>,
+        /// Seconds of silence before it's skipped in the middle of a track
+        #[arg(long)]
+        min_length: Option<f32>,
+    },
+    /// Equalizer bands and presets
+    Eq {
+        #[command(subcommand)]
+        action: EqAction,
+    },
+    /// Audio output devices
+    Output {
+        #[command(subcommand)]
+        action: OutputAction,
+    },
     Add { paths: Vec<String> },
+    /// Measure loudness (EBU R128) of files without ReplayGain tags
+    Analyze {
+        paths: Vec<String>,
+        /// Let the running daemon do it in the background
+        #[arg(long)]
+        background: bool,
+        /// Measure again even if a cached result is up to date
+        #[arg(long)]
+        force: bool,
+    },
     Status,
     Playlist,
     Clear,
     Tui,
 }
 
+#[derive(Subcommand)]
+enum EqAction {
+    /// Show band gains and available presets
+    Show,
+    /// Set the gain of one band (index as listed by `eq show`) in dB
+    Band {
+        band: usize,
+        #[arg(allow_hyphen_values = true)]
+        gain: f32,
+    },
+    /// Load a built-in or saved preset
+    Preset { name: String },
+    /// Save the current gains as a preset
+    Save { name: String },
+    On,
+    Off,
+}
+
+#[derive(Subcommand)]
+enum LoopAction {
+    /// Set the loop start, at the current position unless given (1:23, 83)
+    A { position: Option<String> },
+    /// Set the loop end and start looping
+    B { position: Option<String> },
+    /// Stop looping
+    Clear,
+}
+
+#[derive(Subcommand)]
+enum ChapterAction {
+    /// List the chapters, marking the one playing
+    List,
+    Next,
+    /// Back to the start of the chapter, or to the one before right after it began
+    Prev,
+}
+
+#[derive(Subcommand)]
+enum AudiobookAction {
+    On {
+        /// Only resume tracks at least this many minutes long
+        #[arg(long)]
+        min_minutes: Option<f64>,
+    },
+    Off,
+}
+
+#[derive(Subcommand)]
+enum OutputAction {
+    /// List devices, marking the one in use
+    List,
+    /// Play on this device from now on; leave out the name for the system default
+    Set { name: Option<String> },
+}
+
 #[derive(Subcommand)]
 enum DaemonAction {
-    Start,
+    Start {
+        /// Where audio goes: device[:<name>], null[:<speedup>] or wav:<path>
+        #[arg(long)]
+        output: Option<output::BackendSpec>,
+    },
     Stop,
     Status,
     Restart,
@@ -64,12 +239,12 @@ fn main() -> Result<()> {
     match cli.command {
         Some(Commands::Daemon { action }) => {
             match action {
-                DaemonAction::Start => {
+                DaemonAction::Start { output } => {
                     // For now, just run the daemon in foreground for testing
                     eprintln!("Starting daemon in foreground (use Ctrl+C to stop)...");
                     let rt = tokio::runtime::Runtime::new()?;
                     rt.block_on(async {
-                        daemon::start().await?;
+                        daemon::start(output).await?;
                         Ok::<(), anyhow::Error>(())
                     })?;
                 }
@@ -116,15 +291,95 @@ fn main() -> Result<()> {
             let rt = tokio::runtime::Runtime::new()?;
             rt.block_on(cli::send_command(ipc::Command::Previous))?;
         }
-        Some(Commands::Volume { level }) => {
+        Some(Commands::Volume { level, boost }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            if let Some(enabled) = boost {
+                rt.block_on(cli::send_command(ipc::Command::SetVolumeBoost { enabled }))?;
+            }
+            if let Some(level) = level {
+                rt.block_on(cli::send_command(ipc::Command::SetVolume { level }))?;
+            }
+        }
+        Some(Commands::Mute) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(cli::send_command(ipc::Command::ToggleMute))?;
+        }
+        Some(Commands::Seek { position }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            let command = cli::parse_seek(&position)?;
+            rt.block_on(cli::send_command(command))?;
+        }
+        Some(Commands::Speed { factor }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(cli::send_command(ipc::Command::SetSpeed { factor }))?;
+        }
+        Some(Commands::Crossfade { seconds, curve, fade_on_skip }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(cli::send_command(ipc::Command::SetCrossfade { seconds, curve, fade_on_skip }))?;
+        }
+        Some(Commands::Replaygain { mode, preamp, prevent_clipping }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(cli::send_command(ipc::Command::SetReplayGain { mode, preamp, prevent_clipping }))?;
+        }
+        Some(Commands::Loop { action }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            let command = match action {
+                LoopAction::A { position } => ipc::Command::SetLoopA {
+                    position: position.as_deref().map(cli::parse_position).transpose()?,
+                },
+                LoopAction::B { position } => ipc::Command::SetLoopB {
+                    position: position.as_deref().map(cli::parse_position).transpose()?,
+                },
+                LoopAction::Clear => ipc::Command::ClearLoop,
+            };
+            rt.block_on(cli::send_command(command))?;
+        }
+        Some(Commands::Chapter { action }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            let command = match action {
+                ChapterAction::List => return rt.block_on(cli::show_chapters()),
+                ChapterAction::Next => ipc::Command::NextChapter,
+                ChapterAction::Prev => ipc::Command::PrevChapter,
+            };
+            rt.block_on(cli::send_command(command))?;
+        }
+        Some(Commands::Audiobook { action }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            let command = match action {
+                AudiobookAction::On { min_minutes } => ipc::Command::SetAudiobookMode { enabled: Some(true), min_minutes },
+                AudiobookAction::Off => ipc::Command::SetAudiobookMode { enabled: Some(false), min_minutes: None },
+            };
+            rt.block_on(cli::send_command(command))?;
+        }
+        Some(Commands::Sleep { when }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            let command = cli::parse_sleep(&when)?;
+            rt.block_on(cli::send_command(command))?;
+        }
+        Some(Commands::Channels { balance, mono, swap }) => {
             let rt = tokio::runtime::Runtime::new()?;
-            let level = level.min(100);
-            rt.block_on(cli::send_command(ipc::Command::SetVolume { level }))?;
+            rt.block_on(cli::send_command(ipc::Command::SetChannels { balance, mono, swap }))?;
+        }
+        Some(Commands::Silence { mode, threshold, min_length }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(cli::send_command(ipc::Command::SetSilence {
+                mode,
+                threshold_db: threshold,
+                min_seconds: min_length,
+            }))?;
         }
         Some(Commands::Add { paths }) => {
             let rt = tokio::runtime::Runtime::new()?;
             rt.block_on(cli::send_command(ipc::Command::AddTracks { paths }))?;
         }
+        Some(Commands::Analyze { paths, background, force }) => {
+            if background {
+                let rt = tokio::runtime::Runtime::new()?;
+                rt.block_on(cli::send_command(ipc::Command::Analyze { paths }))?;
+            } else {
+                cli::analyze(&paths, force)?;
+            }
+        }
         Some(Commands::Status) => {
             let rt = tokio::runtime::Runtime::new()?;
             rt.block_on(cli::show_status())?;
@@ -133,6 +388,26 @@ fn main() -> Result<()> {
             let rt = tokio::runtime::Runtime::new()?;
             rt.block_on(cli::show_playlist())?;
         }
+        Some(Commands::Eq { action }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            let command = match action {
+                EqAction::Show => return rt.block_on(cli::show_eq()),
+                EqAction::Band { band, gain } => ipc::Command::SetEqBand { band, gain },
+                EqAction::Preset { name } => ipc::Command::SetEqPreset { name },
+                EqAction::Save { name } => ipc::Command::SaveEqPreset { name },
+                EqAction::On => ipc::Command::SetEqEnabled { enabled: true },
+                EqAction::Off => ipc::Command::SetEqEnabled { enabled: false },
+            };
+            rt.block_on(cli::send_command(command))?;
+        }
+        Some(Commands::Output { action }) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            let command = match action {
+                OutputAction::List => return rt.block_on(cli::list_output_devices()),
+                OutputAction::Set { name } => ipc::Command::SetOutputDevice { name },
+            };
+            rt.block_on(cli::send_command(command))?;
+        }
         Some(Commands::Clear) => {
             let rt = tokio::runtime::Runtime::new()?;
             rt.block_on(cli::send_command(ipc::Command::ClearPlaylist))?;
@@ -0,0 +1,233 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+// Bitrates in kbps, indexed by the 4-bit bitrate field. Index 0 ("free") and
+// 15 (invalid) are rejected by the header parser.
+const BITRATES_V1_L1: [u32; 15] = [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448];
+const BITRATES_V1_L2: [u32; 15] = [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384];
+const BITRATES_V1_L3: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const BITRATES_V2_L1: [u32; 15] = [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256];
+const BITRATES_V2_L23: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MpegVersion {
+    V1,
+    V2,
+    V25,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    version: MpegVersion,
+    layer: u8,
+    sample_rate: u32,
+    channels: u16,
+    /// Samples per channel carried by this frame
+    samples: u32,
+    /// Total frame length in bytes, header included
+    frame_len: usize,
+}
+
+impl FrameHeader {
+    fn parse(bytes: [u8; 4]) -> Option<Self> {
+        let word = u32::from_be_bytes(bytes);
+        if word & 0xFFE0_0000 != 0xFFE0_0000 {
+            return None;
+        }
+
+        let version = match (word >> 19) & 0b11 {
+            0b00 => MpegVersion::V25,
+            0b10 => MpegVersion::V2,
+            0b11 => MpegVersion::V1,
+            _ => return None,
+        };
+        let layer = match (word >> 17) & 0b11 {
+            0b01 => 3,
+            0b10 => 2,
+            0b11 => 1,
+            _ => return None,
+        };
+
+        let bitrate_index = ((word >> 12) & 0b1111) as usize;
+        if bitrate_index == 0 || bitrate_index == 15 {
+            return None;
+        }
+        let bitrate = match (version, layer) {
+            (MpegVersion::V1, 1) => BITRATES_V1_L1[bitrate_index],
+            (MpegVersion::V1, 2) => BITRATES_V1_L2[bitrate_index],
+            (MpegVersion::V1, _) => BITRATES_V1_L3[bitrate_index],
+            (_, 1) => BITRATES_V2_L1[bitrate_index],
+            _ => BITRATES_V2_L23[bitrate_index],
+        };
+
+        let base_rate = match (word >> 10) & 0b11 {
+            0 => 44100,
+            1 => 48000,
+            2 => 32000,
+            _ => return None,
+        };
+        let sample_rate = match version {
+            MpegVersion::V1 => base_rate,
+            MpegVersion::V2 => base_rate / 2,
+            MpegVersion::V25 => base_rate / 4,
+        };
+
+        let padding = (word >> 9) & 1;
+        let channels = if (word >> 6) & 0b11 == 0b11 { 1 } else { 2 };
+
+        let samples = match (version, layer) {
+            (_, 1) => 384,
+            (MpegVersion::V1, _) | (_, 2) => 1152,
+            _ => 576,
+        };
+
+        let frame_len = if layer == 1 {
+            (12 * bitrate * 1000 / sample_rate + padding) * 4
+        } else {
+            samples / 8 * bitrate * 1000 / sample_rate + padding
+        } as usize;
+
+        Some(Self {
+            version,
+            layer,
+            sample_rate,
+            channels,
+            samples,
+            frame_len,
+        })
+    }
+
+    /// Whether `other` can belong to the same stream as this frame. Used to
+    /// reject false syncs inside corrupt data.
+    fn is_compatible(&self, other: &FrameHeader) -> bool {
+        self.version == other.version
+            && self.layer == other.layer
+            && self.sample_rate == other.sample_rate
+    }
+}
+
+/// Skips a leading ID3v2 tag, if any, and returns the offset where the audio
+/// data starts. The reader is left positioned at that offset.
+fn skip_id3v2<R: Read + Seek>(reader: &mut R) -> io::Result<u64> {
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut header = [0u8; 10];
+    let start = match reader.read_exact(&mut header) {
+        Ok(()) if &header[0..3] == b"ID3" => {
+            let size = header[6..10]
+                .iter()
+                .fold(0u64, |acc, b| (acc << 7) | (*b as u64 & 0x7F));
+            let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+            10 + size + footer
+        }
+        Ok(()) => 0,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+        Err(e) => return Err(e),
+    };
+
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(start)
+}
+
+/// Walks the frame headers of an MPEG audio stream without decoding, yielding
+/// each frame together with its byte offset. Garbage between frames is
+/// skipped byte by byte until the next plausible header.
+struct FrameScanner<R> {
+    reader: R,
+    offset: u64,
+    first: Option<FrameHeader>,
+}
+
+impl<R: Read + Seek> FrameScanner<R> {
+    fn new(mut reader: R) -> io::Result<Self> {
+        let offset = skip_id3v2(&mut reader)?;
+        Ok(Self {
+            reader,
+            offset,
+            first: None,
+        })
+    }
+}
+
+impl<R: Read> FrameScanner<R> {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8; 1];
+        self.reader.read_exact(&mut byte).ok().map(|_| byte[0])
+    }
+}
+
+impl<R: Read> Iterator for FrameScanner<R> {
+    type Item = (u64, FrameHeader);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut window = [0u8; 4];
+        self.reader.read_exact(&mut window).ok()?;
+
+        loop {
+            let header = FrameHeader::parse(window)
+                .filter(|h| self.first.is_none_or(|first| first.is_compatible(h)));
+
+            if let Some(header) = header {
+                let offset = self.offset;
+                self.first.get_or_insert(header);
+
+                let body = header.frame_len.saturating_sub(4) as u64;
+                let skipped = io::copy(&mut self.reader.by_ref().take(body), &mut io::sink()).ok()?;
+                self.offset += 4 + skipped;
+                return Some((offset, header));
+            }
+
+            window.rotate_left(1);
+            window[3] = self.read_byte()?;
+            self.offset += 1;
+        }
+    }
+}
+
+/// Measures an MPEG audio stream that carries no Xing/Info or VBRI header by
+/// walking every frame header and summing up the samples. Symphonia only
+/// extrapolates from the first frames then, which is off for VBR files.
+/// Returns `None` when the stream has such a header, since symphonia reads
+/// the frame count (and the encoder delay) from it already.
+pub fn scan_duration<R: Read + Seek>(mut reader: R) -> io::Result<Option<Duration>> {
+    let first = FrameScanner::new(&mut reader)?.next();
+    let Some((offset, header)) = first else {
+        return Ok(None);
+    };
+
+    let mut frame = Vec::with_capacity(header.frame_len);
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.by_ref().take(header.frame_len as u64).read_to_end(&mut frame)?;
+    if has_vbr_header(&header, &frame) {
+        return Ok(None);
+    }
+
+    let total_samples: u64 = FrameScanner::new(&mut reader)?
+        .map(|(_, h)| h.samples as u64)
+        .sum();
+    if total_samples == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Duration::from_secs_f64(
+        total_samples as f64 / header.sample_rate as f64,
+    )))
+}
+
+/// Whether the first frame of the stream holds a Xing/Info or VBRI header
+/// instead of audio.
+fn has_vbr_header(header: &FrameHeader, frame: &[u8]) -> bool {
+    // The Xing tag sits right after the side information
+    let side_info = match (header.version, header.channels) {
+        (MpegVersion::V1, 1) => 17,
+        (MpegVersion::V1, _) => 32,
+        (_, 1) => 9,
+        _ => 17,
+    };
+    let xing = 4 + side_info;
+    if matches!(frame.get(xing..xing + 4), Some(b"Xing" | b"Info")) {
+        return true;
+    }
+
+    // VBRI (Fraunhofer) always sits 32 bytes after the frame header
+    matches!(frame.get(36..40), Some(b"VBRI"))
+}
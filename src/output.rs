@@ -0,0 +1,387 @@
+use anyhow::{Context, Result};
+use rodio::cpal::traits::HostTrait;
+use rodio::source::UniformSourceIterator;
+use rodio::{Device, DeviceTrait, OutputStream, OutputStreamHandle, Sink};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::error;
+
+// Format the headless backends render in
+const RENDER_CHANNELS: u16 = 2;
+const RENDER_SAMPLE_RATE: u32 = 44100;
+
+// Samples rendered per step, 10 ms
+const RENDER_CHUNK: usize = (RENDER_SAMPLE_RATE / 100) as usize * RENDER_CHANNELS as usize;
+
+/// Where the player's audio ends up. A backend creates the `Sink` the player
+/// appends to and keeps whatever consumes it running.
+pub trait AudioBackend {
+    /// Creates a sink playing through this backend. Headless backends feed a
+    /// single sink; creating another one stops the previous.
+    fn create_sink(&mut self) -> Result<Sink>;
+
+    /// Output device in use, `None` for the system default or no device
+    fn device(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Backend selection, as given to `daemon start --output`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendSpec {
+    /// Sound card through rodio, `None` for the system default device
+    Device(Option<String>),
+    /// Discards the audio, consuming it `speedup` times as fast as real time
+    Null { speedup: f64 },
+    /// Renders to a 16-bit WAV file in real time
+    Wav(PathBuf),
+    /// Discards the audio, consuming only as much as the steps let through
+    #[cfg(test)]
+    Stepped(RenderSteps),
+}
+
+impl FromStr for BackendSpec {
+    type Err = anyhow::Error;
+
+    /// `device`, `device:<name>`, `null`, `null:<speedup>` or `wav:<path>`
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None),
+        };
+
+        match (kind, arg) {
+            ("device", name) => Ok(Self::Device(name.map(str::to_string))),
+            ("null", None) => Ok(Self::Null { speedup: 1.0 }),
+            ("null", Some(speedup)) => {
+                let speedup: f64 = speedup.parse().context(format!("Invalid speedup: {}", speedup))?;
+                if !speedup.is_finite() || speedup <= 0.0 {
+                    anyhow::bail!("Speedup must be a positive number");
+                }
+                Ok(Self::Null { speedup })
+            }
+            ("wav", Some(path)) if !path.is_empty() => Ok(Self::Wav(PathBuf::from(path))),
+            _ => anyhow::bail!("Unknown output '{}', expected device[:<name>], null[:<speedup>] or wav:<path>", s),
+        }
+    }
+}
+
+/// Opens the backend described by `spec`.
+pub fn open(spec: &BackendSpec) -> Result<Box<dyn AudioBackend>> {
+    Ok(match spec {
+        BackendSpec::Device(name) => Box::new(DeviceBackend::open(name.as_deref())?),
+        BackendSpec::Null { speedup } => Box::new(NullBackend {
+            pace: Pace::RealTime(*speedup),
+            renderer: None,
+        }),
+        #[cfg(test)]
+        BackendSpec::Stepped(steps) => Box::new(NullBackend {
+            pace: Pace::Steps(steps.clone()),
+            renderer: None,
+        }),
+        BackendSpec::Wav(path) => Box::new(WavBackend {
+            path: path.clone(),
+            renderer: None,
+        }),
+    })
+}
+
+/// A sound card. Sinks created from it go silent once it is dropped.
+pub struct DeviceBackend {
+    _stream: OutputStream,
+    handle: OutputStreamHandle,
+    device: Option<String>,
+}
+
+impl DeviceBackend {
+    /// Opens the output device called `name`, or the system default.
+    pub fn open(name: Option<&str>) -> Result<Self> {
+        let (stream, handle) = match name {
+            Some(name) => OutputStream::try_from_device(&find_device(name)?)
+                .context(format!("Failed to open output device: {}", name))?,
+            None => OutputStream::try_default()
+                .context("Failed to create audio output stream")?,
+        };
+
+        Ok(Self {
+            _stream: stream,
+            handle,
+            device: name.map(str::to_string),
+        })
+    }
+}
+
+impl AudioBackend for DeviceBackend {
+    fn create_sink(&mut self) -> Result<Sink> {
+        Sink::try_new(&self.handle).context("Failed to create audio sink")
+    }
+
+    fn device(&self) -> Option<String> {
+        self.device.clone()
+    }
+}
+
+/// Plays into the void, for machines without a sound card.
+pub struct NullBackend {
+    pace: Pace,
+    renderer: Option<Renderer>,
+}
+
+impl AudioBackend for NullBackend {
+    fn create_sink(&mut self) -> Result<Sink> {
+        self.renderer = None;
+        let (sink, renderer) = Renderer::spawn(self.pace.clone(), |_| Ok(()))?;
+        self.renderer = Some(renderer);
+        Ok(sink)
+    }
+}
+
+/// Records everything played, silence included, to a WAV file.
+pub struct WavBackend {
+    path: PathBuf,
+    renderer: Option<Renderer>,
+}
+
+impl AudioBackend for WavBackend {
+    fn create_sink(&mut self) -> Result<Sink> {
+        // Finish the previous file before starting over
+        self.renderer = None;
+        let mut writer = WavWriter::create(&self.path)
+            .context(format!("Failed to create WAV file: {}", self.path.display()))?;
+        let (sink, renderer) = Renderer::spawn(Pace::RealTime(1.0), move |samples| writer.write(samples))?;
+        self.renderer = Some(renderer);
+        Ok(sink)
+    }
+}
+
+/// How fast a headless backend consumes audio
+#[derive(Clone)]
+enum Pace {
+    /// `speedup` times as fast as a sound card would
+    RealTime(f64),
+    /// As the steps are taken, regardless of the time
+    #[cfg(test)]
+    Steps(RenderSteps),
+}
+
+/// Lets a stepped backend render a given amount of audio at a time, so
+/// tests know exactly how far playback got.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct RenderSteps(Arc<StepsShared>);
+
+#[cfg(test)]
+#[derive(Default)]
+struct StepsShared {
+    // Frames let through and not rendered yet
+    frames: Mutex<usize>,
+    changed: Condvar,
+}
+
+#[cfg(test)]
+impl RenderSteps {
+    /// Renders `duration` more audio and waits until it went through.
+    pub fn advance(&self, duration: Duration) {
+        let frames = (duration.as_secs_f64() * RENDER_SAMPLE_RATE as f64).round() as usize;
+        let mut left = self.0.frames.lock().unwrap();
+        *left += frames;
+        self.0.changed.notify_all();
+        drop(self.0.changed.wait_while(left, |left| *left > 0).unwrap());
+    }
+
+    /// Waits for up to `max` frames to render, `0` when none came in time
+    fn take(&self, max: usize) -> usize {
+        let left = self.0.frames.lock().unwrap();
+        let (left, _) = self
+            .0
+            .changed
+            .wait_timeout_while(left, Duration::from_millis(10), |left| *left == 0)
+            .unwrap();
+        (*left).min(max)
+    }
+
+    fn rendered(&self, frames: usize) {
+        *self.0.frames.lock().unwrap() -= frames;
+        self.0.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for RenderSteps {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+impl std::fmt::Debug for RenderSteps {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("RenderSteps")
+    }
+}
+
+/// Pulls a sink's output on its own thread, at the pace a sound card would.
+struct Renderer {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Renderer {
+    fn spawn(
+        pace: Pace,
+        mut write: impl FnMut(&[i16]) -> io::Result<()> + Send + 'static,
+    ) -> Result<(Sink, Self)> {
+        let (sink, queue) = Sink::new_idle();
+        let mut output = UniformSourceIterator::<_, i16>::new(queue, RENDER_CHANNELS, RENDER_SAMPLE_RATE);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let keep_running = Arc::clone(&running);
+        let thread = thread::Builder::new()
+            .name("audio-render".to_string())
+            .spawn(move || {
+                let started = Instant::now();
+                let mut frames = 0u64;
+                let mut chunk = Vec::with_capacity(RENDER_CHUNK);
+
+                while keep_running.load(Ordering::Acquire) {
+                    let samples = match &pace {
+                        Pace::RealTime(_) => RENDER_CHUNK,
+                        #[cfg(test)]
+                        Pace::Steps(steps) => match steps.take(RENDER_CHUNK / RENDER_CHANNELS as usize) {
+                            0 => continue,
+                            frames => frames * RENDER_CHANNELS as usize,
+                        },
+                    };
+                    chunk.clear();
+                    chunk.extend(output.by_ref().take(samples));
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    if let Err(e) = write(&chunk) {
+                        error!("Failed to write audio output: {}", e);
+                        break;
+                    }
+
+                    let rendered = chunk.len() / RENDER_CHANNELS as usize;
+                    match &pace {
+                        Pace::RealTime(speedup) => {
+                            frames += rendered as u64;
+                            let due = Duration::from_secs_f64(frames as f64 / RENDER_SAMPLE_RATE as f64 / speedup);
+                            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                                thread::sleep(wait);
+                            }
+                        }
+                        #[cfg(test)]
+                        Pace::Steps(steps) => steps.rendered(rendered),
+                    }
+                }
+            })
+            .context("Failed to start audio render thread")?;
+
+        Ok((sink, Self { running, thread: Some(thread) }))
+    }
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// 16-bit PCM WAV writer. The header is kept up to date as it goes, so the
+/// file stays readable when the daemon is killed.
+struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+    unsynced: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let channels = RENDER_CHANNELS as u32;
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&(channels as u16).to_le_bytes())?;
+        file.write_all(&RENDER_SAMPLE_RATE.to_le_bytes())?;
+        file.write_all(&(RENDER_SAMPLE_RATE * block_align).to_le_bytes())?;
+        file.write_all(&(block_align as u16).to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { file, data_len: 0, unsynced: 0 })
+    }
+
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+
+        let bytes = samples.len() as u32 * 2;
+        self.data_len = self.data_len.saturating_add(bytes);
+        self.unsynced += bytes;
+        // About once a second
+        if self.unsynced >= RENDER_SAMPLE_RATE * RENDER_CHANNELS as u32 * 2 {
+            self.sync_header()?;
+        }
+        Ok(())
+    }
+
+    fn sync_header(&mut self) -> io::Result<()> {
+        self.unsynced = 0;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&self.data_len.saturating_add(36).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.sync_header();
+    }
+}
+
+fn find_device(name: &str) -> Result<Device> {
+    rodio::cpal::default_host()
+        .output_devices()
+        .context("Failed to list output devices")?
+        .find(|device| device.name().is_ok_and(|n| n == name))
+        .context(format!("No output device named '{}'", name))
+}
+
+/// Names of the output devices offered by the default audio host
+pub fn list_devices() -> Result<Vec<String>> {
+    let devices = rodio::cpal::default_host()
+        .output_devices()
+        .context("Failed to list output devices")?;
+
+    Ok(devices.filter_map(|device| device.name().ok()).collect())
+}
+
+/// Name of the device the system plays on by default
+pub fn default_device() -> Option<String> {
+    rodio::cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
@@ -1,258 +1,1305 @@
 use anyhow::{Context, Result};
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
+use rodio::source::SeekError;
+use rodio::{Sink, Source};
 
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::error;
 
-use crate::ipc::PlaybackState;
+use crate::ipc::{ChannelSettings, Chapter, CrossfadeCurve, PlaybackState, ReplayGainMode, SilenceMode, SilenceSettings};
 
-use minimp3::{Decoder as Mp3Decoder, Frame};
+use crate::channel_mix::{ChannelMix, ChannelShared};
+use crate::chapters;
+use crate::config::Storage;
+use crate::cue::{self, Segment};
+use crate::decoder::{ScanCache, SymphoniaSource, TrackMetadata};
+use crate::equalizer::{EqPresets, EqShared, Equalizer, BAND_COUNT, MAX_GAIN_DB};
+use crate::ipc::{EqStatus, Visualization};
+use crate::loudness::GainCache;
+use crate::output::{self, AudioBackend, BackendSpec};
+use crate::replaygain::{self, ReplayGainSettings, ReplayGainSource, TrackGain};
+use crate::silence::{Silence, SilenceShared};
+use crate::stream::{self, HttpStream, StreamSource};
+use crate::time_stretch::{SpeedControl, TimeStretch};
+use crate::track_queue::{AbLoop, BoxedSource, CrossfadeSettings, QueueShared, QueuedSource, TrackQueue};
+use crate::visualizer::{SampleTap, Tap};
+use crate::volume::{self, Volume, VolumeControl, MAX_VOLUME, UNITY_VOLUME};
 
+const MAX_CROSSFADE_SECONDS: f64 = 30.0;
+const MAX_PREAMP_DB: f32 = 15.0;
+const DEFAULT_VOLUME: u8 = 70;
+const MIN_SILENCE_THRESHOLD_DB: f32 = -90.0;
+const MIN_SILENCE_SECONDS: f32 = 0.1;
+const MAX_SILENCE_SECONDS: f32 = 60.0;
+
+// Going back a chapter this far into one restarts it instead
+const CHAPTER_RESTART_SECONDS: f64 = 3.0;
+
+// A position this close before a chapter start counts as in the chapter,
+// since seeking there may land a rounding error short
+const CHAPTER_SLACK_SECONDS: f64 = 0.05;
+
+/// What the player reports as it happens. Sent from the audio thread as
+/// well, so the events of one track arrive in the order they occurred.
 #[derive(Debug, Clone)]
 pub enum PlayerEvent {
-    TrackChanged(()),
-    StateChanged(()),
+    /// The output started playing a track, either loaded or queued
+    TrackStarted {
+        path: String,
+        metadata: TrackMetadata,
+        duration: f64,
+    },
+    /// A track played to its end. Not sent for tracks that are replaced or
+    /// stopped.
+    TrackFinished { path: String },
+    StateChanged(PlaybackState),
+    VolumeChanged { level: u8, muted: bool },
+    Seeked { position: f64 },
+    /// A damaged part of a track was skipped, the `count`th one in it
+    DecodeError { path: String, message: String, count: usize },
+    /// A radio stream announced what it's playing now
+    StreamTitle { path: String, title: String },
 }
 
-struct Mp3Source<R: std::io::Read> {
-    decoder: Mp3Decoder<R>,
-    current_frame: Option<Frame>,
-    frame_pos: usize,
+/// Playback position of one track, advanced by the samples the output has
+/// actually pulled from it rather than by wall-clock time.
+#[derive(Debug, Default)]
+pub struct PlaybackClock {
+    // Position reached before the current run of `samples`
+    base_nanos: AtomicU64,
+    samples: AtomicU64,
+    // sample_rate << 16 | channels
+    spec: AtomicU64,
 }
 
-impl<R: std::io::Read> Mp3Source<R> {
-    fn new(reader: R) -> Self {
+impl PlaybackClock {
+    pub fn position(&self) -> Duration {
+        let base = Duration::from_nanos(self.base_nanos.load(Ordering::Acquire));
+        let spec = self.spec.load(Ordering::Acquire);
+        let per_second = (spec >> 16) as u128 * (spec & 0xFFFF) as u128;
+        if per_second == 0 {
+            return base;
+        }
+        let samples = self.samples.load(Ordering::Acquire) as u128;
+        base + Duration::from_nanos((samples * 1_000_000_000 / per_second) as u64)
+    }
+
+    /// Moves the position on by `by` for audio left out rather than played
+    pub fn skip(&self, by: Duration) {
+        self.base_nanos.fetch_add(by.as_nanos() as u64, Ordering::AcqRel);
+    }
+
+    /// Starts counting again from `position`
+    pub fn reset(&self, position: Duration) {
+        self.samples.store(0, Ordering::Release);
+        self.base_nanos.store(position.as_nanos() as u64, Ordering::Release);
+    }
+
+    fn set_spec(&self, sample_rate: u32, channels: u16) {
+        // Fold the samples counted so far into the base before the rate changes
+        self.reset(self.position());
+        self.spec.store((sample_rate as u64) << 16 | channels as u64, Ordering::Release);
+    }
+
+    /// Samples the output still has to pull to reach `target`, which is
+    /// rounded to a whole frame. `None` before the format is known.
+    pub fn samples_until(&self, target: Duration) -> Option<u64> {
+        let spec = self.spec.load(Ordering::Acquire);
+        let (sample_rate, channels) = (spec >> 16, spec & 0xFFFF);
+        if sample_rate == 0 {
+            return None;
+        }
+        // Counted from the base, which is where the last run of samples began
+        let base = Duration::from_nanos(self.base_nanos.load(Ordering::Acquire));
+        let frames = target.saturating_sub(base).as_nanos() * sample_rate as u128 / 1_000_000_000;
+        let target_samples = frames as u64 * channels;
+        Some(target_samples.saturating_sub(self.samples.load(Ordering::Acquire)))
+    }
+
+    /// Whether the output has pulled any sample of this track yet
+    pub fn has_started(&self) -> bool {
+        self.spec.load(Ordering::Acquire) != 0
+    }
+
+    fn advance(&self) {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts every sample handed to the output into a shared `PlaybackClock`.
+pub struct ClockedSource<S> {
+    inner: S,
+    clock: Arc<PlaybackClock>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl<S: Source> ClockedSource<S>
+where
+    S::Item: rodio::Sample,
+{
+    pub fn new(inner: S, clock: Arc<PlaybackClock>) -> Self {
         Self {
-            decoder: Mp3Decoder::new(reader),
-            current_frame: None,
-            frame_pos: 0,
+            inner,
+            clock,
+            sample_rate: 0,
+            channels: 0,
         }
     }
 }
 
-impl<R: std::io::Read> rodio::Source for Mp3Source<R> {
+impl<S: Source> Iterator for ClockedSource<S>
+where
+    S::Item: rodio::Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        let sample = self.inner.next()?;
+
+        let (sample_rate, channels) = (self.inner.sample_rate(), self.inner.channels());
+        if sample_rate != self.sample_rate || channels != self.channels {
+            self.sample_rate = sample_rate;
+            self.channels = channels;
+            self.clock.set_spec(sample_rate, channels);
+        }
+        self.clock.advance();
+
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for ClockedSource<S>
+where
+    S::Item: rodio::Sample,
+{
     fn current_frame_len(&self) -> Option<usize> {
-        None
+        self.inner.current_frame_len()
     }
 
     fn channels(&self) -> u16 {
-        self.current_frame.as_ref().map(|f| f.channels as u16).unwrap_or(2)
+        self.inner.channels()
     }
 
     fn sample_rate(&self) -> u32 {
-        self.current_frame.as_ref().map(|f| f.sample_rate as u32).unwrap_or(44100)
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
     }
 
-    fn total_duration(&self) -> Option<std::time::Duration> {
-        None
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        // Runs on the audio thread, so no sample is counted against the old position
+        let pos = self.total_duration().map_or(pos, |total| pos.min(total));
+        self.clock.reset(pos);
+        Ok(())
     }
 }
 
-impl<R: std::io::Read> Iterator for Mp3Source<R> {
-    type Item = i16;
-
-    fn next(&mut self) -> Option<i16> {
-        loop {
-            if let Some(frame) = &self.current_frame {
-                if self.frame_pos < frame.data.len() {
-                    let sample = frame.data[self.frame_pos];
-                    self.frame_pos += 1;
-                    return Some(sample);
-                } else {
-                    self.current_frame = None;
-                    self.frame_pos = 0;
-                }
+/// A track handed to the queue, either playing or waiting behind the
+/// current one.
+struct QueuedTrack {
+    path: String,
+    metadata: TrackMetadata,
+    duration: f64,
+    clock: Arc<PlaybackClock>,
+    gain: Arc<TrackGain>,
+    chapters: Arc<[Chapter]>,
+    decode_errors: Arc<AtomicUsize>,
+    stream_title: Arc<Mutex<Option<String>>>,
+}
+
+/// Decodes `path`, returning the source and its duration in seconds (0 when
+/// unknown). The file is streamed from disk while playing. Probing it
+/// blocks, so call this off the async executor.
+pub fn open_source(path: &str) -> Result<(BoxedSource, f64)> {
+    let source = SymphoniaSource::open(path)?;
+    let duration = source.total_duration()
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    Ok((Box::new(source), duration))
+}
+
+/// A track that has been opened and probed, ready for `Player::load_track`
+/// or `Player::queue_next`.
+pub struct PreparedTrack {
+    source: QueuedSource,
+    track: QueuedTrack,
+}
+
+impl PreparedTrack {
+    /// Length in seconds, 0 when unknown
+    pub fn duration(&self) -> f64 {
+        self.track.duration
+    }
+
+    /// Starts playback `position` seconds into the track
+    pub fn start_at(&mut self, position: f64) -> Result<()> {
+        self.source.source.try_seek(Duration::from_secs_f64(position.max(0.0)))
+            .map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
+
+/// Opens tracks the way the `Player` wants them. Can be moved to a blocking
+/// thread, unlike the player itself.
+#[derive(Clone)]
+pub struct TrackLoader {
+    replaygain: ReplayGainSettings,
+    gain_cache: Arc<Mutex<GainCache>>,
+    scans: Arc<Mutex<ScanCache>>,
+    silence: Arc<SilenceShared>,
+    event_tx: mpsc::UnboundedSender<PlayerEvent>,
+}
+
+impl TrackLoader {
+    /// Opens `path` and wraps it for the track queue, together with the
+    /// handles the player keeps to follow it. Cue sheet entries play just
+    /// their part of the file, as if it were a file of its own. Chapters
+    /// are read from whole files only. URLs are streamed, and have neither
+    /// a duration nor tags beyond the titles the station sends.
+    pub fn open(&self, path: &str) -> Result<PreparedTrack> {
+        self.open_at(path, Duration::ZERO, Arc::new(PlaybackClock::default()), None)
+    }
+
+    /// Like `open`, but already at `start` and counting into `clock`, which
+    /// is left alone until the output pulls from the new copy. `gain`, when
+    /// given, is used instead of the one from the tags.
+    fn open_at(
+        &self,
+        path: &str,
+        start: Duration,
+        clock: Arc<PlaybackClock>,
+        gain: Option<Arc<TrackGain>>,
+    ) -> Result<PreparedTrack> {
+        let live = stream::is_url(path);
+        let cue_track = if live { None } else { cue::resolve(path) };
+        let file = cue_track.as_ref().map_or(path, |track| track.file.as_str());
+
+        let stream_title = Arc::new(Mutex::new(None));
+        let mut network = None;
+        let mut source = if live {
+            let title = Arc::clone(&stream_title);
+            let event_tx = self.event_tx.clone();
+            let title_path = path.to_string();
+            let stream = HttpStream::connect(path, Box::new(move |text| {
+                *title.lock().unwrap() = Some(text.to_string());
+                let _ = event_tx.send(PlayerEvent::StreamTitle {
+                    path: title_path.clone(),
+                    title: text.to_string(),
+                });
+            }))?;
+            network = Some(stream.closer());
+            SymphoniaSource::open_stream(path, stream)?
+        } else {
+            SymphoniaSource::open_with(file, &self.scans)?
+        };
+        let mut metadata = source.metadata().clone();
+
+        // Errors while probing were logged already; count them all the same
+        let decode_errors = Arc::new(AtomicUsize::new(source.decode_errors()));
+        let error_count = Arc::clone(&decode_errors);
+        let event_tx = self.event_tx.clone();
+        let error_path = path.to_string();
+        source.on_decode_error(Box::new(move |message| {
+            let _ = event_tx.send(PlayerEvent::DecodeError {
+                path: error_path.clone(),
+                message: message.to_string(),
+                count: error_count.fetch_add(1, Ordering::Relaxed) + 1,
+            });
+        }));
+
+        let source: BoxedSource = match &cue_track {
+            Some(track) => {
+                metadata.title = Some(track.title.clone().unwrap_or_else(|| format!("Track {:02}", track.number)));
+                metadata.artist = track.performer.clone().or(metadata.artist);
+                metadata.album = track.album.clone().or(metadata.album);
+                let segment = Segment::new(source, track)
+                    .map_err(|e| anyhow::anyhow!("Failed to seek to track {}: {}", track.number, e))?;
+                Box::new(segment)
             }
-            match self.decoder.next_frame() {
-                Ok(frame) => {
-                    self.current_frame = Some(frame);
-                }
-                Err(_) => return None,
+            // Decoded on a thread of its own, so the network never holds up the output
+            None => match network {
+                Some(network) => Box::new(StreamSource::new(source, network)?),
+                None => Box::new(source),
+            },
+        };
+        let duration = source.total_duration()
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        let chapters = match cue_track {
+            Some(_) => Vec::new(),
+            None if live => Vec::new(),
+            None => chapters::read(file),
+        };
+
+        // Fall back to our own measurement for untagged files
+        let mut info = if live { Default::default() } else { replaygain::read_tags(file) };
+        if !info.has_gain() && !live {
+            if let Some(cached) = self.gain_cache.lock().unwrap().lookup(file) {
+                info = cached.replaygain();
             }
         }
+        let gain = gain.unwrap_or_else(|| Arc::new(TrackGain::new(info, &self.replaygain)));
+
+        // Silence left out moves the clock on, so it comes before it
+        let source = Silence::new(source, Arc::clone(&self.silence), Arc::clone(&clock));
+        let mut source = ReplayGainSource::new(source, Arc::clone(&gain));
+        // Seeked below the clock, which keeps following the copy playing now
+        if !start.is_zero() {
+            source.try_seek(start).map_err(|e| anyhow::anyhow!("{}", e))?;
+        }
+        let queued = QueuedSource {
+            path: path.to_string(),
+            source: Box::new(ClockedSource::new(source, Arc::clone(&clock))),
+            clock: Arc::clone(&clock),
+            duration: (duration > 0.0).then(|| Duration::from_secs_f64(duration)),
+        };
+        let track = QueuedTrack {
+            path: path.to_string(),
+            metadata,
+            duration,
+            clock,
+            gain,
+            chapters: chapters.into(),
+            decode_errors,
+            stream_title,
+        };
+        Ok(PreparedTrack { source: queued, track })
     }
 }
 
+/// Everything about the player a client can ask for. The audio thread
+/// publishes a new snapshot after every command, so readers never wait for
+/// it and never see fields from two different moments.
+#[derive(Debug, Clone)]
+pub struct PlayerSnapshot {
+    pub state: PlaybackState,
+    pub current_track: Option<String>,
+    pub duration: f64,
+    pub volume: u8,
+    pub muted: bool,
+    /// Whether levels above 100 are allowed
+    pub volume_boost: bool,
+    pub speed: f32,
+    pub crossfade: CrossfadeSettings,
+    pub replaygain: ReplayGainSettings,
+    pub eq: EqStatus,
+    pub channels: ChannelSettings,
+    pub silence: SilenceSettings,
+    pub output_device: Option<String>,
+    /// A–B loop points in seconds; the loop runs once both are set
+    pub loop_a: Option<f64>,
+    pub loop_b: Option<f64>,
+    pub chapters: Arc<[Chapter]>,
+    clock: Arc<PlaybackClock>,
+    decode_errors: Arc<AtomicUsize>,
+    stream_title: Arc<Mutex<Option<String>>>,
+}
+
+impl PlayerSnapshot {
+    /// Position in the current track, live rather than as of the snapshot
+    pub fn position(&self) -> f64 {
+        self.clock.position().as_secs_f64()
+    }
+
+    /// Wall-clock time until the current track ends at the current speed
+    pub fn remaining(&self) -> f64 {
+        let left = (self.duration - self.position()).max(0.0);
+        left / self.speed as f64
+    }
+
+    /// Index of the chapter playing, live like the position
+    pub fn chapter(&self) -> Option<usize> {
+        chapter_at(&self.chapters, self.position())
+    }
+
+    /// Damaged parts skipped in the current track so far, live as well
+    pub fn decode_errors(&self) -> usize {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    /// What a radio stream last said it's playing
+    pub fn stream_title(&self) -> Option<String> {
+        self.stream_title.lock().unwrap().clone()
+    }
+}
+
+/// Index of the chapter `position` falls in, `None` before the first one
+fn chapter_at(chapters: &[Chapter], position: f64) -> Option<usize> {
+    chapters.iter().rposition(|chapter| chapter.start <= position + CHAPTER_SLACK_SECONDS)
+}
+
+type Job = Box<dyn FnOnce(&mut AudioEngine) + Send>;
+
+/// Handle to the audio thread. The thread owns the sink and everything
+/// about the current track; commands are queued to it and run in order.
 pub struct Player {
-    sink: Arc<Mutex<Sink>>,
-    _stream: OutputStream,
-    _stream_handle: OutputStreamHandle,
-    current_track: Arc<Mutex<Option<String>>>,
-    state: Arc<Mutex<PlaybackState>>,
-    volume: Arc<Mutex<u8>>,
-    duration: Arc<Mutex<f64>>,
-    start_time: Arc<Mutex<Option<std::time::Instant>>>,
-    paused_position: Arc<Mutex<f64>>,
+    jobs: std::sync::mpsc::Sender<Job>,
+    snapshot: watch::Receiver<PlayerSnapshot>,
+    gain_cache: Arc<Mutex<GainCache>>,
+    scans: Arc<Mutex<ScanCache>>,
     event_tx: mpsc::UnboundedSender<PlayerEvent>,
+    tap: Arc<SampleTap>,
+    silence: Arc<SilenceShared>,
 }
 
 impl Player {
-    pub fn new() -> Result<(Self, mpsc::UnboundedReceiver<PlayerEvent>)> {
-        let (stream, stream_handle) = OutputStream::try_default()
-            .context("Failed to create audio output stream")?;
-        
-        let sink = Sink::try_new(&stream_handle)
-            .context("Failed to create audio sink")?;
-        
+    /// Starts the audio thread playing through the backend described by
+    /// `backend`. A named output device that can't be opened falls back to
+    /// the system default. The loudness cache and equalizer presets are
+    /// kept in `storage`.
+    pub fn new(backend: &BackendSpec, storage: &Storage) -> Result<(Self, mpsc::UnboundedReceiver<PlayerEvent>)> {
         let (event_tx, event_rx) = mpsc::unbounded_channel();
-        
+
+        let gain_cache = storage.data_file("loudness.json").map(GainCache::open).unwrap_or_else(|e| {
+            error!("Loudness cache unavailable: {}", e);
+            GainCache::default()
+        });
+        let gain_cache = Arc::new(Mutex::new(gain_cache));
+        let eq_presets = storage.data_file("eq_presets.json").and_then(EqPresets::open).unwrap_or_else(|e| {
+            error!("Equalizer presets unavailable: {}", e);
+            EqPresets::default()
+        });
+
+        let (jobs, job_rx) = std::sync::mpsc::channel::<Job>();
+        let (ready_tx, ready_rx) = std::sync::mpsc::sync_channel(1);
+        let spec = backend.clone();
+        let engine_events = event_tx.clone();
+        let tap = Arc::new(SampleTap::default());
+        let engine_tap = Arc::clone(&tap);
+        let silence = Arc::new(SilenceShared::default());
+        let engine_silence = Arc::clone(&silence);
+        thread::Builder::new()
+            .name("player".to_string())
+            .spawn(move || {
+                // Output streams can't move between threads, so the backend
+                // is opened on the thread that keeps it
+                match AudioEngine::new(&spec, eq_presets, engine_events, engine_tap, engine_silence) {
+                    Ok(engine) => {
+                        let _ = ready_tx.send(Ok(engine.snapshot.subscribe()));
+                        engine.run(job_rx);
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                    }
+                }
+            })
+            .context("Failed to start audio thread")?;
+
+        let snapshot = ready_rx.recv().context("Audio thread failed to start")??;
+
         let player = Self {
-            sink: Arc::new(Mutex::new(sink)),
-            _stream: stream,
-            _stream_handle: stream_handle,
-            current_track: Arc::new(Mutex::new(None)),
-            state: Arc::new(Mutex::new(PlaybackState::Stopped)),
-            volume: Arc::new(Mutex::new(70)),
-            duration: Arc::new(Mutex::new(0.0)),
-            start_time: Arc::new(Mutex::new(None)),
-            paused_position: Arc::new(Mutex::new(0.0)),
+            jobs,
+            snapshot,
+            gain_cache,
+            scans: Arc::default(),
             event_tx,
+            tap,
+            silence,
         };
-        
-        // Set initial volume
-        player.sink.lock().unwrap().set_volume(0.7);
-        
         Ok((player, event_rx))
     }
 
-    pub fn load_track(&self, path: String) -> Result<()> {
-        let is_mp3 = path.to_lowercase().ends_with(".mp3");
+    /// Runs `job` on the audio thread and waits for its result.
+    async fn call<T: Send + 'static>(&self, job: impl FnOnce(&mut AudioEngine) -> T + Send + 'static) -> Result<T> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |engine| {
+                let result = job(engine);
+                // Publish first, so the caller sees its own change
+                engine.publish();
+                let _ = reply_tx.send(result);
+            }))
+            .map_err(|_| anyhow::anyhow!("Audio thread has stopped"))?;
+        reply_rx.await.context("Audio thread has stopped")
+    }
+
+    /// The latest published state
+    pub fn snapshot(&self) -> PlayerSnapshot {
+        self.snapshot.borrow().clone()
+    }
+
+    /// Snapshot of the settings tracks are opened with
+    pub fn loader(&self) -> TrackLoader {
+        TrackLoader {
+            replaygain: self.snapshot.borrow().replaygain,
+            gain_cache: Arc::clone(&self.gain_cache),
+            scans: Arc::clone(&self.scans),
+            silence: Arc::clone(&self.silence),
+            event_tx: self.event_tx.clone(),
+        }
+    }
+
+    pub async fn load_track(&self, prepared: PreparedTrack) -> Result<()> {
+        self.call(move |engine| engine.load_track(prepared)).await
+    }
+
+    /// Lines up a track to start the very sample the current one ends.
+    /// Replaces a previously queued track that hasn't started yet.
+    pub async fn queue_next(&self, prepared: PreparedTrack) -> Result<()> {
+        self.call(move |engine| engine.queue_next(prepared)).await
+    }
+
+    /// Drops the queued track unless playback has already moved on to it.
+    pub async fn clear_next(&self) -> Result<()> {
+        self.call(|engine| engine.clear_next()).await
+    }
+
+    /// Makes the queued track current once the output has started playing
+    /// it, returning its path. Returns `None` while the old track still plays.
+    pub async fn poll_transition(&self) -> Result<Option<String>> {
+        self.call(|engine| engine.poll_transition()).await
+    }
+
+    pub async fn play(&self) -> Result<()> {
+        self.call(|engine| engine.play()).await
+    }
+
+    pub async fn pause(&self) -> Result<()> {
+        self.call(|engine| engine.pause()).await
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        self.call(|engine| engine.stop()).await
+    }
+
+    pub async fn seek(&self, position: f64) -> Result<()> {
+        self.call(move |engine| engine.seek(position)).await?
+    }
+
+    pub async fn seek_relative(&self, offset: f64) -> Result<()> {
+        self.call(move |engine| engine.seek(engine.position() + offset)).await?
+    }
+
+    /// Sets the start of the A–B loop, at the current position when
+    /// `position` is `None`. A loop end before it is dropped.
+    pub async fn set_loop_a(&self, position: Option<f64>) -> Result<()> {
+        let loader = self.loader();
+        self.call(move |engine| engine.set_loop_a(position, loader)).await?
+    }
+
+    /// Sets the end of the A–B loop and starts looping. Playback jumps back
+    /// to A the very sample it reaches B.
+    pub async fn set_loop_b(&self, position: Option<f64>) -> Result<()> {
+        let loader = self.loader();
+        self.call(move |engine| engine.set_loop_b(position, loader)).await?
+    }
+
+    pub async fn clear_loop(&self) -> Result<()> {
+        self.call(|engine| engine.clear_loop()).await
+    }
+
+    pub async fn next_chapter(&self) -> Result<()> {
+        self.call(|engine| engine.next_chapter()).await?
+    }
+
+    /// Restarts the current chapter, or goes to the one before it when
+    /// the current one has only just begun
+    pub async fn prev_chapter(&self) -> Result<()> {
+        self.call(|engine| engine.prev_chapter()).await?
+    }
+
+    /// Sets the volume level, 0–100 or up to 150 with boost enabled, and
+    /// unmutes.
+    pub async fn set_volume(&self, level: u8) -> Result<()> {
+        self.call(move |engine| engine.set_volume(level)).await
+    }
+
+    /// Mutes, or brings back the volume from before muting
+    pub async fn toggle_mute(&self) -> Result<()> {
+        self.call(|engine| engine.toggle_mute()).await
+    }
+
+    /// Allows levels above 100 (up to +12 dB, limited to avoid clipping).
+    /// Turning it off brings a boosted volume back to 100.
+    pub async fn set_volume_boost(&self, enabled: bool) -> Result<()> {
+        self.call(move |engine| engine.set_volume_boost(enabled)).await
+    }
+
+    /// Scales the output by `gain` (0.0–1.0) on top of the volume, for
+    /// fades that leave the volume setting alone.
+    pub async fn set_fade(&self, gain: f32) -> Result<()> {
+        self.call(move |engine| engine.set_fade(gain)).await
+    }
+
+    /// Moves playback to another output device (`None` for the system
+    /// default). A sink can't hand its sources over, so `current` is the
+    /// current track opened again; it carries on from the same position and
+    /// in the same state. The queued track is dropped and has to be queued
+    /// again. A headless backend is replaced by the device.
+    pub async fn set_output_device(&self, name: Option<String>, current: Option<PreparedTrack>) -> Result<()> {
+        self.call(move |engine| engine.set_output_device(name.as_deref(), current)).await?
+    }
+
+    pub async fn set_crossfade(&self, seconds: f64, curve: Option<CrossfadeCurve>, fade_on_skip: Option<bool>) -> Result<()> {
+        self.call(move |engine| engine.set_crossfade(seconds, curve, fade_on_skip)).await
+    }
+
+    /// Changes how ReplayGain tags are applied. The playing and the queued
+    /// track pick up the new gain right away.
+    pub async fn set_replaygain(&self, mode: ReplayGainMode, preamp: Option<f32>, prevent_clipping: Option<bool>) -> Result<()> {
+        self.call(move |engine| engine.set_replaygain(mode, preamp, prevent_clipping)).await
+    }
+
+    /// Plays faster or slower while keeping the pitch
+    pub async fn set_speed(&self, factor: f32) -> Result<()> {
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(anyhow::anyhow!("Invalid speed: {}", factor));
+        }
+        self.call(move |engine| engine.speed.set(factor)).await
+    }
+
+    pub async fn set_eq_band(&self, band: usize, gain: f32) -> Result<()> {
+        if band >= BAND_COUNT {
+            return Err(anyhow::anyhow!("No equalizer band {}", band));
+        }
+        self.call(move |engine| {
+            engine.eq.update(|eq| {
+                eq.gains[band] = gain.clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+                eq.preset = None;
+            })
+        })
+        .await
+    }
+
+    pub async fn set_eq_preset(&self, name: String) -> Result<()> {
+        self.call(move |engine| engine.set_eq_preset(name)).await?
+    }
+
+    /// Stores the current gains as a user preset on disk
+    pub async fn save_eq_preset(&self, name: String) -> Result<()> {
+        self.call(move |engine| engine.save_eq_preset(name)).await?
+    }
+
+    /// Changes balance, mono downmix and channel swap; `None` keeps the
+    /// current value
+    pub async fn set_channels(&self, balance: Option<f32>, mono: Option<bool>, swap: Option<bool>) -> Result<()> {
+        if balance.is_some_and(|b| !b.is_finite()) {
+            return Err(anyhow::anyhow!("Invalid balance"));
+        }
+        self.call(move |engine| {
+            engine.channels.update(|channels| {
+                if let Some(balance) = balance {
+                    channels.balance = balance.clamp(-1.0, 1.0);
+                }
+                channels.mono = mono.unwrap_or(channels.mono);
+                channels.swap = swap.unwrap_or(channels.swap);
+            })
+        })
+        .await
+    }
+
+    /// Changes how silence is left out; `None` keeps the current value.
+    /// Applies to the playing track from its next silence on.
+    pub async fn set_silence(&self, mode: SilenceMode, threshold_db: Option<f32>, min_seconds: Option<f32>) -> Result<()> {
+        if threshold_db.is_some_and(|t| !t.is_finite()) || min_seconds.is_some_and(|s| !s.is_finite()) {
+            return Err(anyhow::anyhow!("Invalid silence settings"));
+        }
+        self.call(move |engine| {
+            engine.silence.update(|silence| {
+                silence.mode = mode;
+                if let Some(threshold_db) = threshold_db {
+                    silence.threshold_db = threshold_db.clamp(MIN_SILENCE_THRESHOLD_DB, 0.0);
+                }
+                if let Some(min_seconds) = min_seconds {
+                    silence.min_seconds = min_seconds.clamp(MIN_SILENCE_SECONDS, MAX_SILENCE_SECONDS);
+                }
+            })
+        })
+        .await
+    }
 
-        let data = std::fs::read(&path)
-            .context(format!("Failed to read audio file: {}", path))?;
-        let cursor = std::io::Cursor::new(data);
+    pub async fn set_eq_enabled(&self, enabled: bool) -> Result<()> {
+        self.call(move |engine| engine.eq.update(|eq| eq.enabled = enabled)).await
+    }
 
-        let (source, duration) = if is_mp3 {
-            let mp3_source = Mp3Source::new(cursor);
-            // For MP3, try to get duration by decoding a bit, but for simplicity, use 0.0
-            let duration = 0.0; // TODO: estimate duration for MP3
-            (Box::new(mp3_source) as Box<dyn Source<Item = i16> + Send>, duration)
+    /// Spectrum and levels of the output, silent unless playing
+    pub fn visualization(&self) -> Visualization {
+        if self.snapshot.borrow().state == PlaybackState::Playing {
+            self.tap.analyze()
         } else {
-            let source = Decoder::new(cursor)
-                .context("Failed to decode audio file")?;
-            let duration = source.total_duration()
-                .map(|d| d.as_secs_f64())
-                .unwrap_or(0.0);
-            (Box::new(source) as Box<dyn Source<Item = i16> + Send>, duration)
+            Visualization::silent()
+        }
+    }
+
+    /// Loudness measurements used for files without ReplayGain tags
+    pub fn gain_cache(&self) -> Arc<Mutex<GainCache>> {
+        Arc::clone(&self.gain_cache)
+    }
+}
+
+/// The player's state, owned by the audio thread.
+struct AudioEngine {
+    sink: Sink,
+    backend: Box<dyn AudioBackend>,
+    current_track: Option<String>,
+    state: PlaybackState,
+    volume: u8,
+    muted: bool,
+    volume_boost: bool,
+    fade: f32,
+    volume_control: Arc<VolumeControl>,
+    duration: f64,
+    clock: Arc<PlaybackClock>,
+    decode_errors: Arc<AtomicUsize>,
+    stream_title: Arc<Mutex<Option<String>>>,
+    // A–B loop points in the current track, in seconds
+    loop_a: Option<f64>,
+    loop_b: Option<f64>,
+    chapters: Arc<[Chapter]>,
+    gain: Option<Arc<TrackGain>>,
+    replaygain: ReplayGainSettings,
+    eq: Arc<EqShared>,
+    eq_presets: EqPresets,
+    channels: Arc<ChannelShared>,
+    silence: Arc<SilenceShared>,
+    speed: Arc<SpeedControl>,
+    queue: Arc<QueueShared>,
+    queued: Option<QueuedTrack>,
+    tap: Arc<SampleTap>,
+    event_tx: mpsc::UnboundedSender<PlayerEvent>,
+    snapshot: watch::Sender<PlayerSnapshot>,
+}
+
+impl AudioEngine {
+    fn new(
+        spec: &BackendSpec,
+        eq_presets: EqPresets,
+        event_tx: mpsc::UnboundedSender<PlayerEvent>,
+        tap: Arc<SampleTap>,
+        silence: Arc<SilenceShared>,
+    ) -> Result<Self> {
+        let mut backend = match output::open(spec) {
+            Ok(backend) => backend,
+            Err(e) if matches!(spec, BackendSpec::Device(Some(_))) => {
+                error!("{:#}, using the default device", e);
+                output::open(&BackendSpec::Device(None))?
+            }
+            Err(e) => return Err(e),
         };
 
-        *self.duration.lock().unwrap() = duration;
+        let sink = backend.create_sink()?;
 
-        // Clear current sink and create new one
-        let sink = self.sink.lock().unwrap();
-        sink.stop();
+        let eq = Arc::new(EqShared::default());
+        let clock = Arc::new(PlaybackClock::default());
+        let (snapshot, _) = watch::channel(PlayerSnapshot {
+            state: PlaybackState::Stopped,
+            current_track: None,
+            duration: 0.0,
+            volume: DEFAULT_VOLUME,
+            muted: false,
+            volume_boost: false,
+            speed: 1.0,
+            crossfade: CrossfadeSettings::default(),
+            replaygain: ReplayGainSettings::default(),
+            eq: eq.status(&eq_presets),
+            channels: ChannelSettings::default(),
+            silence: silence.settings(),
+            output_device: backend.device(),
+            loop_a: None,
+            loop_b: None,
+            chapters: Arc::from([]),
+            clock: Arc::clone(&clock),
+            decode_errors: Arc::default(),
+            stream_title: Arc::default(),
+        });
 
-        // Load new track
-        sink.append(source);
-        sink.play(); // Ensure playback starts
+        Ok(Self {
+            sink,
+            backend,
+            current_track: None,
+            state: PlaybackState::Stopped,
+            volume: DEFAULT_VOLUME,
+            muted: false,
+            volume_boost: false,
+            fade: 1.0,
+            volume_control: Arc::new(VolumeControl::new(volume::level_to_gain(DEFAULT_VOLUME))),
+            duration: 0.0,
+            clock,
+            decode_errors: Arc::default(),
+            stream_title: Arc::default(),
+            loop_a: None,
+            loop_b: None,
+            chapters: Arc::from([]),
+            gain: None,
+            replaygain: ReplayGainSettings::default(),
+            eq,
+            eq_presets,
+            channels: Arc::new(ChannelShared::default()),
+            silence,
+            speed: Arc::new(SpeedControl::default()),
+            queue: Arc::new(QueueShared::new(event_tx.clone())),
+            queued: None,
+            tap,
+            event_tx,
+            snapshot,
+        })
+    }
 
-        *self.start_time.lock().unwrap() = Some(std::time::Instant::now());
-        *self.paused_position.lock().unwrap() = 0.0;
+    /// Runs commands until every `Player` handle is gone.
+    fn run(mut self, jobs: std::sync::mpsc::Receiver<Job>) {
+        while let Ok(job) = jobs.recv() {
+            job(&mut self);
+        }
+    }
+
+    fn publish(&self) {
+        self.snapshot.send_replace(PlayerSnapshot {
+            state: self.state.clone(),
+            current_track: self.current_track.clone(),
+            duration: self.duration,
+            volume: self.volume,
+            muted: self.muted,
+            volume_boost: self.volume_boost,
+            speed: self.speed.get(),
+            crossfade: self.queue.crossfade(),
+            replaygain: self.replaygain,
+            eq: self.eq.status(&self.eq_presets),
+            channels: self.channels.settings(),
+            silence: self.silence.settings(),
+            output_device: self.backend.device(),
+            loop_a: self.loop_a,
+            loop_b: self.loop_b,
+            chapters: Arc::clone(&self.chapters),
+            clock: Arc::clone(&self.clock),
+            decode_errors: Arc::clone(&self.decode_errors),
+            stream_title: Arc::clone(&self.stream_title),
+        });
+    }
 
-        *self.current_track.lock().unwrap() = Some(path.clone());
-        *self.state.lock().unwrap() = PlaybackState::Playing;
+    fn emit(&self, event: PlayerEvent) {
+        let _ = self.event_tx.send(event);
+    }
 
-        let _ = self.event_tx.send(PlayerEvent::TrackChanged(()));
-        let _ = self.event_tx.send(PlayerEvent::StateChanged(()));
+    fn track_started(&mut self, track: &QueuedTrack) {
+        // Loop points belong to the track they were set in
+        self.loop_a = None;
+        self.loop_b = None;
+        self.queue.set_loop(None);
+        self.chapters = Arc::clone(&track.chapters);
 
-        Ok(())
+        self.emit(PlayerEvent::TrackStarted {
+            path: track.path.clone(),
+            metadata: track.metadata.clone(),
+            duration: track.duration,
+        });
+    }
+
+    /// Records the new state, reporting it if it changed
+    fn set_state(&mut self, state: PlaybackState) {
+        if self.state != state {
+            self.state = state.clone();
+            self.emit(PlayerEvent::StateChanged(state));
+        }
+    }
+
+    fn position(&self) -> f64 {
+        self.clock.position().as_secs_f64()
+    }
+
+    fn load_track(&mut self, prepared: PreparedTrack) {
+        let PreparedTrack { source, track } = prepared;
+        self.track_started(&track);
+
+        self.duration = track.duration;
+
+        let crossfade = self.queue.crossfade();
+        let playing = self.state == PlaybackState::Playing;
+
+        if crossfade.fade_on_skip && playing && !self.sink.empty() {
+            // Let the running queue fade over instead of cutting the sink.
+            // The track queued after the old one doesn't follow this one.
+            self.queue.next_slot().take();
+            self.queued = None;
+            self.queue.skip_to(source);
+        } else {
+            // Clear current sink and create new one
+            self.sink.stop();
+            self.queue.next_slot().take();
+            self.queue.cancel_skip();
+            self.queued = None;
+
+            // Load new track
+            self.append_chain(&self.sink, source);
+            self.sink.play(); // Ensure playback starts
+        }
+
+        self.clock = track.clock;
+        self.decode_errors = track.decode_errors;
+        self.stream_title = track.stream_title;
+        self.gain = Some(track.gain);
+
+        self.current_track = Some(track.path);
+        self.set_state(PlaybackState::Playing);
     }
 
-    pub fn play(&self) -> Result<()> {
-        let sink = self.sink.lock().unwrap();
+    /// Feeds `source` to `sink` through the track queue and effect stages.
+    fn append_chain(&self, sink: &Sink, source: QueuedSource) {
+        let queue = TrackQueue::new(source, Arc::clone(&self.queue));
+        let equalizer = Equalizer::new(queue, Arc::clone(&self.eq));
+        let stretched = TimeStretch::new(equalizer, Arc::clone(&self.speed));
+        let mixed = ChannelMix::new(stretched, Arc::clone(&self.channels));
+        let tapped = Tap::new(mixed, Arc::clone(&self.tap));
+        sink.append(Volume::new(tapped, Arc::clone(&self.volume_control)));
+    }
+
+    fn queue_next(&mut self, prepared: PreparedTrack) {
+        let PreparedTrack { source, track } = prepared;
+
+        let mut slot = self.queue.next_slot();
+
+        // Already taken by the output; wait for `poll_transition`
+        if self.queued.is_some() && slot.is_none() {
+            return;
+        }
 
+        *slot = Some(source);
+        self.queued = Some(track);
+    }
+
+    fn clear_next(&mut self) {
+        if self.queue.next_slot().take().is_some() {
+            self.queued = None;
+        }
+    }
+
+    fn poll_transition(&mut self) -> Option<String> {
+        if !self.queued.as_ref()?.clock.has_started() {
+            return None;
+        }
+        let track = self.queued.take()?;
+        self.track_started(&track);
+
+        self.duration = track.duration;
+        self.clock = track.clock;
+        self.decode_errors = track.decode_errors;
+        self.stream_title = track.stream_title;
+        self.gain = Some(track.gain);
+        self.current_track = Some(track.path.clone());
+
+        Some(track.path)
+    }
+
+    fn play(&mut self) {
         // Always try to play if there's a current track
-        if self.current_track.lock().unwrap().is_some() {
-            sink.play();
-            *self.start_time.lock().unwrap() = Some(std::time::Instant::now());
-            *self.state.lock().unwrap() = PlaybackState::Playing;
-            let _ = self.event_tx.send(PlayerEvent::StateChanged(()));
+        if self.current_track.is_some() {
+            self.sink.play();
+            self.set_state(PlaybackState::Playing);
         }
+    }
 
-        Ok(())
+    fn pause(&mut self) {
+        if !self.sink.is_paused() {
+            self.sink.pause();
+            self.set_state(PlaybackState::Paused);
+        }
+    }
+
+    fn stop(&mut self) {
+        self.sink.stop();
+        self.queue.next_slot().take();
+        self.queue.cancel_skip();
+        self.queued = None;
+
+        self.clock = Arc::new(PlaybackClock::default());
+        self.decode_errors = Arc::default();
+        self.stream_title = Arc::default();
+        self.gain = None;
+
+        self.current_track = None;
+        self.chapters = Arc::from([]);
+        self.loop_a = None;
+        self.loop_b = None;
+        self.queue.set_loop(None);
+        self.set_state(PlaybackState::Stopped);
     }
 
-    pub fn pause(&self) -> Result<()> {
-        let sink = self.sink.lock().unwrap();
-        
-        if !sink.is_paused() {
-            sink.pause();
-            let elapsed = self.start_time.lock().unwrap().take().map(|t| t.elapsed().as_secs_f64()).unwrap_or(0.0);
-            *self.paused_position.lock().unwrap() += elapsed;
-            *self.state.lock().unwrap() = PlaybackState::Paused;
-            let _ = self.event_tx.send(PlayerEvent::StateChanged(()));
+    fn seek(&mut self, position: f64) -> Result<()> {
+        if self.current_track.is_none() {
+            return Err(anyhow::anyhow!("No track loaded"));
         }
-        
+
+        let mut position = position.max(0.0);
+        if self.duration > 0.0 {
+            position = position.min(self.duration);
+        }
+
+        // The clocked source resets the position once the seek has happened
+        self.sink.try_seek(Duration::from_secs_f64(position))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+        self.emit(PlayerEvent::Seeked { position });
         Ok(())
     }
 
-    pub fn stop(&self) -> Result<()> {
-        let sink = self.sink.lock().unwrap();
-        sink.stop();
+    fn next_chapter(&mut self) -> Result<()> {
+        if self.chapters.is_empty() {
+            return Err(anyhow::anyhow!("Track has no chapters"));
+        }
+        let next = chapter_at(&self.chapters, self.position()).map_or(0, |current| current + 1);
+        let start = self.chapters.get(next).context("Already in the last chapter")?.start;
+        self.seek(start)
+    }
+
+    fn prev_chapter(&mut self) -> Result<()> {
+        if self.chapters.is_empty() {
+            return Err(anyhow::anyhow!("Track has no chapters"));
+        }
+        let position = self.position();
+        let start = match chapter_at(&self.chapters, position) {
+            Some(current) if position - self.chapters[current].start > CHAPTER_RESTART_SECONDS => self.chapters[current].start,
+            Some(current) if current > 0 => self.chapters[current - 1].start,
+            // Before the first chapter, or in it: back to the beginning
+            _ => 0.0,
+        };
+        self.seek(start)
+    }
 
-        *self.start_time.lock().unwrap() = None;
-        *self.paused_position.lock().unwrap() = 0.0;
+    /// A loop point at `position`, or at the current position
+    fn loop_point(&self, position: Option<f64>) -> Result<f64> {
+        if self.current_track.is_none() {
+            return Err(anyhow::anyhow!("No track loaded"));
+        }
 
-        *self.current_track.lock().unwrap() = None;
-        *self.state.lock().unwrap() = PlaybackState::Stopped;
+        let position = position.unwrap_or_else(|| self.position()).max(0.0);
+        if self.duration > 0.0 {
+            Ok(position.min(self.duration))
+        } else {
+            Ok(position)
+        }
+    }
 
-        let _ = self.event_tx.send(PlayerEvent::StateChanged(()));
-        
-        Ok(())
+    fn set_loop_a(&mut self, position: Option<f64>, loader: TrackLoader) -> Result<()> {
+        let a = self.loop_point(position)?;
+        self.loop_a = Some(a);
+        if self.loop_b.is_some_and(|b| b <= a) {
+            self.loop_b = None;
+        }
+        self.update_loop(loader)
     }
 
-    pub fn set_volume(&self, level: u8) -> Result<()> {
-        let level = level.min(100);
-        let volume = level as f32 / 100.0;
-        
-        let sink = self.sink.lock().unwrap();
-        sink.set_volume(volume);
-        
-        *self.volume.lock().unwrap() = level;
-        
+    fn set_loop_b(&mut self, position: Option<f64>, loader: TrackLoader) -> Result<()> {
+        let b = self.loop_point(position)?;
+        let Some(a) = self.loop_a else {
+            return Err(anyhow::anyhow!("Set the loop start (A) first"));
+        };
+        if b <= a {
+            return Err(anyhow::anyhow!("The loop end (B) has to come after A"));
+        }
+        self.loop_b = Some(b);
+        self.update_loop(loader)
+    }
+
+    fn clear_loop(&mut self) {
+        self.loop_a = None;
+        self.loop_b = None;
+        self.queue.set_loop(None);
+    }
+
+    /// Hands the loop to the queue once both ends are set, along with a way
+    /// to open the track again at A. Live streams can't seek, so don't loop.
+    fn update_loop(&self, loader: TrackLoader) -> Result<()> {
+        let (Some(a), Some(b), Some(path), Some(gain)) =
+            (self.loop_a, self.loop_b, self.current_track.clone(), self.gain.clone())
+        else {
+            self.queue.set_loop(None);
+            return Ok(());
+        };
+        if stream::is_url(&path) {
+            self.queue.set_loop(None);
+            return Ok(());
+        }
+
+        let start = Duration::from_secs_f64(a);
+        let clock = Arc::clone(&self.clock);
+        let open = move || match loader.open_at(&path, start, Arc::clone(&clock), Some(Arc::clone(&gain))) {
+            Ok(prepared) => Some(prepared.source.source),
+            Err(e) => {
+                error!("Failed to open {} again for the A–B loop: {:#}", path, e);
+                None
+            }
+        };
+        let ab_loop = AbLoop::new(start, Duration::from_secs_f64(b), Box::new(open))
+            .context("Failed to start the loop thread")?;
+        self.queue.set_loop(Some(ab_loop));
         Ok(())
     }
 
-    pub fn get_volume(&self) -> u8 {
-        *self.volume.lock().unwrap()
+    fn set_volume(&mut self, level: u8) {
+        let max = if self.volume_boost { MAX_VOLUME } else { UNITY_VOLUME };
+        self.update_volume(level.min(max), false);
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.sink.lock().unwrap().empty()
+    fn toggle_mute(&mut self) {
+        self.update_volume(self.volume, !self.muted);
     }
 
-    pub fn get_state(&self) -> PlaybackState {
-        self.state.lock().unwrap().clone()
+    fn set_volume_boost(&mut self, enabled: bool) {
+        self.volume_boost = enabled;
+        if !enabled && self.volume > UNITY_VOLUME {
+            self.update_volume(UNITY_VOLUME, self.muted);
+        }
     }
 
-    pub fn get_current_track(&self) -> Option<String> {
-        self.current_track.lock().unwrap().clone()
+    /// Scales the output by `gain` on top of the volume
+    fn set_fade(&mut self, gain: f32) {
+        self.fade = gain.clamp(0.0, 1.0);
+        self.update_volume(self.volume, self.muted);
     }
 
-    pub fn get_duration(&self) -> f64 {
-        *self.duration.lock().unwrap()
+    /// Applies a new level and mute state, reporting it if it changed. The
+    /// level is kept while muted, so unmuting restores it.
+    fn update_volume(&mut self, level: u8, muted: bool) {
+        let gain = if muted { 0.0 } else { volume::level_to_gain(level) };
+        self.volume_control.set(gain * self.fade);
+
+        if self.volume != level || self.muted != muted {
+            self.volume = level;
+            self.muted = muted;
+            self.emit(PlayerEvent::VolumeChanged { level, muted });
+        }
     }
 
-    pub fn get_position(&self) -> f64 {
-        let paused = *self.paused_position.lock().unwrap();
-        let elapsed = self.start_time.lock().unwrap().as_ref().map(|t| t.elapsed().as_secs_f64()).unwrap_or(0.0);
-        paused + elapsed
+    fn set_output_device(&mut self, name: Option<&str>, current: Option<PreparedTrack>) -> Result<()> {
+        let mut backend = output::open(&BackendSpec::Device(name.map(str::to_string)))?;
+        let sink = backend.create_sink()?;
+
+        if let Some(PreparedTrack { mut source, track }) = current {
+            // Streams carry on from where the station is now
+            let position = self.position();
+            if position > 0.0 && !stream::is_url(&track.path) {
+                source.source.try_seek(Duration::from_secs_f64(position))
+                    .map_err(|e| anyhow::anyhow!("{}", e))?;
+            }
+
+            self.queue.next_slot().take();
+            self.queue.cancel_skip();
+            self.queued = None;
+
+            if self.state != PlaybackState::Playing {
+                sink.pause();
+            }
+            self.append_chain(&sink, source);
+
+            // Errors already met in the track still count
+            track.decode_errors.fetch_add(self.decode_errors.load(Ordering::Relaxed), Ordering::Relaxed);
+            self.clock = track.clock;
+            self.decode_errors = track.decode_errors;
+            self.stream_title = track.stream_title;
+            self.gain = Some(track.gain);
+        }
+
+        self.sink.stop();
+        self.sink = sink;
+        self.backend = backend;
+
+        Ok(())
     }
-}
 
-impl Clone for Player {
-    fn clone(&self) -> Self {
-        Self {
-            sink: Arc::clone(&self.sink),
-            _stream: OutputStream::try_default().unwrap().0,
-            _stream_handle: OutputStream::try_default().unwrap().1,
-            current_track: Arc::clone(&self.current_track),
-            state: Arc::clone(&self.state),
-            volume: Arc::clone(&self.volume),
-            duration: Arc::clone(&self.duration),
-            start_time: Arc::new(Mutex::new(None)),
-            paused_position: Arc::new(Mutex::new(0.0)),
-            event_tx: self.event_tx.clone(),
+    fn set_crossfade(&mut self, seconds: f64, curve: Option<CrossfadeCurve>, fade_on_skip: Option<bool>) {
+        let current = self.queue.crossfade();
+        self.queue.set_crossfade(CrossfadeSettings {
+            seconds: seconds.clamp(0.0, MAX_CROSSFADE_SECONDS),
+            curve: curve.unwrap_or(current.curve),
+            fade_on_skip: fade_on_skip.unwrap_or(current.fade_on_skip),
+        });
+    }
+
+    fn set_replaygain(&mut self, mode: ReplayGainMode, preamp: Option<f32>, prevent_clipping: Option<bool>) {
+        let settings = &mut self.replaygain;
+        settings.mode = mode;
+        if let Some(preamp) = preamp {
+            settings.preamp = preamp.clamp(-MAX_PREAMP_DB, MAX_PREAMP_DB);
+        }
+        if let Some(prevent_clipping) = prevent_clipping {
+            settings.prevent_clipping = prevent_clipping;
+        }
+
+        if let Some(gain) = &self.gain {
+            gain.update(&self.replaygain);
         }
+        if let Some(track) = &self.queued {
+            track.gain.update(&self.replaygain);
+        }
+    }
+
+    fn set_eq_preset(&mut self, name: String) -> Result<()> {
+        let gains = self.eq_presets.get(&name)
+            .context(format!("Unknown equalizer preset: {}", name))?;
+        self.eq.update(|eq| {
+            eq.gains = gains;
+            eq.preset = Some(name);
+        });
+        Ok(())
+    }
+
+    fn save_eq_preset(&mut self, name: String) -> Result<()> {
+        let gains = self.eq.settings().gains;
+        self.eq_presets.save(&name, &gains)?;
+        self.eq.update(|eq| eq.preset = Some(name));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    /// Two seconds of 44.1 kHz stereo counted into `clock`
+    fn clocked(clock: &Arc<PlaybackClock>) -> ClockedSource<SamplesBuffer<i16>> {
+        ClockedSource::new(SamplesBuffer::new(2, 44100, vec![0; 44100 * 2 * 2]), Arc::clone(clock))
+    }
+
+    #[test]
+    fn position_follows_the_samples_pulled() {
+        let clock = Arc::new(PlaybackClock::default());
+        let mut source = clocked(&clock);
+        assert!(!clock.has_started());
+        assert_eq!(clock.samples_until(Duration::from_secs(1)), None);
+
+        // Half a second of stereo, however long pulling it took
+        source.by_ref().take(44100).for_each(drop);
+        assert!(clock.has_started());
+        assert_eq!(clock.position(), Duration::from_millis(500));
+        assert_eq!(clock.samples_until(Duration::from_secs(1)), Some(44100));
+    }
+
+    #[test]
+    fn seeking_counts_on_from_the_new_position() {
+        let clock = Arc::new(PlaybackClock::default());
+        let mut source = clocked(&clock);
+        source.by_ref().take(4410).for_each(drop);
+
+        source.try_seek(Duration::from_millis(1500)).unwrap();
+        assert_eq!(clock.position(), Duration::from_millis(1500));
+        source.by_ref().take(4410).for_each(drop);
+        assert_eq!(clock.position(), Duration::from_millis(1550));
+
+        // Past the end stops at the end
+        source.try_seek(Duration::from_secs(5)).unwrap();
+        assert_eq!(clock.position(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn format_changes_keep_the_position() {
+        let clock = PlaybackClock::default();
+        clock.set_spec(44100, 2);
+        (0..44100).for_each(|_| clock.advance());
+        // Like a stream going from stereo 44.1 kHz to mono 22.05 kHz
+        clock.set_spec(22050, 1);
+        assert_eq!(clock.position(), Duration::from_millis(500));
+        (0..11025).for_each(|_| clock.advance());
+        assert_eq!(clock.position(), Duration::from_secs(1));
+
+        // Skipped silence moves it on without samples
+        clock.skip(Duration::from_millis(250));
+        assert_eq!(clock.position(), Duration::from_millis(1250));
     }
 }
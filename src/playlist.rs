@@ -1,8 +1,13 @@
 use anyhow::Result;
+use std::collections::HashSet;
 use std::path::Path;
+use tracing::warn;
 use walkdir::WalkDir;
 
-const AUDIO_EXTENSIONS: &[&str] = &[
+use crate::cue;
+use crate::stream;
+
+pub const AUDIO_EXTENSIONS: &[&str] = &[
     "mp3", "flac", "wav", "ogg", "opus", "m4a", "aac", "wma", "ape", "aiff"
 ];
 
@@ -22,42 +27,14 @@ impl Playlist {
         }
     }
 
-    pub fn add_track(&mut self, path: String) -> Result<()> {
-        let path_obj = Path::new(&path);
-        
-        if path_obj.is_file() {
-            if is_audio_file(&path) {
-                self.tracks.push(path);
-            }
-        } else if path_obj.is_dir() {
-            // Recursively add all audio files from directory
-            for entry in WalkDir::new(path_obj).follow_links(true) {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if path.is_file() {
-                        if let Some(path_str) = path.to_str() {
-                            if is_audio_file(path_str) {
-                                self.tracks.push(path_str.to_string());
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    /// Adds the entries `collect_tracks` found for one path.
+    pub fn add_entries(&mut self, entries: Vec<String>) {
+        self.tracks.extend(entries);
         
         // If this is the first track, set it as current
         if self.tracks.len() == 1 {
             self.current_index = Some(0);
         }
-        
-        Ok(())
-    }
-
-    pub fn add_tracks(&mut self, paths: Vec<String>) -> Result<()> {
-        for path in paths {
-            self.add_track(path)?;
-        }
-        Ok(())
     }
 
 
@@ -88,6 +65,12 @@ impl Playlist {
         next_index.map(|i| self.tracks[i].clone())
     }
 
+    /// The track `next()` would return, without moving to it
+    pub fn peek_next(&self) -> Option<String> {
+        let next_index = self.current_index.map_or(0, |i| i + 1);
+        self.tracks.get(next_index).cloned()
+    }
+
     pub fn previous(&mut self) -> Option<String> {
         if self.tracks.is_empty() {
             return None;
@@ -133,6 +116,99 @@ impl Playlist {
 
 
 
+}
+
+/// The audio files at `path`: the file itself, or every audio file found
+/// recursively below a directory.
+pub fn collect_audio_files(path: &str) -> Vec<String> {
+    let path_obj = Path::new(path);
+    let mut files = Vec::new();
+
+    if path_obj.is_file() {
+        if is_audio_file(path) {
+            files.push(path.to_string());
+        }
+    } else if path_obj.is_dir() {
+        // Recursively add all audio files from directory
+        for entry in WalkDir::new(path_obj).follow_links(true).into_iter().flatten() {
+            let path = entry.path();
+            if path.is_file() {
+                if let Some(path_str) = path.to_str() {
+                    if is_audio_file(path_str) {
+                        files.push(path_str.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    files
+}
+
+/// The playlist entries for `path`. Like `collect_audio_files`, but albums
+/// with a cue sheet come as one entry per track, and files a `.cue` next to
+/// them already covers are left out. Stream URLs are entries as they are,
+/// if they can be played. Reads through directories and probes FLAC files,
+/// so it belongs on a blocking thread.
+pub fn collect_tracks(path: &str) -> Result<Vec<String>> {
+    if stream::is_url(path) {
+        stream::check_url(path)?;
+        return Ok(vec![path.to_string()]);
+    }
+    let path_obj = Path::new(path);
+    let files: Vec<String> = if path_obj.is_dir() {
+        WalkDir::new(path_obj)
+            .follow_links(true)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.path().to_str().map(str::to_string))
+            .collect()
+    } else if path_obj.is_file() {
+        vec![path.to_string()]
+    } else if cue::resolve(path).is_some() {
+        // A single track of a cue sheet, as saved from a playlist
+        return Ok(vec![path.to_string()]);
+    } else {
+        Vec::new()
+    };
+
+    let mut sheets = Vec::new();
+    let mut covered = HashSet::new();
+    for file in files.iter().filter(|file| is_cue_sheet(file)) {
+        match cue::read_sheet(Path::new(file)) {
+            Ok(tracks) => {
+                covered.extend(tracks.iter().map(|track| track.file.clone()));
+                sheets.push((file, tracks));
+            }
+            Err(e) => warn!("Skipping cue sheet: {:#}", e),
+        }
+    }
+
+    let mut entries = Vec::new();
+    for file in &files {
+        if let Some((sheet, tracks)) = sheets.iter().find(|(sheet, _)| *sheet == file) {
+            entries.extend(tracks.iter().map(|track| cue::track_path(sheet, track.number)));
+        } else if is_audio_file(file) && !covered.contains(file) {
+            let embedded = if has_extension(file, "flac") { cue::read_embedded(file) } else { None };
+            match embedded {
+                Some(tracks) => entries.extend(tracks.iter().map(|track| cue::track_path(file, track.number))),
+                None => entries.push(file.clone()),
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn is_cue_sheet(path: &str) -> bool {
+    has_extension(path, "cue")
+}
+
+fn has_extension(path: &str, extension: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
 }
 
 fn is_audio_file(path: &str) -> bool {
@@ -0,0 +1,337 @@
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+use crate::ipc::ReplayGainMode;
+
+/// ReplayGain values of one file, gains in dB and peaks as linear amplitude.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplayGainInfo {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGainInfo {
+    pub fn has_gain(&self) -> bool {
+        self.track_gain.is_some() || self.album_gain.is_some()
+    }
+
+    fn apply_tag(&mut self, tag: &Tag) {
+        // Taggers disagree on case, and MP4 freeform keys carry a namespace
+        // ("com.apple.iTunes:replaygain_track_gain") that symphonia doesn't map.
+        let key = tag.key.rsplit(':').next().unwrap_or(&tag.key).to_lowercase();
+        let slot = match (tag.std_key, key.as_str()) {
+            (Some(StandardTagKey::ReplayGainTrackGain), _) | (_, "replaygain_track_gain") => &mut self.track_gain,
+            (Some(StandardTagKey::ReplayGainTrackPeak), _) | (_, "replaygain_track_peak") => &mut self.track_peak,
+            (Some(StandardTagKey::ReplayGainAlbumGain), _) | (_, "replaygain_album_gain") => &mut self.album_gain,
+            (Some(StandardTagKey::ReplayGainAlbumPeak), _) | (_, "replaygain_album_peak") => &mut self.album_peak,
+            _ => return,
+        };
+        if slot.is_none() {
+            *slot = parse_number(&tag.value.to_string());
+        }
+    }
+}
+
+// Values look like "-6.54 dB" or "0.988831"
+fn parse_number(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.')))
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+/// Reads ReplayGain tags from ID3v2 TXXX frames, Vorbis comments or MP4
+/// freeform atoms. Files without tags, or that can't be probed, yield an
+/// empty `ReplayGainInfo`.
+pub fn read_tags(path: &str) -> ReplayGainInfo {
+    let mut info = ReplayGainInfo::default();
+
+    let Ok(file) = File::open(path) else {
+        return info;
+    };
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(ext) = Path::new(path).extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+
+    let Ok(mut probed) = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) else {
+        return info;
+    };
+
+    // Tags in front of the container (ID3v2) end up with the probe, the
+    // rest with the format reader.
+    if let Some(metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.current() {
+            revision.tags().iter().for_each(|tag| info.apply_tag(tag));
+        }
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        revision.tags().iter().for_each(|tag| info.apply_tag(tag));
+    }
+
+    info
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayGainSettings {
+    pub mode: ReplayGainMode,
+    /// Added to the tagged gain, in dB
+    pub preamp: f32,
+    /// Lower the gain so the tagged peak never exceeds full scale
+    pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainSettings {
+    fn default() -> Self {
+        Self {
+            mode: ReplayGainMode::Off,
+            preamp: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+impl ReplayGainSettings {
+    /// Linear amplification for a track with the given tags
+    pub fn factor(&self, info: &ReplayGainInfo) -> f32 {
+        let track = (info.track_gain, info.track_peak);
+        let album = (info.album_gain, info.album_peak);
+
+        let (gain, peak) = match self.mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => track,
+            ReplayGainMode::Album => album,
+            ReplayGainMode::Auto if info.album_gain.is_some() => album,
+            ReplayGainMode::Auto => track,
+        };
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+
+        let mut factor = 10f32.powf((gain + self.preamp) / 20.0);
+        if self.prevent_clipping {
+            if let Some(peak) = peak.filter(|p| *p > 0.0) {
+                factor = factor.min(1.0 / peak);
+            }
+        }
+        factor
+    }
+}
+
+/// The gain applied to one track. Kept behind an `Arc` so a mode change can
+/// update tracks that are already playing or queued.
+#[derive(Debug)]
+pub struct TrackGain {
+    pub info: ReplayGainInfo,
+    factor: AtomicU32,
+}
+
+impl TrackGain {
+    pub fn new(info: ReplayGainInfo, settings: &ReplayGainSettings) -> Self {
+        Self {
+            info,
+            factor: AtomicU32::new(settings.factor(&info).to_bits()),
+        }
+    }
+
+    pub fn update(&self, settings: &ReplayGainSettings) {
+        self.factor.store(settings.factor(&self.info).to_bits(), Ordering::Relaxed);
+    }
+
+    fn factor(&self) -> f32 {
+        f32::from_bits(self.factor.load(Ordering::Relaxed))
+    }
+}
+
+/// Per-track amplification stage driven by a `TrackGain`.
+pub struct ReplayGainSource<S> {
+    inner: S,
+    gain: Arc<TrackGain>,
+}
+
+impl<S> ReplayGainSource<S> {
+    pub fn new(inner: S, gain: Arc<TrackGain>) -> Self {
+        Self { inner, gain }
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for ReplayGainSource<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.inner.next()?;
+        let amplified = sample as f32 * self.gain.factor();
+        Some(amplified.clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: Source<Item = i16>> Source for ReplayGainSource<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::meta::Value;
+
+    fn info(tags: &[Tag]) -> ReplayGainInfo {
+        let mut info = ReplayGainInfo::default();
+        tags.iter().for_each(|tag| info.apply_tag(tag));
+        info
+    }
+
+    fn tag(std_key: Option<StandardTagKey>, key: &str, value: &str) -> Tag {
+        Tag::new(std_key, key, Value::from(value))
+    }
+
+    #[test]
+    fn gains_are_read_from_each_kind_of_tag() {
+        // ID3v2 TXXX frames, as symphonia names them
+        let id3 = info(&[
+            tag(None, "TXXX:REPLAYGAIN_TRACK_GAIN", "-6.54 dB"),
+            tag(None, "TXXX:replaygain_track_peak", "0.988831"),
+        ]);
+        assert_eq!((id3.track_gain, id3.track_peak), (Some(-6.54), Some(0.988831)));
+
+        // Vorbis comments, mapped to standard keys
+        let vorbis = info(&[
+            tag(Some(StandardTagKey::ReplayGainAlbumGain), "REPLAYGAIN_ALBUM_GAIN", "+2.10 dB"),
+            tag(Some(StandardTagKey::ReplayGainAlbumPeak), "REPLAYGAIN_ALBUM_PEAK", "1.000000"),
+        ]);
+        assert_eq!((vorbis.album_gain, vorbis.album_peak), (Some(2.1), Some(1.0)));
+        assert_eq!(vorbis.track_gain, None);
+
+        // MP4 freeform atoms
+        let mp4 = info(&[tag(None, "----:com.apple.iTunes:replaygain_track_gain", "-3.00 dB")]);
+        assert_eq!(mp4.track_gain, Some(-3.0));
+        assert!(mp4.has_gain());
+    }
+
+    #[test]
+    fn the_first_of_repeated_tags_wins_and_junk_is_ignored() {
+        let info = info(&[
+            tag(None, "TXXX:REPLAYGAIN_TRACK_GAIN", "-1.00 dB"),
+            tag(None, "REPLAYGAIN_TRACK_GAIN", "-9.00 dB"),
+            tag(None, "REPLAYGAIN_ALBUM_GAIN", "loud"),
+            tag(None, "COMMENT", "-4 dB"),
+        ]);
+        assert_eq!(info.track_gain, Some(-1.0));
+        assert_eq!(info.album_gain, None);
+    }
+
+    #[test]
+    fn txxx_frames_in_an_mp3_file_are_found() {
+        let mut frames = Vec::new();
+        for (description, value) in [("REPLAYGAIN_TRACK_GAIN", "-7.25 dB"), ("REPLAYGAIN_TRACK_PEAK", "0.5")] {
+            let mut body = vec![0];
+            body.extend(description.as_bytes());
+            body.push(0);
+            body.extend(value.as_bytes());
+            frames.extend(b"TXXX");
+            frames.extend((body.len() as u32).to_be_bytes());
+            frames.extend([0, 0]);
+            frames.extend(body);
+        }
+        let mut file = b"ID3\x03\x00\x00\x00\x00".to_vec();
+        file.extend([(frames.len() >> 7) as u8, (frames.len() & 0x7F) as u8]);
+        file.extend(frames);
+        // A few silent MPEG-1 Layer III frames at 128 kbps
+        for _ in 0..4 {
+            let mut frame = vec![0; 417];
+            frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+            file.extend(frame);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tagged.mp3");
+        std::fs::write(&path, file).unwrap();
+        let info = read_tags(path.to_str().unwrap());
+        assert_eq!((info.track_gain, info.track_peak), (Some(-7.25), Some(0.5)));
+    }
+
+    fn settings(mode: ReplayGainMode, preamp: f32, prevent_clipping: bool) -> ReplayGainSettings {
+        ReplayGainSettings { mode, preamp, prevent_clipping }
+    }
+
+    fn assert_db(factor: f32, db: f32) {
+        let measured = 20.0 * factor.log10();
+        assert!((measured - db).abs() < 0.01, "{} dB instead of {} dB", measured, db);
+    }
+
+    #[test]
+    fn mode_picks_track_or_album_gain() {
+        let both = ReplayGainInfo {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.5),
+            album_gain: Some(-3.0),
+            album_peak: Some(0.5),
+        };
+        let track_only = ReplayGainInfo { album_gain: None, album_peak: None, ..both };
+
+        assert_eq!(settings(ReplayGainMode::Off, 0.0, true).factor(&both), 1.0);
+        assert_db(settings(ReplayGainMode::Track, 0.0, true).factor(&both), -6.0);
+        assert_db(settings(ReplayGainMode::Album, 0.0, true).factor(&both), -3.0);
+        assert_db(settings(ReplayGainMode::Auto, 0.0, true).factor(&both), -3.0);
+        assert_db(settings(ReplayGainMode::Auto, 0.0, true).factor(&track_only), -6.0);
+        // Nothing to go by
+        assert_eq!(settings(ReplayGainMode::Album, 0.0, true).factor(&track_only), 1.0);
+        assert_eq!(settings(ReplayGainMode::Track, 0.0, true).factor(&ReplayGainInfo::default()), 1.0);
+    }
+
+    #[test]
+    fn preamp_is_added_and_clipping_prevented_by_the_peak() {
+        let info = ReplayGainInfo {
+            track_gain: Some(-2.0),
+            track_peak: Some(0.5),
+            ..Default::default()
+        };
+        assert_db(settings(ReplayGainMode::Track, 4.0, false).factor(&info), 2.0);
+        assert_db(settings(ReplayGainMode::Track, 4.0, true).factor(&info), 2.0);
+        // 0.5 may go up by 6 dB at most
+        assert_db(settings(ReplayGainMode::Track, 10.0, false).factor(&info), 8.0);
+        assert_eq!(settings(ReplayGainMode::Track, 10.0, true).factor(&info), 2.0);
+    }
+}
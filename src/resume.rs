@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Positions this close to the start aren't worth coming back to, and this
+// close to the end the track counts as finished
+const START_MARGIN: f64 = 15.0;
+const END_MARGIN: f64 = 30.0;
+
+// Tracks remembered at most; the ones left longest ago go first
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ResumePoint {
+    position: f64,
+    /// When it was saved, in seconds since the epoch
+    saved: u64,
+}
+
+/// Where long tracks were left off, stored as JSON in the data directory.
+/// Changes are kept in memory at once; writing them out is up to the
+/// caller, with the `PendingSave` they return.
+#[derive(Debug, Default)]
+pub struct ResumeStore {
+    file: Option<PathBuf>,
+    entries: HashMap<String, ResumePoint>,
+    pending: Arc<Pending>,
+}
+
+/// The newest positions not written yet, shared by a store and its saves
+#[derive(Debug, Default)]
+struct Pending {
+    json: Mutex<Option<String>>,
+    // Held while writing, so saves land one at a time
+    writing: Mutex<()>,
+}
+
+/// A write of the positions file. It blocks, so run it off the async
+/// executor. Saves may run in any order: each writes the newest positions
+/// there are, or nothing when a later save got to them first.
+#[must_use]
+pub struct PendingSave {
+    file: PathBuf,
+    pending: Arc<Pending>,
+}
+
+impl PendingSave {
+    /// Writes a temporary file and renames it over the old one, so a crash
+    /// halfway leaves the previous positions whole.
+    pub fn write(self) -> Result<()> {
+        let _writing = self.pending.writing.lock().unwrap();
+        let Some(json) = self.pending.json.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let temp = self.file.with_extension("json.tmp");
+        fs::write(&temp, json).context("Failed to write resume positions")?;
+        fs::rename(&temp, &self.file).context("Failed to replace resume positions")
+    }
+}
+
+impl ResumeStore {
+    pub fn open(file: PathBuf) -> Self {
+        let entries = match fs::read_to_string(&file) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                error!("Ignoring corrupt resume positions: {}", e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self {
+            file: Some(file),
+            entries,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Where to pick up `path`, if it was left partway through
+    pub fn position(&self, path: &str) -> Option<f64> {
+        self.entries.get(path).map(|entry| entry.position)
+    }
+
+    /// Remembers `position` in a track `duration` seconds long. Near either
+    /// end the track is forgotten instead, so it starts over next time.
+    pub fn remember(&mut self, path: &str, position: f64, duration: f64) -> Result<Option<PendingSave>> {
+        if position < START_MARGIN || duration - position < END_MARGIN {
+            return self.forget(path);
+        }
+        // Paused tracks keep their position, no need to write it again
+        if self.position(path).is_some_and(|saved| (saved - position).abs() < 1.0) {
+            return Ok(None);
+        }
+
+        let saved = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.entries.insert(path.to_string(), ResumePoint { position, saved });
+        if self.entries.len() > MAX_ENTRIES {
+            let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.saved).map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.save()
+    }
+
+    /// Drops the position of `path`, which played to the end
+    pub fn forget(&mut self, path: &str) -> Result<Option<PendingSave>> {
+        if self.entries.remove(path).is_none() {
+            return Ok(None);
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<Option<PendingSave>> {
+        let Some(file) = &self.file else {
+            return Ok(None);
+        };
+        *self.pending.json.lock().unwrap() = Some(serde_json::to_string(&self.entries)?);
+        Ok(Some(PendingSave {
+            file: file.clone(),
+            pending: Arc::clone(&self.pending),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_near_either_end_are_not_kept() {
+        let mut store = ResumeStore::default();
+
+        store.remember("book.m4b", 10.0, 3600.0).unwrap();
+        assert_eq!(store.position("book.m4b"), None);
+        store.remember("book.m4b", 1800.0, 3600.0).unwrap();
+        assert_eq!(store.position("book.m4b"), Some(1800.0));
+
+        // Finishing it forgets the old position too
+        store.remember("book.m4b", 3580.0, 3600.0).unwrap();
+        assert_eq!(store.position("book.m4b"), None);
+    }
+
+    #[test]
+    fn positions_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("resume.json");
+
+        let mut store = ResumeStore::open(file.clone());
+        let save = |save: Result<Option<PendingSave>>| save.unwrap().unwrap().write().unwrap();
+        save(store.remember("a.mp3", 600.0, 3600.0));
+        save(store.remember("b.mp3", 900.0, 3600.0));
+        save(store.forget("b.mp3"));
+
+        let store = ResumeStore::open(file.clone());
+        assert_eq!(store.position("a.mp3"), Some(600.0));
+        assert_eq!(store.position("b.mp3"), None);
+        assert!(!file.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn saves_run_out_of_order_keep_the_newest_positions() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("resume.json");
+
+        let mut store = ResumeStore::open(file.clone());
+        let first = store.remember("a.mp3", 600.0, 3600.0).unwrap().unwrap();
+        let second = store.remember("a.mp3", 900.0, 3600.0).unwrap().unwrap();
+        second.write().unwrap();
+        first.write().unwrap();
+
+        assert_eq!(ResumeStore::open(file).position("a.mp3"), Some(900.0));
+    }
+}
@@ -0,0 +1,365 @@
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::ipc::{SilenceMode, SilenceSettings};
+use crate::player::PlaybackClock;
+
+// How often (in frames) the settings are looked at
+const CHECK_INTERVAL: usize = 1024;
+
+// Frames read ahead for each frame played while in a long silence. Decoding
+// stays a bounded multiple of real time on the output thread, yet a minute
+// of silence is over in about two seconds.
+const SCAN_SPEED: usize = 32;
+
+// Longest stretch of quiet audio held back while trimming, to learn whether
+// it runs to the end of the track
+const MAX_LOOKAHEAD_SECONDS: usize = 30;
+
+impl SilenceSettings {
+    /// Highest sample value that still counts as silent
+    fn threshold(&self) -> u16 {
+        (i16::MAX as f32 * 10f32.powf(self.threshold_db / 20.0)) as u16
+    }
+
+    /// Frames of silence in a row before they are skipped or trimmed
+    fn min_frames(&self, sample_rate: u32) -> usize {
+        ((self.min_seconds * sample_rate as f32) as usize).max(1)
+    }
+}
+
+/// Silence settings shared between the `Player` and the audio thread.
+#[derive(Default)]
+pub struct SilenceShared {
+    settings: Mutex<SilenceSettings>,
+    // Bumped on every change so the audio thread only locks when needed
+    version: AtomicU64,
+}
+
+impl SilenceShared {
+    pub fn settings(&self) -> SilenceSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut SilenceSettings)) {
+        f(&mut self.settings.lock().unwrap());
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// A long silence being looked through ahead of the output
+struct Scan {
+    // Silent frames read so far, and how many of them were played
+    scanned: usize,
+    played: usize,
+    // Take the silence out of the timeline instead of playing it
+    skip: bool,
+}
+
+/// Leaves out silence of one track. Silence at the start is always dropped;
+/// a silence running to the end ends the track as soon as it's reached. In
+/// between, long silences are either played as they are (`Trim`) or cut
+/// out, moving the track's clock past them (`Skip`).
+///
+/// A long silence is read ahead of the output, a few frames for each one
+/// played, so the end of a track is found quickly. When trimming, the quiet
+/// audio read this way is kept and played back unchanged unless the track
+/// ends in it; when skipping, it is dropped and silence is played while
+/// looking for the end of it.
+pub struct Silence<S> {
+    inner: S,
+    shared: Arc<SilenceShared>,
+    version: u64,
+    settings: SilenceSettings,
+    clock: Arc<PlaybackClock>,
+    // The frame being handed out, up to `pos`
+    frame: Vec<i16>,
+    pos: usize,
+    channels: u16,
+    sample_rate: u32,
+    // Silent frames in a row played so far
+    quiet: usize,
+    leading: bool,
+    scan: Option<Scan>,
+    // Frames read ahead and not played yet, the one that ended a scanned
+    // silence last
+    ahead: VecDeque<i16>,
+    until_check: usize,
+}
+
+impl<S: Source<Item = i16>> Silence<S> {
+    pub fn new(inner: S, shared: Arc<SilenceShared>, clock: Arc<PlaybackClock>) -> Self {
+        let (channels, sample_rate) = (inner.channels(), inner.sample_rate());
+        Self {
+            inner,
+            shared,
+            version: u64::MAX,
+            settings: SilenceSettings::default(),
+            clock,
+            frame: Vec::new(),
+            pos: 0,
+            channels,
+            sample_rate,
+            quiet: 0,
+            leading: true,
+            scan: None,
+            ahead: VecDeque::new(),
+            until_check: 0,
+        }
+    }
+
+    fn check(&mut self) {
+        self.until_check = CHECK_INTERVAL;
+        let version = self.shared.version.load(Ordering::Acquire);
+        if version != self.version {
+            self.version = version;
+            self.settings = self.shared.settings();
+        }
+    }
+
+    /// Reads on through a long silence, `None` when it runs to the end of
+    /// the track. Stops early on the first frame that isn't silent.
+    fn scan_ahead(&mut self) -> Option<()> {
+        let channels = self.channels as usize;
+        let lookahead = MAX_LOOKAHEAD_SECONDS * self.sample_rate as usize * channels;
+        let threshold = self.settings.threshold();
+        let Some(scan) = &mut self.scan else {
+            return Some(());
+        };
+
+        for _ in 0..SCAN_SPEED {
+            if !scan.skip && self.ahead.len() >= lookahead {
+                break;
+            }
+            read_frame(&mut self.inner, &mut self.frame)?;
+            let silent = is_silent(&self.frame, threshold);
+            if !silent || !scan.skip {
+                self.ahead.extend(&self.frame);
+            }
+            if !silent {
+                if scan.skip {
+                    let frames = scan.scanned.saturating_sub(scan.played) as u64;
+                    self.clock.skip(Duration::from_nanos(frames * 1_000_000_000 / self.sample_rate.max(1) as u64));
+                }
+                self.scan = None;
+                self.quiet = 0;
+                return Some(());
+            }
+            scan.scanned += 1;
+        }
+
+        // Keep reading while holding back as much as allowed, to still
+        // notice the end of the track
+        if self.ahead.len() >= lookahead {
+            read_frame(&mut self.inner, &mut self.frame)?;
+            self.ahead.extend(&self.frame);
+            if !is_silent(&self.frame, threshold) {
+                self.scan = None;
+                self.quiet = 0;
+            }
+        }
+        Some(())
+    }
+
+    /// Moves on to the next frame to hand out; `None` once the track is over
+    fn next_frame(&mut self) -> Option<()> {
+        self.pos = 0;
+        if self.scan.is_some() && self.scan_ahead().is_none() {
+            // Silent all the way to the end
+            self.ahead.clear();
+            return None;
+        }
+
+        if !self.ahead.is_empty() {
+            let channels = (self.channels as usize).min(self.ahead.len());
+            self.frame.clear();
+            self.frame.extend(self.ahead.drain(..channels));
+            return Some(());
+        }
+
+        if let Some(scan) = &mut self.scan {
+            // Silence in place of the frames skipped
+            self.frame.clear();
+            self.frame.resize(self.channels as usize, 0);
+            scan.played += 1;
+            return Some(());
+        }
+
+        if self.until_check == 0 {
+            self.check();
+        }
+        self.until_check -= 1;
+
+        self.channels = self.inner.channels().max(1);
+        self.sample_rate = self.inner.sample_rate();
+        read_frame(&mut self.inner, &mut self.frame)?;
+
+        let leading = std::mem::replace(&mut self.leading, false);
+        if self.settings.mode == SilenceMode::Off || !is_silent(&self.frame, self.settings.threshold()) {
+            self.quiet = 0;
+            return Some(());
+        }
+        self.quiet += 1;
+        if leading || self.quiet >= self.settings.min_frames(self.sample_rate) {
+            self.scan = Some(Scan {
+                scanned: 0,
+                played: 0,
+                skip: leading || self.settings.mode == SilenceMode::Skip,
+            });
+        }
+        Some(())
+    }
+}
+
+/// Reads one frame of `inner` into `frame`; `None` at the end
+fn read_frame<S: Source<Item = i16>>(inner: &mut S, frame: &mut Vec<i16>) -> Option<()> {
+    frame.clear();
+    let channels = inner.channels().max(1);
+    frame.extend(inner.by_ref().take(channels as usize));
+    (!frame.is_empty()).then_some(())
+}
+
+fn is_silent(frame: &[i16], threshold: u16) -> bool {
+    frame.iter().all(|sample| sample.unsigned_abs() <= threshold)
+}
+
+impl<S: Source<Item = i16>> Iterator for Silence<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.pos == self.frame.len() {
+            self.next_frame()?;
+        }
+        let sample = self.frame[self.pos];
+        self.pos += 1;
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Silence<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        match self.frame.len() - self.pos {
+            0 if self.scan.is_none() && self.ahead.is_empty() => self.inner.current_frame_len(),
+            // Handed out one frame at a time while reading ahead
+            0 => Some(self.channels as usize),
+            left => Some(left),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        if self.pos < self.frame.len() || self.scan.is_some() || !self.ahead.is_empty() {
+            self.channels
+        } else {
+            self.inner.channels()
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        if self.pos < self.frame.len() || self.scan.is_some() || !self.ahead.is_empty() {
+            self.sample_rate
+        } else {
+            self.inner.sample_rate()
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.frame.clear();
+        self.pos = 0;
+        self.quiet = 0;
+        self.leading = pos.is_zero();
+        self.scan = None;
+        self.ahead.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const SAMPLE_RATE: u32 = 8000;
+    const SECOND: usize = SAMPLE_RATE as usize;
+
+    /// Mono audio made of `(seconds, loud)` parts; the quiet parts hiss
+    /// well below the default threshold instead of being digital zero.
+    fn track(parts: &[(usize, bool)]) -> Vec<i16> {
+        parts
+            .iter()
+            .flat_map(|&(seconds, loud)| {
+                (0..seconds * SECOND).map(move |i| match loud {
+                    true => 8000,
+                    false => (i % 41) as i16 - 20,
+                })
+            })
+            .collect()
+    }
+
+    fn play(samples: &[i16], mode: SilenceMode) -> (Vec<i16>, Arc<PlaybackClock>) {
+        let shared = Arc::new(SilenceShared::default());
+        shared.update(|settings| settings.mode = mode);
+        let clock = Arc::new(PlaybackClock::default());
+        let source = SamplesBuffer::new(1, SAMPLE_RATE, samples.to_vec());
+        let played = Silence::new(source, shared, Arc::clone(&clock)).collect();
+        (played, clock)
+    }
+
+    fn assert_skipped(clock: &PlaybackClock, seconds: f64) {
+        let skipped = clock.position().as_secs_f64();
+        assert!((skipped - seconds).abs() < 0.001, "skipped {}s instead of {}s", skipped, seconds);
+    }
+
+    #[test]
+    fn leading_silence_is_dropped() {
+        let (played, clock) = play(&track(&[(2, false), (1, true)]), SilenceMode::Trim);
+
+        // A little silence is played while reading through it
+        let scanning = 2 * SECOND / SCAN_SPEED;
+        assert_eq!(played.len(), scanning + SECOND);
+        assert!(played[scanning..].iter().all(|&s| s == 8000));
+        assert_skipped(&clock, (2 * SECOND - scanning) as f64 / SAMPLE_RATE as f64);
+    }
+
+    #[test]
+    fn trailing_silence_ends_the_track_early() {
+        let (played, clock) = play(&track(&[(1, true), (10, false)]), SilenceMode::Trim);
+
+        // The first second of it is played before it counts as long
+        let heard = 2 * SECOND;
+        assert!(played.len() <= heard + 9 * SECOND / SCAN_SPEED + 1, "played {} samples", played.len());
+        assert_eq!(played[..heard], track(&[(1, true), (1, false)])[..]);
+        assert_skipped(&clock, 0.0);
+    }
+
+    #[test]
+    fn long_silence_is_cut_out_in_podcast_mode() {
+        let samples = track(&[(1, true), (5, false), (1, true)]);
+        let (played, clock) = play(&samples, SilenceMode::Skip);
+
+        let scanning = 4 * SECOND / SCAN_SPEED;
+        assert_eq!(played.len(), 2 * SECOND + scanning + SECOND);
+        assert_eq!(played[..2 * SECOND], samples[..2 * SECOND]);
+        assert!(played[2 * SECOND..2 * SECOND + scanning].iter().all(|&s| s == 0));
+        assert!(played[2 * SECOND + scanning..].iter().all(|&s| s == 8000));
+        assert_skipped(&clock, (4 * SECOND - scanning) as f64 / SAMPLE_RATE as f64);
+    }
+
+    #[test]
+    fn silence_inside_a_track_is_kept_when_trimming() {
+        let samples = track(&[(1, true), (5, false), (1, true)]);
+        let (played, clock) = play(&samples, SilenceMode::Trim);
+
+        assert!(played == samples);
+        assert_skipped(&clock, 0.0);
+    }
+}
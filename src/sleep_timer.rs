@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+use crate::ipc::{SleepTimerMode, SleepTimerStatus};
+
+/// Playback fades out over this long before the timer goes off
+pub const FADE: Duration = Duration::from_secs(30);
+
+/// Longest timer that can be set, a day
+pub const MAX_MINUTES: f64 = 24.0 * 60.0;
+
+/// What to do once the timer runs out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SleepAction {
+    Pause,
+    Stop,
+}
+
+/// A running sleep timer. Minutes count wall-clock time; the other modes
+/// follow playback, so pausing holds them.
+pub struct SleepTimer {
+    mode: SleepTimerMode,
+    deadline: Option<Instant>,
+    expired: bool,
+    // Fade gain last handed to the player
+    gain: f32,
+}
+
+impl SleepTimer {
+    pub fn new(mode: SleepTimerMode) -> Self {
+        let deadline = match mode {
+            SleepTimerMode::Minutes(minutes) => Some(Instant::now() + Duration::from_secs_f64(minutes.clamp(0.0, MAX_MINUTES) * 60.0)),
+            _ => None,
+        };
+        Self {
+            mode,
+            deadline,
+            expired: false,
+            gain: 1.0,
+        }
+    }
+
+    pub fn action(&self) -> SleepAction {
+        match self.mode {
+            SleepTimerMode::Minutes(_) => SleepAction::Pause,
+            SleepTimerMode::EndOfTrack | SleepTimerMode::EndOfPlaylist => SleepAction::Stop,
+        }
+    }
+
+    /// The current track played out; `last` when nothing follows it.
+    pub fn track_ended(&mut self, last: bool) {
+        match self.mode {
+            SleepTimerMode::EndOfTrack => self.expired = true,
+            SleepTimerMode::EndOfPlaylist if last => self.expired = true,
+            _ => {}
+        }
+    }
+
+    /// Whether the timer went off with the end of a track
+    pub fn expired(&self) -> bool {
+        self.expired
+    }
+
+    /// Time until the timer goes off, given what's left of the current track
+    /// (`None` when unknown) and whether it's the last one. `None` while the
+    /// end can't be told yet.
+    pub fn remaining(&self, track_left: Option<Duration>, last_track: bool) -> Option<Duration> {
+        if self.expired {
+            return Some(Duration::ZERO);
+        }
+        match self.mode {
+            SleepTimerMode::Minutes(_) => self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            SleepTimerMode::EndOfTrack => track_left,
+            SleepTimerMode::EndOfPlaylist if last_track => track_left,
+            SleepTimerMode::EndOfPlaylist => None,
+        }
+    }
+
+    /// The output gain for `remaining` left, if it changed since last time.
+    pub fn fade(&mut self, remaining: Duration) -> Option<f32> {
+        let gain = fade_gain(remaining);
+        (gain != self.gain).then(|| {
+            self.gain = gain;
+            gain
+        })
+    }
+
+    pub fn status(&self, remaining: Option<Duration>) -> SleepTimerStatus {
+        SleepTimerStatus {
+            mode: self.mode,
+            remaining: remaining.map(|left| left.as_secs_f64()),
+        }
+    }
+}
+
+/// Output gain with `remaining` left on the timer: full until the fade
+/// starts, then down to silence along a curve that sounds even.
+fn fade_gain(remaining: Duration) -> f32 {
+    let t = (remaining.as_secs_f32() / FADE.as_secs_f32()).min(1.0);
+    t * t
+}
@@ -0,0 +1,807 @@
+use anyhow::{bail, Context, Result};
+use rodio::source::SeekError;
+use rodio::Source;
+use tracing::{info, warn};
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::decoder::SymphoniaSource;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// A station silent for this long counts as gone
+const READ_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Audio read ahead of the decoder, about half a minute at 128 kbit/s
+const BUFFER_SIZE: usize = 512 * 1024;
+// Read before decoding starts, so a slow start doesn't stutter
+const PREBUFFER_SIZE: usize = 64 * 1024;
+const READ_CHUNK: usize = 16 * 1024;
+
+// Decoded audio kept ready for the output
+const DECODE_AHEAD: Duration = Duration::from_secs(1);
+// How much silence the output plays when no decoded audio is ready, before
+// looking again
+const UNDERRUN_MILLIS: u32 = 20;
+
+const MAX_REDIRECTS: usize = 5;
+
+// Limits on the response head, so a broken server can't fill memory
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+// Failed attempts in a row before a dropped stream is given up on
+const MAX_RECONNECTS: usize = 5;
+#[cfg(not(test))]
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+// Tests run through their reconnects without waiting
+#[cfg(test)]
+const RECONNECT_DELAY: Duration = Duration::from_millis(10);
+
+/// Called with each new title the station announces
+pub type TitleCallback = Box<dyn FnMut(&str) + Send>;
+
+/// Whether `path` is a stream URL rather than a file
+pub fn is_url(path: &str) -> bool {
+    ["http://", "https://"]
+        .iter()
+        .any(|scheme| path.get(..scheme.len()).is_some_and(|start| start.eq_ignore_ascii_case(scheme)))
+}
+
+/// Fails for URLs that can't be streamed, so they are turned away when
+/// added rather than when played.
+pub fn check_url(url: &str) -> Result<()> {
+    Url::parse(url).map(|_| ())
+}
+
+struct Url {
+    /// Host and port as given, for the `Host` header
+    authority: String,
+    host: String,
+    port: u16,
+    /// Path and query
+    target: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = match url.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &url[7..],
+            _ => bail!("Only http:// streams are supported: {}", url),
+        };
+        // The fragment never goes to the server
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        // Credentials aren't sent anywhere
+        let authority = authority.rsplit('@').next().unwrap_or_default();
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().context("Invalid port")?),
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            bail!("No host in {}", url);
+        }
+
+        Ok(Self {
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            target: if target.starts_with('?') { format!("/{}", target) } else { target.to_string() },
+        })
+    }
+
+    /// Where a redirect to `location` leads
+    fn resolve(&self, location: &str) -> String {
+        if location.contains("://") {
+            location.to_string()
+        } else if location.starts_with('/') {
+            format!("http://{}{}", self.authority, location)
+        } else {
+            let dir = self.target.rsplit_once('/').map_or("", |(dir, _)| dir);
+            format!("http://{}{}/{}", self.authority, dir, location)
+        }
+    }
+}
+
+enum Reply {
+    Stream(Connection),
+    Redirect(String),
+}
+
+/// The body of an HTTP or ICY response, with the metadata blocks taken
+/// out of the audio.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    // Audio bytes between two metadata blocks, and left before the next one
+    metaint: Option<usize>,
+    until_meta: usize,
+    /// Files served over HTTP have a length; live streams don't
+    finite: bool,
+    content_type: Option<String>,
+    name: Option<String>,
+}
+
+impl Connection {
+    /// Requests `url`, following redirects
+    fn open(url: &str) -> Result<Self> {
+        let mut url = url.to_string();
+        for _ in 0..=MAX_REDIRECTS {
+            match Self::request(&url)? {
+                Reply::Stream(connection) => return Ok(connection),
+                Reply::Redirect(location) => url = location,
+            }
+        }
+        bail!("Too many redirects")
+    }
+
+    fn request(url: &str) -> Result<Reply> {
+        let url = Url::parse(url)?;
+        let mut stream = None;
+        let mut last_error = None;
+        for addr in (url.host.as_str(), url.port).to_socket_addrs().context(format!("Failed to resolve {}", url.host))? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        let mut stream = match (stream, last_error) {
+            (Some(stream), _) => stream,
+            (None, Some(e)) => return Err(e).context(format!("Failed to connect to {}", url.authority)),
+            (None, None) => bail!("No address for {}", url.host),
+        };
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_write_timeout(Some(READ_TIMEOUT))?;
+
+        // HTTP/1.0 keeps the body from being chunked
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: musicplayer/{}\r\nAccept: */*\r\nIcy-MetaData: 1\r\n\r\n",
+            url.target,
+            url.authority,
+            env!("CARGO_PKG_VERSION"),
+        )?;
+
+        let mut reader = BufReader::new(stream);
+        // "HTTP/1.1 200 OK", or "ICY 200 OK" from older SHOUTcast servers
+        let status_line = read_line(&mut reader)?;
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .context("Not an HTTP response")?;
+
+        let mut headers = HashMap::new();
+        for count in 0.. {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if count == MAX_HEADERS {
+                bail!("More than {} headers", MAX_HEADERS);
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+            }
+        }
+
+        match status {
+            200 => {}
+            301 | 302 | 303 | 307 | 308 => {
+                let location = headers.get("location").context("Redirect without a location")?;
+                return Ok(Reply::Redirect(url.resolve(location)));
+            }
+            _ => bail!("Server replied {}", status_line),
+        }
+        if headers.get("transfer-encoding").is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity")) {
+            bail!("Unsupported transfer encoding");
+        }
+
+        let metaint = headers.get("icy-metaint").and_then(|n| n.parse().ok()).filter(|&n| n > 0);
+        Ok(Reply::Stream(Self {
+            reader,
+            metaint,
+            until_meta: metaint.unwrap_or(0),
+            finite: headers.contains_key("content-length"),
+            content_type: headers.remove("content-type"),
+            name: headers.remove("icy-name").filter(|name| !name.is_empty()),
+        }))
+    }
+
+    /// Reads audio into `buf`, passing on the title of any metadata block
+    /// on the way. `Ok(0)` at the end of the body.
+    fn read_audio(&mut self, buf: &mut [u8], on_title: &mut dyn FnMut(String)) -> io::Result<usize> {
+        let Some(metaint) = self.metaint else {
+            return self.reader.read(buf);
+        };
+        if self.until_meta == 0 {
+            // A length in 16-byte units, mostly 0 while the title stays
+            let mut len = [0u8];
+            self.reader.read_exact(&mut len)?;
+            let mut block = vec![0; len[0] as usize * 16];
+            self.reader.read_exact(&mut block)?;
+            if let Some(title) = stream_title(&block) {
+                on_title(title);
+            }
+            self.until_meta = metaint;
+        }
+        let len = buf.len().min(self.until_meta);
+        let read = self.reader.read(&mut buf[..len])?;
+        self.until_meta -= read;
+        Ok(read)
+    }
+}
+
+/// A header line without its line break, at most `MAX_LINE` bytes long.
+/// Header values aren't always UTF-8, station names especially.
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.len() > MAX_LINE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Header line too long"));
+    }
+    Ok(decode_text(&line).trim_end().to_string())
+}
+
+/// UTF-8, or Latin-1 when it isn't
+fn decode_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// The title in an ICY metadata block: `StreamTitle='Artist - Title';`
+fn stream_title(block: &[u8]) -> Option<String> {
+    let end = block.iter().position(|&b| b == 0).unwrap_or(block.len());
+    let text = decode_text(&block[..end]);
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &text[start..];
+    // Titles can have quotes of their own; the field ends at `';`
+    let title = rest.find("';").map_or(rest.trim_end_matches('\''), |end| &rest[..end]);
+    Some(title.trim().to_string()).filter(|title| !title.is_empty())
+}
+
+/// Audio on its way from the network thread to the decoder
+#[derive(Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    // Nothing more is coming
+    ended: bool,
+    // The stream was dropped or closed, so the network thread can stop
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    buffer: Mutex<Buffer>,
+    changed: Condvar,
+}
+
+impl Shared {
+    /// Adds `bytes`, waiting for room as needed. `false` once the stream is
+    /// dropped.
+    fn push(&self, mut bytes: &[u8]) -> bool {
+        let mut buffer = self.buffer.lock().unwrap();
+        while !bytes.is_empty() {
+            buffer = self
+                .changed
+                .wait_while(buffer, |buffer| buffer.data.len() >= BUFFER_SIZE && !buffer.closed)
+                .unwrap();
+            if buffer.closed {
+                return false;
+            }
+            let len = bytes.len().min(BUFFER_SIZE - buffer.data.len());
+            buffer.data.extend(&bytes[..len]);
+            bytes = &bytes[len..];
+            self.changed.notify_all();
+        }
+        true
+    }
+
+    fn end(&self) {
+        self.buffer.lock().unwrap().ended = true;
+        self.changed.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.buffer.lock().unwrap().closed
+    }
+
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Closes an `HttpStream` from elsewhere, which ends any read waiting on
+/// the network.
+pub struct StreamCloser(Arc<Shared>);
+
+impl StreamCloser {
+    pub fn close(&self) {
+        self.0.close();
+    }
+}
+
+/// Audio streamed over HTTP, the way Icecast and SHOUTcast servers send
+/// it. A thread of its own reads ahead into a bounded buffer and
+/// reconnects when the connection drops. Reading waits for the network,
+/// so it belongs on a thread of its own; see `StreamSource`.
+pub struct HttpStream {
+    shared: Arc<Shared>,
+    content_type: Option<String>,
+    name: Option<String>,
+}
+
+impl HttpStream {
+    /// Connects to `url` and starts reading. Titles the station announces
+    /// go to `on_title`.
+    pub fn connect(url: &str, on_title: TitleCallback) -> Result<Self> {
+        let connection = Connection::open(url)?;
+        let stream = Self {
+            shared: Arc::default(),
+            content_type: connection.content_type.clone(),
+            name: connection.name.clone(),
+        };
+
+        let shared = Arc::clone(&stream.shared);
+        let thread_url = url.to_string();
+        thread::Builder::new()
+            .name("stream".to_string())
+            .spawn(move || receive(&thread_url, connection, &shared, on_title))
+            .context("Failed to start stream thread")?;
+
+        let buffer = stream.shared.buffer.lock().unwrap();
+        let _ = stream.shared.changed.wait_timeout_while(buffer, READ_TIMEOUT, |buffer| {
+            buffer.data.len() < PREBUFFER_SIZE && !buffer.ended
+        });
+        Ok(stream)
+    }
+
+    /// File extension of the content type, as a hint for probing
+    pub fn extension(&self) -> Option<&'static str> {
+        let content_type = self.content_type.as_deref()?.split(';').next()?.trim().to_ascii_lowercase();
+        Some(match content_type.as_str() {
+            "audio/mpeg" | "audio/mp3" => "mp3",
+            "audio/aac" | "audio/aacp" | "audio/x-aac" => "aac",
+            "audio/ogg" | "application/ogg" => "ogg",
+            "audio/flac" | "audio/x-flac" => "flac",
+            "audio/wav" | "audio/x-wav" | "audio/wave" => "wav",
+            _ => return None,
+        })
+    }
+
+    /// The station name, from the `icy-name` header
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn closer(&self) -> StreamCloser {
+        StreamCloser(Arc::clone(&self.shared))
+    }
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let buffer = self.shared.buffer.lock().unwrap();
+        let mut buffer = self
+            .shared
+            .changed
+            .wait_while(buffer, |buffer| buffer.data.is_empty() && !buffer.ended && !buffer.closed)
+            .unwrap();
+        // Closed streams read as ended
+        if buffer.closed {
+            return Ok(0);
+        }
+        let read = buffer.data.read(buf)?;
+        self.shared.changed.notify_all();
+        Ok(read)
+    }
+}
+
+impl Drop for HttpStream {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// A stretch of decoded audio in one format
+struct Chunk {
+    samples: Vec<i16>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl Chunk {
+    fn silence(channels: u16, sample_rate: u32) -> Self {
+        let frames = (sample_rate * UNDERRUN_MILLIS / 1000).max(1) as usize;
+        Self {
+            samples: vec![0; frames * channels as usize],
+            channels,
+            sample_rate,
+        }
+    }
+}
+
+/// Decoded audio on its way from the decoding thread to the output
+#[derive(Default)]
+struct Decoded {
+    chunks: VecDeque<Chunk>,
+    // Samples in `chunks`
+    len: usize,
+    ended: bool,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct DecodedShared {
+    decoded: Mutex<Decoded>,
+    changed: Condvar,
+}
+
+/// Plays a stream decoded by a thread of its own, so the output never
+/// waits on the network. When decoding falls behind, because the station
+/// stalls or is being reconnected to, silence is played until it catches
+/// up. The stream ends once the station is given up on.
+pub struct StreamSource {
+    shared: Arc<DecodedShared>,
+    network: StreamCloser,
+    chunk: Chunk,
+    pos: usize,
+}
+
+impl StreamSource {
+    /// Starts decoding `source`, an `HttpStream` that `network` closes
+    pub fn new(source: SymphoniaSource, network: StreamCloser) -> Result<Self> {
+        let shared = Arc::new(DecodedShared::default());
+        let chunk = Chunk {
+            samples: Vec::new(),
+            channels: source.channels(),
+            sample_rate: source.sample_rate(),
+        };
+
+        let decoder_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("stream decoder".to_string())
+            .spawn(move || decode(source, &decoder_shared))
+            .context("Failed to start stream decoder")?;
+
+        let mut source = Self {
+            shared,
+            network,
+            chunk,
+            pos: 0,
+        };
+        source.next_chunk();
+        Ok(source)
+    }
+
+    /// Moves on to the next chunk, or to a little silence if none is ready.
+    /// Runs on the output's thread, so it never waits for the decoder. The
+    /// chunk stays empty at the end of the stream.
+    fn next_chunk(&mut self) {
+        self.pos = 0;
+        let mut decoded = self.shared.decoded.lock().unwrap();
+        match decoded.chunks.pop_front() {
+            Some(chunk) => {
+                decoded.len -= chunk.samples.len();
+                self.chunk = chunk;
+                self.shared.changed.notify_all();
+            }
+            None if decoded.ended => self.chunk.samples.clear(),
+            None => self.chunk = Chunk::silence(self.chunk.channels, self.chunk.sample_rate),
+        }
+    }
+}
+
+/// Decodes `source` into `shared`, keeping `DECODE_AHEAD` of audio ready,
+/// until the stream ends or the output is dropped.
+fn decode(mut source: SymphoniaSource, shared: &DecodedShared) {
+    loop {
+        // Packets come as frames of one format
+        let len = source.current_frame_len().unwrap_or(0);
+        if len == 0 {
+            break;
+        }
+        let (channels, sample_rate) = (source.channels(), source.sample_rate());
+        let samples: Vec<i16> = source.by_ref().take(len).collect();
+
+        let ahead = (DECODE_AHEAD.as_secs_f64() * sample_rate as f64 * channels as f64) as usize;
+        let decoded = shared.decoded.lock().unwrap();
+        let mut decoded = shared
+            .changed
+            .wait_while(decoded, |decoded| decoded.len >= ahead && !decoded.closed)
+            .unwrap();
+        if decoded.closed {
+            return;
+        }
+        decoded.len += samples.len();
+        decoded.chunks.push_back(Chunk {
+            samples,
+            channels,
+            sample_rate,
+        });
+        shared.changed.notify_all();
+    }
+    shared.decoded.lock().unwrap().ended = true;
+    shared.changed.notify_all();
+}
+
+impl Iterator for StreamSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = *self.chunk.samples.get(self.pos)?;
+        self.pos += 1;
+        // Move on eagerly so the format always describes the coming samples
+        if self.pos == self.chunk.samples.len() {
+            self.next_chunk();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for StreamSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.chunk.samples.len() - self.pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.chunk.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.chunk.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, _: Duration) -> Result<(), SeekError> {
+        Err(SeekError::NotSupported { underlying_source: "StreamSource" })
+    }
+}
+
+impl Drop for StreamSource {
+    fn drop(&mut self) {
+        self.shared.decoded.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        // Wakes the decoder if it waits on the network
+        self.network.close();
+    }
+}
+
+/// Copies audio into the buffer until the stream is dropped. A live stream
+/// that ends or fails is connected to again; a file served over HTTP ends
+/// the stream when it's done.
+fn receive(url: &str, mut connection: Connection, shared: &Shared, mut on_title: TitleCallback) {
+    let mut chunk = vec![0; READ_CHUNK];
+    let mut last_title = None;
+    // Stations repeat the title every so often; only changes are passed on
+    let mut announce = |title: String| {
+        if last_title.as_ref() != Some(&title) {
+            on_title(&title);
+            last_title = Some(title);
+        }
+    };
+    let mut attempts = 0;
+
+    loop {
+        match connection.read_audio(&mut chunk, &mut announce) {
+            Ok(0) if connection.finite => break,
+            Ok(0) => warn!("Stream ended: {}", url),
+            Ok(read) => {
+                attempts = 0;
+                if !shared.push(&chunk[..read]) {
+                    return;
+                }
+                continue;
+            }
+            Err(e) if connection.finite => {
+                warn!("Lost {}: {}", url, e);
+                break;
+            }
+            Err(e) => warn!("Lost {}: {}", url, e),
+        }
+
+        connection = loop {
+            attempts += 1;
+            if attempts > MAX_RECONNECTS || shared.is_closed() {
+                shared.end();
+                return;
+            }
+            thread::sleep(RECONNECT_DELAY);
+            match Connection::open(url) {
+                Ok(connection) => {
+                    info!("Reconnected to {}", url);
+                    break connection;
+                }
+                Err(e) => warn!("Failed to reconnect to {}: {:#}", url, e),
+            }
+        };
+    }
+    shared.end();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serves each connection in turn with the next of `bodies`, as an ICY
+    /// stream with metadata every `metaint` bytes, and hangs up after it.
+    /// Connections past the last body are dropped at once. Returns the URL
+    /// and the number of connections so far.
+    fn serve(metaint: usize, bodies: Vec<Vec<u8>>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/live", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&connections);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let n = counted.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while !read_line(&mut reader).unwrap().is_empty() {}
+                if let Some(body) = bodies.get(n) {
+                    write!(stream, "ICY 200 OK\r\nContent-Type: audio/mpeg\r\nicy-name: Test FM\r\nicy-metaint: {}\r\n\r\n", metaint).unwrap();
+                    stream.write_all(body).unwrap();
+                }
+            }
+        });
+        (url, connections)
+    }
+
+    /// Answers one connection with `head`, then hangs up. Returns the URL.
+    fn reply(head: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/live", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while !read_line(&mut reader).unwrap().is_empty() {}
+            // The client gives up partway through
+            let _ = stream.write_all(&head);
+        });
+        url
+    }
+
+    /// A metadata block announcing `title`
+    fn meta(title: &str) -> Vec<u8> {
+        let mut text = format!("StreamTitle='{}';", title).into_bytes();
+        text.resize(text.len().div_ceil(16) * 16, 0);
+        let mut block = vec![(text.len() / 16) as u8];
+        block.extend(text);
+        block
+    }
+
+    fn connect(url: &str) -> (HttpStream, Arc<Mutex<Vec<String>>>) {
+        let titles = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&titles);
+        let stream = HttpStream::connect(url, Box::new(move |title| seen.lock().unwrap().push(title.to_string()))).unwrap();
+        (stream, titles)
+    }
+
+    #[test]
+    fn strips_metadata_and_reports_titles() {
+        let body = [
+            b"abcd".to_vec(),
+            meta("Artist - First"),
+            b"efgh".to_vec(),
+            // Repeated titles are only announced once
+            meta("Artist - First"),
+            b"ijkl".to_vec(),
+            vec![0],
+            b"mnop".to_vec(),
+            meta("Caf\u{e9} - Second"),
+            b"qr".to_vec(),
+        ]
+        .concat();
+        let (url, _) = serve(4, vec![body]);
+
+        let (mut stream, titles) = connect(&url);
+        assert_eq!(stream.name(), Some("Test FM"));
+        assert_eq!(stream.extension(), Some("mp3"));
+        let mut audio = Vec::new();
+        stream.read_to_end(&mut audio).unwrap();
+        assert_eq!(audio, b"abcdefghijklmnopqr");
+        assert_eq!(*titles.lock().unwrap(), ["Artist - First", "Caf\u{e9} - Second"]);
+    }
+
+    #[test]
+    fn reconnects_after_the_connection_drops() {
+        let (url, connections) = serve(4, vec![b"firs".to_vec(), [b"t-se".to_vec(), meta("Back"), b"cond".to_vec()].concat()]);
+
+        let (mut stream, titles) = connect(&url);
+        let mut audio = Vec::new();
+        stream.read_to_end(&mut audio).unwrap();
+        assert_eq!(audio, b"first-second");
+        assert_eq!(*titles.lock().unwrap(), ["Back"]);
+        // Reading again counts as getting through
+        assert_eq!(connections.load(Ordering::SeqCst), 2 + MAX_RECONNECTS);
+    }
+
+    #[test]
+    fn ends_after_running_out_of_reconnects() {
+        let (url, connections) = serve(16, vec![b"only".to_vec()]);
+
+        let (mut stream, _) = connect(&url);
+        let mut audio = Vec::new();
+        stream.read_to_end(&mut audio).unwrap();
+        assert_eq!(audio, b"only");
+        assert_eq!(connections.load(Ordering::SeqCst), 1 + MAX_RECONNECTS);
+    }
+
+    #[test]
+    fn underrun_plays_silence_without_waiting() {
+        let mut source = StreamSource {
+            shared: Arc::default(),
+            network: StreamCloser(Arc::default()),
+            chunk: Chunk { samples: Vec::new(), channels: 2, sample_rate: 44100 },
+            pos: 0,
+        };
+        source.next_chunk();
+
+        // A second of silence, far quicker than the decoder could be waited on
+        let started = std::time::Instant::now();
+        let silence: Vec<i16> = source.by_ref().take(2 * 44100).collect();
+        assert!(silence.iter().all(|&sample| sample == 0));
+        assert!(started.elapsed() < Duration::from_millis(50), "took {:?}", started.elapsed());
+
+        // Audio decoded meanwhile follows the silence chunk in progress
+        let mut decoded = source.shared.decoded.lock().unwrap();
+        decoded.chunks.push_back(Chunk { samples: vec![7; 4], channels: 2, sample_rate: 44100 });
+        decoded.len = 4;
+        drop(decoded);
+        let left = source.chunk.samples.len() - source.pos;
+        let played: Vec<i16> = source.by_ref().take(left + 4).collect();
+        assert_eq!(played[left..], [7; 4]);
+
+        // The end comes once the silence already started is played out
+        source.shared.decoded.lock().unwrap().ended = true;
+        assert!(source.by_ref().all(|sample| sample == 0));
+        assert_eq!(source.next(), None);
+    }
+
+    #[test]
+    fn turns_away_oversized_heads() {
+        let mut long_line = b"HTTP/1.0 200 OK\r\nicy-name: ".to_vec();
+        long_line.extend(vec![b'a'; 2 * MAX_LINE]);
+        long_line.extend(b"\r\n\r\n");
+        let error = Connection::open(&reply(long_line)).err().unwrap();
+        assert!(format!("{:#}", error).contains("too long"), "{:#}", error);
+
+        let mut many = b"HTTP/1.0 200 OK\r\n".to_vec();
+        many.extend(b"x-filler: 1\r\n".repeat(MAX_HEADERS + 1));
+        many.extend(b"\r\n");
+        let error = Connection::open(&reply(many)).err().unwrap();
+        assert!(format!("{:#}", error).contains("headers"), "{:#}", error);
+
+        // Right at the limit is fine
+        let mut enough = b"HTTP/1.0 200 OK\r\n".to_vec();
+        enough.extend(b"x-filler: 1\r\n".repeat(MAX_HEADERS));
+        enough.extend(b"\r\n");
+        assert!(Connection::open(&reply(enough)).is_ok());
+    }
+
+    #[test]
+    fn turns_away_https() {
+        assert!(is_url("HTTPS://example.com/stream"));
+        assert!(check_url("https://example.com/stream").is_err());
+        assert!(check_url("http://example.com:8000/stream").is_ok());
+    }
+}
@@ -0,0 +1,415 @@
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+
+// Segment length and how far around the nominal position a segment may be
+// moved to line up with the previous one
+const SEGMENT: Duration = Duration::from_millis(30);
+const TOLERANCE: Duration = Duration::from_millis(8);
+
+// The similarity search works on every DECIMATION-th sample and offset,
+// then refines around the best match
+const DECIMATION: usize = 4;
+
+/// Playback speed shared between the `Player` and the audio thread.
+#[derive(Debug)]
+pub struct SpeedControl {
+    factor: AtomicU32,
+}
+
+impl Default for SpeedControl {
+    fn default() -> Self {
+        Self {
+            factor: AtomicU32::new(1.0f32.to_bits()),
+        }
+    }
+}
+
+impl SpeedControl {
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.factor.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, factor: f32) {
+        self.factor.store(factor.clamp(MIN_SPEED, MAX_SPEED).to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// WSOLA state for one stream format.
+struct Wsola {
+    channels: usize,
+    window: Vec<f32>,
+    hop: usize,
+    tolerance: usize,
+    // Interleaved input, `input_start` being the absolute frame of input[0]
+    input: Vec<f32>,
+    input_start: usize,
+    // Where the next segment would be taken from without any adjustment
+    nominal: f64,
+    // Start of the previous segment
+    previous: Option<usize>,
+    // Second half of the previous windowed segment, still to be overlapped
+    tail: Vec<f32>,
+}
+
+impl Wsola {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        let frames = |d: Duration| (d.as_secs_f64() * sample_rate as f64) as usize;
+        // Even length, so a periodic Hann window overlapped by half sums to 1
+        let length = (frames(SEGMENT) / 2 * 2).max(2 * DECIMATION);
+        let window = (0..length)
+            .map(|n| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * n as f32 / length as f32).cos())
+            .collect();
+
+        Self {
+            channels,
+            window,
+            hop: length / 2,
+            tolerance: frames(TOLERANCE),
+            input: Vec::new(),
+            input_start: 0,
+            nominal: 0.0,
+            previous: None,
+            tail: vec![0.0; length / 2 * channels],
+        }
+    }
+
+    fn input_end(&self) -> usize {
+        self.input_start + self.input.len() / self.channels
+    }
+
+    fn mono(&self, frame: usize) -> f32 {
+        let start = (frame - self.input_start) * self.channels;
+        self.input[start..start + self.channels].iter().sum()
+    }
+
+    /// Normalized similarity of the overlap region starting at `candidate`
+    /// to the one starting at `reference`
+    fn similarity(&self, reference: usize, candidate: usize) -> f32 {
+        let mut correlation = 0.0;
+        let mut energy = 1e-9;
+        for n in (0..self.hop).step_by(DECIMATION) {
+            let c = self.mono(candidate + n);
+            correlation += self.mono(reference + n) * c;
+            energy += c * c;
+        }
+        correlation / energy.sqrt()
+    }
+
+    /// The segment start within `lo..=hi` that continues the previous
+    /// segment most smoothly
+    fn best_position(&self, natural: usize, lo: usize, hi: usize) -> usize {
+        let best_of = |positions: &mut dyn Iterator<Item = usize>| {
+            positions
+                .map(|p| (p, self.similarity(natural, p)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(lo, |(p, _)| p)
+        };
+
+        let coarse = best_of(&mut (lo..=hi).step_by(DECIMATION));
+        let fine_lo = coarse.saturating_sub(DECIMATION - 1).max(lo);
+        let fine_hi = (coarse + DECIMATION - 1).min(hi);
+        best_of(&mut (fine_lo..=fine_hi))
+    }
+
+    /// Input frames needed before the next segment can be produced
+    fn frames_needed(&self) -> usize {
+        let hi = self.nominal.round() as usize + self.tolerance;
+        let natural = self.previous.map_or(0, |p| p + self.hop);
+        hi.max(natural) + self.window.len()
+    }
+
+    /// Produces `hop` frames of output, moving `speed * hop` frames ahead
+    /// in the input.
+    fn step(&mut self, speed: f64, output: &mut Vec<i16>) {
+        let nominal = self.nominal.round() as usize;
+        let lo = nominal.saturating_sub(self.tolerance).max(self.input_start);
+        let hi = nominal + self.tolerance;
+
+        let position = match self.previous {
+            Some(previous) => self.best_position(previous + self.hop, lo, hi),
+            None => nominal.max(self.input_start),
+        };
+
+        // The first segment continues unstretched audio, so it must not fade in
+        let first = self.previous.is_none();
+
+        let channels = self.channels;
+        let base = (position - self.input_start) * channels;
+        for n in 0..self.window.len() {
+            let gain = self.window[n];
+            for ch in 0..channels {
+                let raw = self.input[base + n * channels + ch];
+                let sample = raw * gain;
+                if n < self.hop {
+                    let mixed = if first { raw } else { self.tail[n * channels + ch] + sample };
+                    output.push(mixed.clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+                } else {
+                    self.tail[(n - self.hop) * channels + ch] = sample;
+                }
+            }
+        }
+
+        self.previous = Some(position);
+        self.nominal += self.hop as f64 * speed;
+
+        // Keep what the next search and the next natural continuation need
+        let keep_from = (self.nominal as usize)
+            .saturating_sub(self.tolerance)
+            .min(position + self.hop);
+        if keep_from > self.input_start {
+            let drop = (keep_from - self.input_start).min(self.input.len() / channels);
+            self.input.drain(..drop * channels);
+            self.input_start += drop;
+        }
+    }
+
+    /// Hands back the input that hasn't been played yet, unstretched. Right
+    /// after a segment the overlap with its tail adds up to the plain input,
+    /// so nothing is lost or repeated.
+    fn flush(self, output: &mut Vec<i16>) {
+        let from = self.previous.map_or(self.nominal as usize, |p| p + self.hop);
+        let from = (from.max(self.input_start) - self.input_start) * self.channels;
+        output.extend(self.input.iter().skip(from).map(|s| *s as i16));
+    }
+}
+
+/// Changes the playback speed without changing the pitch (WSOLA: windowed
+/// segments are overlap-added at a fixed rate while being read from the
+/// input at `speed` times that rate, each nudged to where it lines up best
+/// with the previous one). At 1.0x the samples pass straight through.
+pub struct TimeStretch<S> {
+    inner: S,
+    speed: Arc<SpeedControl>,
+    wsola: Option<Wsola>,
+    output: Vec<i16>,
+    output_pos: usize,
+    // Format of the samples in `output`
+    channels: u16,
+    sample_rate: u32,
+    // Samples left in the inner source's current frame, and the position
+    // within the current interleaved frame while passing through
+    frame_left: usize,
+    channel: usize,
+    // Set when the stretcher had to stop; passing one sample through
+    // before starting again avoids spinning at the end of the stream
+    stalled: bool,
+}
+
+impl<S: Source<Item = i16>> TimeStretch<S> {
+    pub fn new(inner: S, speed: Arc<SpeedControl>) -> Self {
+        Self {
+            channels: inner.channels(),
+            sample_rate: inner.sample_rate(),
+            inner,
+            speed,
+            wsola: None,
+            output: Vec::new(),
+            output_pos: 0,
+            frame_left: 0,
+            channel: 0,
+            stalled: false,
+        }
+    }
+
+    /// Reads the next sample of the current format. Returns `None` at the end
+    /// of the stream and when the format is about to change.
+    fn pull(&mut self) -> Option<i16> {
+        if self.frame_left == 0 {
+            if self.inner.channels() != self.channels || self.inner.sample_rate() != self.sample_rate {
+                return None;
+            }
+            self.frame_left = self.inner.current_frame_len().unwrap_or(usize::MAX).max(1);
+        }
+        let sample = self.inner.next()?;
+        self.frame_left -= 1;
+        Some(sample)
+    }
+
+    /// Fills `output` with the next stretched hop, or drops back to passing
+    /// samples through when the stream ends, the format changes or the
+    /// speed is back to 1.0.
+    fn stretch(&mut self) {
+        let speed = self.speed.get() as f64;
+        let Some(mut wsola) = self.wsola.take() else {
+            return;
+        };
+
+        self.output.clear();
+        self.output_pos = 0;
+
+        // Right after a segment, plain input continues it seamlessly
+        if speed == 1.0 && wsola.previous.is_some() {
+            wsola.flush(&mut self.output);
+            return;
+        }
+
+        while wsola.input_end() < wsola.frames_needed() {
+            let frame: Option<Vec<f32>> = (0..wsola.channels).map(|_| self.pull().map(|s| s as f32)).collect();
+            match frame {
+                Some(frame) => wsola.input.extend(frame),
+                None => {
+                    wsola.flush(&mut self.output);
+                    self.stalled = true;
+                    return;
+                }
+            }
+        }
+
+        wsola.step(speed, &mut self.output);
+        self.wsola = Some(wsola);
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for TimeStretch<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        loop {
+            if let Some(&sample) = self.output.get(self.output_pos) {
+                self.output_pos += 1;
+                // Stay one hop ahead so the frame length is always known
+                if self.output_pos == self.output.len() && self.wsola.is_some() {
+                    self.stretch();
+                }
+                return Some(sample);
+            }
+
+            if self.wsola.is_some() {
+                self.stretch();
+                continue;
+            }
+
+            // Passing through; only start stretching on a frame boundary
+            if self.frame_left == 0 || self.channel == 0 {
+                if self.frame_left == 0 {
+                    self.channels = self.inner.channels();
+                    self.sample_rate = self.inner.sample_rate();
+                    self.channel = 0;
+                }
+                if self.speed.get() != 1.0 && !self.stalled {
+                    self.wsola = Some(Wsola::new(self.sample_rate, self.channels as usize));
+                    continue;
+                }
+            }
+
+            let sample = self.pull()?;
+            self.stalled = false;
+            self.channel = (self.channel + 1) % self.channels.max(1) as usize;
+            return Some(sample);
+        }
+    }
+}
+
+impl<S: Source<Item = i16>> Source for TimeStretch<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        match self.output.len() - self.output_pos {
+            0 => self.inner.current_frame_len(),
+            buffered => Some(buffered),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        if self.output_pos < self.output.len() {
+            self.channels
+        } else {
+            self.inner.channels()
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        if self.output_pos < self.output.len() {
+            self.sample_rate
+        } else {
+            self.inner.sample_rate()
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        // Buffered audio belongs to the old position
+        self.wsola = None;
+        self.output.clear();
+        self.output_pos = 0;
+        self.frame_left = 0;
+        self.channel = 0;
+        self.stalled = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// `seconds` of a 440 Hz tone in mono
+    fn tone(seconds: usize) -> Vec<i16> {
+        (0..seconds * SAMPLE_RATE as usize)
+            .map(|n| {
+                let phase = 2.0 * std::f32::consts::PI * 440.0 * n as f32 / SAMPLE_RATE as f32;
+                (phase.sin() * 10000.0) as i16
+            })
+            .collect()
+    }
+
+    fn stretch(samples: Vec<i16>, channels: u16, factor: f32) -> (TimeStretch<SamplesBuffer<i16>>, Arc<SpeedControl>) {
+        let speed = Arc::new(SpeedControl::default());
+        speed.set(factor);
+        let source = SamplesBuffer::new(channels, SAMPLE_RATE, samples);
+        (TimeStretch::new(source, Arc::clone(&speed)), speed)
+    }
+
+    #[test]
+    fn output_length_follows_the_speed() {
+        let input = tone(4);
+        for factor in [0.5, 1.5, 2.0] {
+            let (stretched, _) = stretch(input.clone(), 1, factor);
+            let played = stretched.count() as f32;
+            let expected = input.len() as f32 / factor;
+            assert!((played / expected - 1.0).abs() < 0.02, "{} samples at {}x, expected {}", played, factor, expected);
+        }
+    }
+
+    #[test]
+    fn channels_stay_in_place_when_the_speed_changes() {
+        // Left is always positive, right always negative
+        let input: Vec<i16> = (0..SAMPLE_RATE as usize * 3).flat_map(|_| [1000, -1000]).collect();
+        let (mut stretched, speed) = stretch(input, 2, 1.0);
+
+        let mut played = Vec::new();
+        for factor in [1.0, 1.5, 0.75, 1.0] {
+            speed.set(factor);
+            // An odd count, so the next change lands in the middle of a frame
+            for _ in 0..SAMPLE_RATE as usize + 1 {
+                assert_eq!(stretched.channels(), 2);
+                played.extend(stretched.next());
+            }
+        }
+        played.extend(stretched.by_ref());
+
+        assert_eq!(played.len() % 2, 0);
+        for frame in played.chunks(2) {
+            assert!(frame[0] > 0 && frame[1] < 0, "frame {:?}", frame);
+        }
+    }
+
+    #[test]
+    fn normal_speed_passes_samples_through() {
+        let input: Vec<i16> = (0..20_000).map(|n| (n * 7919 % 65_536 - 32_768) as i16).collect();
+        let (stretched, _) = stretch(input.clone(), 2, 1.0);
+        assert!(stretched.collect::<Vec<_>>() == input);
+    }
+}
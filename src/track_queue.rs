@@ -0,0 +1,650 @@
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::ipc::CrossfadeCurve;
+use crate::player::{PlaybackClock, PlayerEvent};
+
+pub type BoxedSource = Box<dyn Source<Item = i16> + Send>;
+
+// Frame length reported when the current source can't tell how many samples
+// it has left, same as rodio's own queue.
+const THRESHOLD: usize = 512;
+
+// How often (in samples) the queue looks at the clock and the shared state
+const CHECK_INTERVAL: usize = 1024;
+
+/// Length of the quick fade used for manual track changes
+const SKIP_FADE: Duration = Duration::from_millis(400);
+
+/// A decoded track together with the clock that follows its position.
+pub struct QueuedSource {
+    pub path: String,
+    pub source: BoxedSource,
+    pub clock: Arc<PlaybackClock>,
+    pub duration: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CrossfadeSettings {
+    /// Overlap between consecutive tracks, 0 for plain gapless playback
+    pub seconds: f64,
+    pub curve: CrossfadeCurve,
+    /// Fade quickly on manual track changes instead of cutting
+    pub fade_on_skip: bool,
+}
+
+impl Default for CrossfadeSettings {
+    fn default() -> Self {
+        Self {
+            seconds: 0.0,
+            curve: CrossfadeCurve::EqualPower,
+            fade_on_skip: false,
+        }
+    }
+}
+
+impl CrossfadeCurve {
+    /// Gains for the outgoing and incoming track at `t` (0..=1) into the fade
+    fn gains(&self, t: f32) -> (f32, f32) {
+        match self {
+            CrossfadeCurve::Linear => (1.0 - t, t),
+            CrossfadeCurve::EqualPower => {
+                let angle = t * std::f32::consts::FRAC_PI_2;
+                (angle.cos(), angle.sin())
+            }
+        }
+    }
+}
+
+/// Opens the current track once more, positioned at the loop start. Called
+/// on the loop's own thread, so it's free to block.
+pub type LoopOpener = Box<dyn FnMut() -> Option<BoxedSource> + Send>;
+
+/// An A–B loop in the current track. A thread keeps a second copy of the
+/// track opened and seeked to A, so jumping back is a swap on the audio
+/// thread rather than a seek.
+pub struct AbLoop {
+    start: Duration,
+    end: Duration,
+    ready: Receiver<BoxedSource>,
+    // Copies the queue is done with, dropped on the loop thread. Hanging up
+    // stops it.
+    used: Sender<BoxedSource>,
+}
+
+impl AbLoop {
+    pub fn new(start: Duration, end: Duration, mut open: LoopOpener) -> std::io::Result<Self> {
+        let (ready_tx, ready) = std::sync::mpsc::sync_channel(1);
+        let (used, used_rx) = std::sync::mpsc::channel();
+        thread::Builder::new()
+            .name("ab-loop".to_string())
+            .spawn(move || loop {
+                // One copy ahead, the next one once the queue has taken it
+                let Some(source) = open() else {
+                    return;
+                };
+                if ready_tx.send(source).is_err() || used_rx.recv().is_err() {
+                    return;
+                }
+            })?;
+        Ok(Self { start, end, ready, used })
+    }
+}
+
+/// State shared between the `Player` and the `TrackQueue` on the audio thread.
+pub struct QueueShared {
+    next: Mutex<Option<QueuedSource>>,
+    // Where a manual skip goes, kept apart from `next` so that preloading
+    // the track after it can't replace it before the queue gets there
+    skip: Mutex<Option<QueuedSource>>,
+    crossfade: Mutex<CrossfadeSettings>,
+    // A–B loop in the current track
+    ab_loop: Mutex<Option<AbLoop>>,
+    events: mpsc::UnboundedSender<PlayerEvent>,
+}
+
+impl QueueShared {
+    pub fn new(events: mpsc::UnboundedSender<PlayerEvent>) -> Self {
+        Self {
+            next: Mutex::new(None),
+            skip: Mutex::new(None),
+            crossfade: Mutex::new(CrossfadeSettings::default()),
+            ab_loop: Mutex::new(None),
+            events,
+        }
+    }
+
+    fn report_finished(&self, path: String) {
+        let _ = self.events.send(PlayerEvent::TrackFinished { path });
+    }
+
+    /// The slot for the source that plays after the current one. The audio
+    /// thread empties it the moment it moves on.
+    pub fn next_slot(&self) -> MutexGuard<'_, Option<QueuedSource>> {
+        self.next.lock().unwrap()
+    }
+
+    /// Asks the queue to switch to `source` right away, with a short fade
+    /// when both are in the same format. The next slot is left alone.
+    pub fn skip_to(&self, source: QueuedSource) {
+        *self.skip.lock().unwrap() = Some(source);
+    }
+
+    /// Drops a skip the queue hasn't got to yet
+    pub fn cancel_skip(&self) {
+        self.skip.lock().unwrap().take();
+    }
+
+    pub fn crossfade(&self) -> CrossfadeSettings {
+        *self.crossfade.lock().unwrap()
+    }
+
+    pub fn set_crossfade(&self, settings: CrossfadeSettings) {
+        *self.crossfade.lock().unwrap() = settings;
+    }
+
+    /// Repeats the current track between the two positions, until cleared
+    /// or the track changes.
+    pub fn set_loop(&self, ab_loop: Option<AbLoop>) {
+        *self.ab_loop.lock().unwrap() = ab_loop;
+    }
+
+    fn has_loop(&self) -> bool {
+        self.ab_loop.lock().unwrap().is_some()
+    }
+}
+
+struct Fade {
+    outgoing: BoxedSource,
+    curve: CrossfadeCurve,
+    length: usize,
+    done: usize,
+    // Gain the outgoing track starts from, below 1 when it was still fading
+    // in itself
+    level: f32,
+}
+
+impl Fade {
+    fn gains(&self) -> (f32, f32) {
+        let (gain_out, gain_in) = self.curve.gains(self.done as f32 / self.length as f32);
+        (gain_out * self.level, gain_in)
+    }
+}
+
+/// A track that was still fading out when another skip came, ramped down
+/// from where it got to
+struct Tail {
+    source: BoxedSource,
+    gain: f32,
+    step: f32,
+}
+
+/// A single `Source` that plays the current track and carries on with the
+/// one waiting in the shared slot. The switch happens inside the sample
+/// stream, so tracks join without a gap or, with crossfade enabled, overlap
+/// for the configured time.
+pub struct TrackQueue {
+    current: QueuedSource,
+    // While fading, `current` is the incoming track
+    fade: Option<Fade>,
+    tails: Vec<Tail>,
+    shared: Arc<QueueShared>,
+    until_check: usize,
+    // Samples left to B while a loop is set, counted down one by one
+    loop_left: Option<u64>,
+    // Samples still to go of a silent frame played while holding at B
+    held: u16,
+    // Track that played out, reported once its successor has started
+    finished: Option<String>,
+}
+
+impl TrackQueue {
+    pub fn new(current: QueuedSource, shared: Arc<QueueShared>) -> Self {
+        Self {
+            current,
+            fade: None,
+            tails: Vec::new(),
+            shared,
+            until_check: 0,
+            loop_left: None,
+            held: 0,
+            finished: None,
+        }
+    }
+
+    fn check_fade(&mut self) {
+        let target = self.shared.skip.lock().unwrap().take();
+        let skip = target.is_some();
+        let settings = self.shared.crossfade();
+
+        let length = if skip {
+            SKIP_FADE
+        } else if settings.seconds > 0.0 && self.fade.is_none() && !self.shared.has_loop() {
+            let Some(duration) = self.current.duration else {
+                return;
+            };
+            // Never fade across more than half of a short track
+            let window = Duration::from_secs_f64(settings.seconds).min(duration / 2);
+            let remaining = duration.saturating_sub(self.current.clock.position());
+            if remaining > window {
+                return;
+            }
+            remaining
+        } else {
+            return;
+        };
+
+        let mut slot = self.shared.next.lock().unwrap();
+        let Some(next) = target.or_else(|| slot.take()) else {
+            return;
+        };
+
+        // Mixing needs both tracks in the same format; otherwise leave the
+        // next one for a plain gapless switch at the end of this track.
+        let compatible = next.source.channels() == self.current.source.channels()
+            && next.source.sample_rate() == self.current.source.sample_rate();
+        if !compatible && !skip {
+            *slot = Some(next);
+            return;
+        }
+        drop(slot);
+
+        let outgoing = self.advance(next);
+        // A skip is a manual change, not the end of the track
+        if !skip {
+            self.finished = Some(outgoing.path);
+        }
+        let length = length.as_secs_f64()
+            * outgoing.source.sample_rate() as f64
+            * outgoing.source.channels() as f64;
+
+        if !compatible || length < 1.0 {
+            self.fade = None;
+            self.tails.clear();
+            return;
+        }
+
+        // Skipping again mid-fade: the track on its way out keeps fading
+        // underneath the new fade instead of being cut off, and the one that
+        // was coming in leaves from the level it had reached
+        let mut level = 1.0;
+        if let Some(fade) = self.fade.take() {
+            let (gain_out, gain_in) = fade.gains();
+            level = gain_in;
+            self.tails.push(Tail {
+                source: fade.outgoing,
+                gain: gain_out,
+                step: gain_out / length as f32,
+            });
+        }
+        self.fade = Some(Fade {
+            outgoing: outgoing.source,
+            curve: settings.curve,
+            length: length as usize,
+            done: 0,
+            level,
+        });
+    }
+
+    /// Picks up loop changes and counts the samples left to B from the clock.
+    fn check_loop(&mut self) {
+        let ab_loop = self.shared.ab_loop.lock().unwrap();
+        self.loop_left = ab_loop.as_ref().and_then(|ab| self.current.clock.samples_until(ab.end));
+    }
+
+    /// Jumps back to A on reaching B by swapping in the copy opened there.
+    /// Returns `false` while that copy isn't ready yet.
+    fn wrap_loop(&mut self) -> bool {
+        let mut ab_loop = self.shared.ab_loop.lock().unwrap();
+        let Some(ab) = ab_loop.as_mut() else {
+            self.loop_left = None;
+            return true;
+        };
+        match ab.ready.try_recv() {
+            Ok(source) => {
+                let used = std::mem::replace(&mut self.current.source, source);
+                let _ = ab.used.send(used);
+                self.current.clock.reset(ab.start);
+                self.loop_left = self.current.clock.samples_until(ab.end);
+                self.fade = None;
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => {
+                // The copy couldn't be opened; play on
+                *ab_loop = None;
+                self.loop_left = None;
+                true
+            }
+        }
+    }
+
+    /// Makes `next` the current source, the loop staying with the old one.
+    fn advance(&mut self, next: QueuedSource) -> QueuedSource {
+        self.shared.set_loop(None);
+        self.loop_left = None;
+        std::mem::replace(&mut self.current, next)
+    }
+
+    fn mix(&mut self, sample: i16) -> i16 {
+        if self.fade.is_none() && self.tails.is_empty() {
+            return sample;
+        }
+
+        let mut mixed = sample as f32;
+        if let Some(fade) = &mut self.fade {
+            let (gain_out, gain_in) = fade.gains();
+            let outgoing = fade.outgoing.next().unwrap_or(0);
+            fade.done += 1;
+            if fade.done >= fade.length {
+                self.fade = None;
+            }
+            mixed = sample as f32 * gain_in + outgoing as f32 * gain_out;
+        }
+        for tail in &mut self.tails {
+            mixed += tail.source.next().unwrap_or(0) as f32 * tail.gain;
+            tail.gain -= tail.step;
+        }
+        self.tails.retain(|tail| tail.gain > 0.0);
+
+        mixed.clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+}
+
+impl Iterator for TrackQueue {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.until_check == 0 {
+            self.until_check = CHECK_INTERVAL;
+            self.check_fade();
+            self.check_loop();
+        }
+        self.until_check -= 1;
+        if self.held > 0 {
+            self.held -= 1;
+            return Some(0);
+        }
+        // Nothing past B ever plays: without the copy from A yet, hold
+        // there with silence, a whole frame at a time
+        if self.loop_left == Some(0) && !self.wrap_loop() {
+            self.held = self.current.source.channels().saturating_sub(1);
+            return Some(0);
+        }
+
+        loop {
+            if let Some(sample) = self.current.source.next() {
+                if let Some(left) = &mut self.loop_left {
+                    *left = left.saturating_sub(1);
+                }
+                if let Some(path) = self.finished.take() {
+                    self.shared.report_finished(path);
+                }
+                return Some(self.mix(sample));
+            }
+            self.fade = None;
+            self.tails.clear();
+
+            if let Some(path) = self.finished.take() {
+                self.shared.report_finished(path);
+            }
+            // Ended before the check got to a skip; a manual change all the same
+            let target = self.shared.skip.lock().unwrap().take();
+            if let Some(target) = target {
+                self.advance(target);
+                continue;
+            }
+            let next = self.shared.next.lock().unwrap().take();
+            match next {
+                Some(next) => self.finished = Some(self.advance(next).path),
+                None => {
+                    // Only report the end once, however often we're asked
+                    let path = std::mem::take(&mut self.current.path);
+                    if !path.is_empty() {
+                        self.shared.report_finished(path);
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Source for TrackQueue {
+    fn current_frame_len(&self) -> Option<usize> {
+        // The boundary between two tracks has to be a frame boundary as well,
+        // otherwise the output would keep using the old format for a while.
+        match self.current.source.current_frame_len() {
+            Some(len) if len > 0 => Some(len),
+            _ => match self.current.source.size_hint().0 {
+                0 => Some(THRESHOLD),
+                remaining => Some(remaining),
+            },
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.current.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.current.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Only seeks within the track that is currently playing. A running
+    /// crossfade is cut short. A loop carries on from the new position,
+    /// jumping back to A at once from past B.
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.fade = None;
+        self.held = 0;
+        self.current.source.try_seek(pos)?;
+        self.check_loop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    use crate::player::ClockedSource;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// Ten seconds of mono audio held at `level`
+    fn track(path: &str, level: i16) -> QueuedSource {
+        let samples = vec![level; SAMPLE_RATE as usize * 10];
+        QueuedSource {
+            path: path.to_string(),
+            source: Box::new(SamplesBuffer::new(1, SAMPLE_RATE, samples)),
+            clock: Arc::new(PlaybackClock::default()),
+            duration: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// Two seconds of mono audio counting up one step per sample, from
+    /// `start` on, clocked into `clock`
+    fn ramp(start: usize, clock: &Arc<PlaybackClock>) -> BoxedSource {
+        let samples = (start..SAMPLE_RATE as usize * 2).map(|i| i as i16).collect::<Vec<_>>();
+        Box::new(ClockedSource::new(SamplesBuffer::new(1, SAMPLE_RATE, samples), Arc::clone(clock)))
+    }
+
+    fn skip_to(shared: &QueueShared, next: QueuedSource) {
+        shared.skip_to(next);
+    }
+
+    #[test]
+    fn next_track_follows_without_a_gap() {
+        let (events, mut events_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(QueueShared::new(events));
+        let short = QueuedSource {
+            source: Box::new(SamplesBuffer::new(1, SAMPLE_RATE, vec![1000; 1500])),
+            ..track("a", 0)
+        };
+        let mut queue = TrackQueue::new(short, Arc::clone(&shared));
+        *shared.next_slot() = Some(track("b", 2000));
+
+        let played: Vec<i16> = queue.by_ref().take(3000).collect();
+        assert_eq!(played[..1500], [1000; 1500]);
+        assert_eq!(played[1500..], [2000; 1500]);
+        assert!(shared.next_slot().is_none());
+
+        // Reported once the next one has started, and only for "a"
+        match events_rx.try_recv() {
+            Ok(PlayerEvent::TrackFinished { path }) => assert_eq!(path, "a"),
+            other => panic!("{:?}", other),
+        }
+        assert!(events_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn last_track_ends_the_queue() {
+        let (events, mut events_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(QueueShared::new(events));
+        let short = QueuedSource {
+            source: Box::new(SamplesBuffer::new(1, SAMPLE_RATE, vec![1000; 100])),
+            ..track("a", 0)
+        };
+        let mut queue = TrackQueue::new(short, shared);
+
+        assert_eq!(queue.by_ref().count(), 100);
+        assert_eq!(queue.next(), None);
+        assert!(matches!(events_rx.try_recv(), Ok(PlayerEvent::TrackFinished { path }) if path == "a"));
+        assert!(events_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn skipping_during_a_fade_does_not_cut_the_outgoing_track() {
+        let (events, _events_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(QueueShared::new(events));
+        let mut queue = TrackQueue::new(track("a", 10000), Arc::clone(&shared));
+
+        skip_to(&shared, track("b", 6000));
+        let mut played: Vec<i16> = queue.by_ref().take(CHECK_INTERVAL).collect();
+        // The next check lands a third into the 400 ms fade to "b"
+        assert!(queue.fade.is_some());
+        skip_to(&shared, track("c", -8000));
+        played.extend(queue.by_ref().take(SAMPLE_RATE as usize));
+
+        let jump = played.windows(2).map(|w| (w[1] as i32 - w[0] as i32).abs()).max().unwrap();
+        assert!(jump < 50, "jumped by {}", jump);
+        assert!(queue.fade.is_none() && queue.tails.is_empty());
+        assert_eq!(played.last(), Some(&-8000));
+    }
+
+    #[test]
+    fn preloading_after_a_skip_does_not_replace_its_target() {
+        let (events, _events_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(QueueShared::new(events));
+        let mut queue = TrackQueue::new(track("a", 10000), Arc::clone(&shared));
+        queue.by_ref().take(CHECK_INTERVAL / 2).for_each(drop);
+
+        // The player preloads the track after "b" before the queue's next check
+        skip_to(&shared, track("b", 6000));
+        *shared.next_slot() = Some(track("c", -8000));
+        let played: Vec<i16> = queue.by_ref().take(SAMPLE_RATE as usize).collect();
+
+        assert_eq!(played.last(), Some(&6000));
+        assert_eq!(shared.next_slot().as_ref().map(|next| next.path.as_str()), Some("c"));
+    }
+
+    /// A queue playing `ramp` with a loop from 0.5 to 1 second, and the
+    /// channels the loop thread would hand copies over and get them back by
+    fn looping(clock: &Arc<PlaybackClock>) -> (TrackQueue, Sender<BoxedSource>, Receiver<BoxedSource>) {
+        let (events, _events_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(QueueShared::new(events));
+        let current = QueuedSource {
+            path: "a".to_string(),
+            source: ramp(0, clock),
+            clock: Arc::clone(clock),
+            duration: Some(Duration::from_secs(2)),
+        };
+        let queue = TrackQueue::new(current, Arc::clone(&shared));
+
+        let (ready_tx, ready) = std::sync::mpsc::channel();
+        let (used, used_rx) = std::sync::mpsc::channel();
+        shared.set_loop(Some(AbLoop {
+            start: Duration::from_millis(500),
+            end: Duration::from_secs(1),
+            ready,
+            used,
+        }));
+        (queue, ready_tx, used_rx)
+    }
+
+    // Loop points of `looping`, in samples
+    const A: usize = SAMPLE_RATE as usize / 2;
+    const B: usize = SAMPLE_RATE as usize;
+
+    #[test]
+    fn loop_jumps_back_to_a_on_the_sample_after_b() {
+        let clock = Arc::new(PlaybackClock::default());
+        let (mut queue, ready_tx, used_rx) = looping(&clock);
+        ready_tx.send(ramp(A, &clock)).unwrap();
+        ready_tx.send(ramp(A, &clock)).unwrap();
+
+        let played: Vec<i16> = queue.by_ref().take(B + 2 * (B - A) + 10).collect();
+        // Out of copies on the third pass, so it holds at B with silence
+        let expected: Vec<i16> = (0..B)
+            .chain(A..B)
+            .chain(A..B)
+            .map(|i| i as i16)
+            .chain([0; 10])
+            .collect();
+        assert_eq!(played, expected);
+        assert_eq!(used_rx.try_iter().count(), 2);
+        assert_eq!(clock.position(), Duration::from_secs(1));
+
+        // And carries on from A once the next copy is there
+        ready_tx.send(ramp(A, &clock)).unwrap();
+        let played: Vec<i16> = queue.by_ref().take(3).collect();
+        assert_eq!(played, [A as i16, A as i16 + 1, A as i16 + 2]);
+    }
+
+    #[test]
+    fn seeking_inside_a_loop_keeps_b_in_place() {
+        let clock = Arc::new(PlaybackClock::default());
+        let (mut queue, ready_tx, _used_rx) = looping(&clock);
+        ready_tx.send(ramp(A, &clock)).unwrap();
+        ready_tx.send(ramp(A, &clock)).unwrap();
+        queue.by_ref().take(100).for_each(drop);
+
+        queue.try_seek(Duration::from_millis(750)).unwrap();
+        let played: Vec<i16> = queue.by_ref().take(B - 3 * B / 4 + 1).collect();
+        assert_eq!(played.first(), Some(&(3 * B as i16 / 4)));
+        assert_eq!(played[played.len() - 2..], [B as i16 - 1, A as i16]);
+
+        // From past B straight back to A
+        queue.try_seek(Duration::from_millis(1500)).unwrap();
+        assert_eq!(queue.next(), Some(A as i16));
+    }
+
+    #[test]
+    fn loop_thread_keeps_a_copy_ready() {
+        let clock = Arc::new(PlaybackClock::default());
+        let opener_clock = Arc::clone(&clock);
+        let ab = AbLoop::new(
+            Duration::from_millis(500),
+            Duration::from_secs(1),
+            Box::new(move || Some(ramp(SAMPLE_RATE as usize / 2, &opener_clock))),
+        )
+        .unwrap();
+
+        for _ in 0..3 {
+            let mut copy = ab.ready.recv().unwrap();
+            assert_eq!(copy.next(), Some(SAMPLE_RATE as i16 / 2));
+            ab.used.send(copy).unwrap();
+        }
+    }
+}
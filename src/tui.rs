@@ -3,16 +3,26 @@ use crossterm::event::{self, DisableMouseCapture, EnableMouseCapture, Event, Key
 use crossterm::execute;
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
 use ratatui::prelude::*;
-use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph};
+use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph, Wrap};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::io;
 use std::time::{Duration, Instant};
 use tokio::time::{interval, Duration as TokioDuration};
 
+use crate::stream;
 use crate::theme::{Theme, ThemeStyle};
+use crate::time_stretch::{MAX_SPEED, MIN_SPEED};
+use crate::visualizer::FLOOR_DB;
+use crate::volume::{MAX_VOLUME, UNITY_VOLUME};
 
-use crate::ipc::{Command, IpcClient, PlaybackState, Response};
+use crate::ipc::{ChannelSettings, Chapter, Command, EqStatus, IpcClient, OutputDeviceList, PlaybackState, ReplayGainMode, Response, SilenceMode, SleepTimerMode, SleepTimerStatus, Visualization};
+
+// Crossfade length used when toggling it on from the TUI
+const DEFAULT_CROSSFADE: f64 = 5.0;
+
+// Speed change per key press
+const SPEED_STEP: f32 = 0.25;
 
 pub struct Tui {
     terminal: Terminal<CrosstermBackend<std::io::Stderr>>,
@@ -25,15 +35,119 @@ pub struct PlayerStatus {
     pub current_track: Option<String>,
     pub position: f64,
     pub duration: f64,
+    pub remaining: f64,
+    pub speed: f32,
     pub volume: u8,
+    pub muted: bool,
+    pub volume_boost: bool,
     pub playlist_length: usize,
     pub current_index: Option<usize>,
+    pub crossfade: f64,
+    pub replaygain: ReplayGainMode,
+    pub channels: ChannelSettings,
+    pub silence: SilenceMode,
+    pub sleep_timer: Option<SleepTimerStatus>,
+    pub loop_a: Option<f64>,
+    pub loop_b: Option<f64>,
+    pub chapters: Vec<Chapter>,
+    pub chapter: Option<usize>,
+    pub decode_errors: usize,
+    pub stream_title: Option<String>,
     pub playlist: Vec<String>,
 }
 
 enum AppMode {
     Player,
     FileBrowser,
+    Equalizer,
+    Devices,
+    Audio,
+}
+
+// dB per step when adjusting an equalizer band
+const EQ_STEP: f32 = 1.0;
+const EQ_RANGE: f32 = 12.0;
+
+// Balance change per key press, and the rows of the audio settings panel
+const BALANCE_STEP: f32 = 0.1;
+const AUDIO_ROWS: usize = 3;
+
+/// What the Now Playing block shows below the track name
+#[derive(Clone, Copy, PartialEq)]
+enum VisualStyle {
+    /// Spectrum bars over a level meter
+    Spectrum,
+    /// Level meter only
+    Meter,
+    Off,
+}
+
+impl VisualStyle {
+    fn next(self) -> Self {
+        match self {
+            VisualStyle::Spectrum => VisualStyle::Meter,
+            VisualStyle::Meter => VisualStyle::Off,
+            VisualStyle::Off => VisualStyle::Spectrum,
+        }
+    }
+
+    /// Rows taken inside the Now Playing block
+    fn height(self) -> u16 {
+        match self {
+            VisualStyle::Spectrum => SPECTRUM_HEIGHT + METER_HEIGHT,
+            VisualStyle::Meter => METER_HEIGHT,
+            VisualStyle::Off => 0,
+        }
+    }
+}
+
+// Chapters listed in the Now Playing block, around the one playing
+const CHAPTER_ROWS: u16 = 5;
+
+const SPECTRUM_HEIGHT: u16 = 6;
+const METER_HEIGHT: u16 = 2;
+
+// How fast bars and levels fall back per update, so they don't flicker
+const BAND_FALL: f32 = 0.05;
+const LEVEL_FALL_DB: f32 = 2.5;
+
+// Peaks above this are shown as clipping
+const CLIP_DB: f32 = -0.5;
+
+/// Takes in a new measurement: rises show at once, falls are slowed down.
+fn smooth_visualization(shown: &mut Visualization, latest: Visualization) {
+    if shown.bands.len() != latest.bands.len() {
+        shown.bands = vec![0.0; latest.bands.len()];
+    }
+    for (shown, latest) in shown.bands.iter_mut().zip(latest.bands) {
+        *shown = latest.max(*shown - BAND_FALL);
+    }
+    for channel in 0..2 {
+        shown.peak[channel] = latest.peak[channel].max(shown.peak[channel] - LEVEL_FALL_DB);
+        shown.rms[channel] = latest.rms[channel].max(shown.rms[channel] - LEVEL_FALL_DB);
+    }
+}
+
+async fn fetch_eq() -> Option<EqStatus> {
+    match IpcClient::send_command(Command::GetEq).await {
+        Ok(Response::Equalizer(eq)) => Some(eq),
+        _ => None,
+    }
+}
+
+async fn fetch_output_devices() -> Option<OutputDeviceList> {
+    match IpcClient::send_command(Command::ListOutputDevices).await {
+        Ok(Response::OutputDevices(list)) => Some(list),
+        _ => None,
+    }
+}
+
+/// Picker row of the device in use; row 0 is the system default
+fn selected_device_row(list: &OutputDeviceList) -> usize {
+    list.selected
+        .as_ref()
+        .and_then(|name| list.devices.iter().position(|d| d == name))
+        .map_or(0, |i| i + 1)
 }
 
 #[derive(Clone)]
@@ -280,13 +394,35 @@ impl Tui {
             current_track: None,
             position: 0.0,
             duration: 0.0,
+            remaining: 0.0,
+            speed: 1.0,
             volume: 70,
+            muted: false,
+            volume_boost: false,
             playlist_length: 0,
             current_index: None,
+            crossfade: 0.0,
+            replaygain: ReplayGainMode::Off,
+            channels: ChannelSettings::default(),
+            silence: SilenceMode::Off,
+            sleep_timer: None,
+            loop_a: None,
+            loop_b: None,
+            chapters: Vec::new(),
+            chapter: None,
+            decode_errors: 0,
+            stream_title: None,
             playlist: Vec::new(),
         };
 
         let mut mode = AppMode::Player;
+        let mut eq: Option<EqStatus> = None;
+        let mut eq_selected = 0;
+        let mut devices: Option<OutputDeviceList> = None;
+        let mut device_selected = 0;
+        let mut audio_selected: usize = 0;
+        let mut visual_style = VisualStyle::Spectrum;
+        let mut visual = Visualization::silent();
         let mut file_browser = match FileBrowser::new() {
             Ok(browser) => browser,
             Err(e) => {
@@ -314,9 +450,24 @@ impl Tui {
                         status.current_track = s.current_track;
                         status.position = s.position;
                         status.duration = s.duration;
+                        status.remaining = s.remaining;
+                        status.speed = s.speed;
                         status.volume = s.volume;
+                        status.muted = s.muted;
+                        status.volume_boost = s.volume_boost;
                         status.playlist_length = s.playlist_length;
                         status.current_index = s.current_index;
+                        status.crossfade = s.crossfade;
+                        status.replaygain = s.replaygain;
+                        status.channels = s.channels;
+                        status.silence = s.silence.mode;
+                        status.sleep_timer = s.sleep_timer;
+                        status.loop_a = s.loop_a;
+                        status.loop_b = s.loop_b;
+                        status.chapters = s.chapters;
+                        status.chapter = s.chapter;
+                        status.decode_errors = s.decode_errors;
+                        status.stream_title = s.stream_title;
                     }
 
                     // Fetch playlist less frequently (every 3rd tick)
@@ -328,7 +479,11 @@ impl Tui {
                     }
                 }
                 _ = tokio::time::sleep(TokioDuration::from_millis(50)) => {
-                    // Continue to input handling
+                    if matches!(mode, AppMode::Player) && visual_style != VisualStyle::Off {
+                        if let Ok(Response::Visualization(v)) = IpcClient::send_command(Command::GetVisualization).await {
+                            smooth_visualization(&mut visual, v);
+                        }
+                    }
                 }
             }
             
@@ -347,6 +502,18 @@ impl Tui {
                             AppMode::Player => {
                                 match key.code {
                                     KeyCode::Char('q') | KeyCode::Esc => break,
+                                    KeyCode::Char('e') => {
+                                        eq = fetch_eq().await;
+                                        mode = AppMode::Equalizer;
+                                    }
+                                    KeyCode::Char('o') => {
+                                        devices = fetch_output_devices().await;
+                                        device_selected = devices.as_ref().map_or(0, selected_device_row);
+                                        mode = AppMode::Devices;
+                                    }
+                                    KeyCode::Char('c') => {
+                                        mode = AppMode::Audio;
+                                    }
                                     KeyCode::Char('f') => {
                                         mode = AppMode::FileBrowser;
                                         file_browser = FileBrowser::new()?;
@@ -387,10 +554,21 @@ impl Tui {
                                             last_command = Instant::now();
                                         }
                                     }
+                                    KeyCode::Char(',') | KeyCode::Char('.') if last_command.elapsed() >= command_debounce => {
+                                        let offset = if key.code == KeyCode::Char('.') { 5.0 } else { -5.0 };
+                                        let _ = IpcClient::send_command(Command::SeekRelative { offset }).await;
+                                        last_command = Instant::now();
+                                    }
+                                    KeyCode::Char('<') | KeyCode::Char('>') if last_command.elapsed() >= command_debounce => {
+                                        let cmd = if key.code == KeyCode::Char('>') { Command::NextChapter } else { Command::PrevChapter };
+                                        let _ = IpcClient::send_command(cmd).await;
+                                        last_command = Instant::now();
+                                    }
                                     KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Up => {
                                         // Debounce volume changes
                                         if last_volume_change.elapsed() >= volume_debounce {
-                                            let new_vol = (status.volume + 5).min(100);
+                                            let max = if status.volume_boost { MAX_VOLUME } else { UNITY_VOLUME };
+                                            let new_vol = (status.volume + 5).min(max);
                                             let _ = IpcClient::send_command(Command::SetVolume { level: new_vol }).await;
                                             last_volume_change = Instant::now();
                                         }
@@ -403,6 +581,30 @@ impl Tui {
                                             last_volume_change = Instant::now();
                                         }
                                     }
+                                    KeyCode::Char('m') => {
+                                        let _ = IpcClient::send_command(Command::ToggleMute).await;
+                                    }
+                                    KeyCode::Char('l') => {
+                                        // Set A, then B, then clear, like a tape deck
+                                        let cmd = if status.loop_b.is_some() {
+                                            Command::ClearLoop
+                                        } else if status.loop_a.is_some() {
+                                            Command::SetLoopB { position: None }
+                                        } else {
+                                            Command::SetLoopA { position: None }
+                                        };
+                                        if let Ok(Response::Ok) = IpcClient::send_command(cmd.clone()).await {
+                                            match cmd {
+                                                Command::SetLoopA { .. } => status.loop_a = Some(status.position),
+                                                Command::SetLoopB { .. } => status.loop_b = Some(status.position),
+                                                _ => (status.loop_a, status.loop_b) = (None, None),
+                                            }
+                                        }
+                                    }
+                                    KeyCode::Char('v') => {
+                                        visual_style = visual_style.next();
+                                        visual = Visualization::silent();
+                                    }
                                     KeyCode::Char('t') => {
                                         // Cycle through themes
                                         let themes = Theme::all();
@@ -412,9 +614,171 @@ impl Tui {
                                         let next_idx = (current_idx + 1) % themes.len();
                                         self.set_theme(themes[next_idx]);
                                     }
+                                    KeyCode::Char('x') => {
+                                        // Toggle crossfade
+                                        let seconds = if status.crossfade > 0.0 { 0.0 } else { DEFAULT_CROSSFADE };
+                                        let _ = IpcClient::send_command(Command::SetCrossfade {
+                                            seconds,
+                                            curve: None,
+                                            fade_on_skip: None,
+                                        }).await;
+                                    }
+                                    KeyCode::Char('[') | KeyCode::Char(']') if last_command.elapsed() >= command_debounce => {
+                                        let step = if key.code == KeyCode::Char(']') { SPEED_STEP } else { -SPEED_STEP };
+                                        let factor = (status.speed + step).clamp(MIN_SPEED, MAX_SPEED);
+                                        if IpcClient::send_command(Command::SetSpeed { factor }).await.is_ok() {
+                                            status.speed = factor;
+                                        }
+                                        last_command = Instant::now();
+                                    }
+                                    KeyCode::Char('g') => {
+                                        // Cycle ReplayGain mode
+                                        let mode = match status.replaygain {
+                                            ReplayGainMode::Off => ReplayGainMode::Track,
+                                            ReplayGainMode::Track => ReplayGainMode::Album,
+                                            ReplayGainMode::Album => ReplayGainMode::Auto,
+                                            ReplayGainMode::Auto => ReplayGainMode::Off,
+                                        };
+                                        if IpcClient::send_command(Command::SetReplayGain {
+                                            mode,
+                                            preamp: None,
+                                            prevent_clipping: None,
+                                        }).await.is_ok() {
+                                            status.replaygain = mode;
+                                        }
+                                    }
+                                    KeyCode::Char('z') => {
+                                        // Cycle silence handling
+                                        let mode = match status.silence {
+                                            SilenceMode::Off => SilenceMode::Trim,
+                                            SilenceMode::Trim => SilenceMode::Skip,
+                                            SilenceMode::Skip => SilenceMode::Off,
+                                        };
+                                        if IpcClient::send_command(Command::SetSilence {
+                                            mode,
+                                            threshold_db: None,
+                                            min_seconds: None,
+                                        }).await.is_ok() {
+                                            status.silence = mode;
+                                        }
+                                    }
                                     _ => {}
                                 }
                             }
+                            AppMode::Equalizer => {
+                                let band_count = eq.as_ref().map_or(0, |eq| eq.bands.len());
+                                let gain = eq.as_ref()
+                                    .and_then(|eq| eq.bands.get(eq_selected))
+                                    .map_or(0.0, |band| band.gain);
+                                let command = match key.code {
+                                    KeyCode::Char('q') | KeyCode::Char('e') | KeyCode::Esc => {
+                                        mode = AppMode::Player;
+                                        None
+                                    }
+                                    KeyCode::Left | KeyCode::Char('h') => {
+                                        eq_selected = eq_selected.saturating_sub(1);
+                                        None
+                                    }
+                                    KeyCode::Right | KeyCode::Char('l') => {
+                                        eq_selected = (eq_selected + 1).min(band_count.saturating_sub(1));
+                                        None
+                                    }
+                                    KeyCode::Up | KeyCode::Char('k') => Some(Command::SetEqBand {
+                                        band: eq_selected,
+                                        gain: (gain + EQ_STEP).min(EQ_RANGE),
+                                    }),
+                                    KeyCode::Down | KeyCode::Char('j') => Some(Command::SetEqBand {
+                                        band: eq_selected,
+                                        gain: (gain - EQ_STEP).max(-EQ_RANGE),
+                                    }),
+                                    KeyCode::Char('0') => Some(Command::SetEqBand { band: eq_selected, gain: 0.0 }),
+                                    KeyCode::Char('p') => eq.as_ref().and_then(|eq| {
+                                        // Cycle through presets, starting after the active one
+                                        let current = eq.preset.as_ref()
+                                            .and_then(|p| eq.presets.iter().position(|name| name == p));
+                                        let next = current.map_or(0, |i| (i + 1) % eq.presets.len());
+                                        eq.presets.get(next).map(|name| Command::SetEqPreset { name: name.clone() })
+                                    }),
+                                    KeyCode::Char('o') => eq.as_ref().map(|eq| Command::SetEqEnabled { enabled: !eq.enabled }),
+                                    KeyCode::Char(' ') => Some(if status.state == PlaybackState::Playing {
+                                        Command::Pause
+                                    } else {
+                                        Command::Play { path: None }
+                                    }),
+                                    _ => None,
+                                };
+                                if let Some(command) = command {
+                                    let _ = IpcClient::send_command(command).await;
+                                    eq = fetch_eq().await;
+                                }
+                            }
+                            AppMode::Devices => {
+                                let rows = devices.as_ref().map_or(0, |list| list.devices.len() + 1);
+                                match key.code {
+                                    KeyCode::Char('q') | KeyCode::Char('o') | KeyCode::Esc => {
+                                        mode = AppMode::Player;
+                                    }
+                                    KeyCode::Up | KeyCode::Char('k') => {
+                                        device_selected = device_selected.saturating_sub(1);
+                                    }
+                                    KeyCode::Down | KeyCode::Char('j') => {
+                                        device_selected = (device_selected + 1).min(rows.saturating_sub(1));
+                                    }
+                                    KeyCode::Enter => {
+                                        if let Some(list) = &devices {
+                                            let name = device_selected
+                                                .checked_sub(1)
+                                                .and_then(|i| list.devices.get(i).cloned());
+                                            let _ = IpcClient::send_command(Command::SetOutputDevice { name }).await;
+                                            devices = fetch_output_devices().await;
+                                        }
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            AppMode::Audio => {
+                                let channels = status.channels;
+                                let (left, right) = (
+                                    (channels.balance - BALANCE_STEP).max(-1.0),
+                                    (channels.balance + BALANCE_STEP).min(1.0),
+                                );
+                                let changed = match (key.code, audio_selected) {
+                                    (KeyCode::Char('q') | KeyCode::Char('c') | KeyCode::Esc, _) => {
+                                        mode = AppMode::Player;
+                                        None
+                                    }
+                                    (KeyCode::Up | KeyCode::Char('k'), _) => {
+                                        audio_selected = audio_selected.saturating_sub(1);
+                                        None
+                                    }
+                                    (KeyCode::Down | KeyCode::Char('j'), _) => {
+                                        audio_selected = (audio_selected + 1).min(AUDIO_ROWS - 1);
+                                        None
+                                    }
+                                    (KeyCode::Left | KeyCode::Char('h'), 0) => Some(ChannelSettings { balance: left, ..channels }),
+                                    (KeyCode::Right | KeyCode::Char('l'), 0) => Some(ChannelSettings { balance: right, ..channels }),
+                                    (KeyCode::Char('0') | KeyCode::Enter, 0) => Some(ChannelSettings { balance: 0.0, ..channels }),
+                                    (KeyCode::Left | KeyCode::Right | KeyCode::Char('h') | KeyCode::Char('l') | KeyCode::Enter, 1) => {
+                                        Some(ChannelSettings { mono: !channels.mono, ..channels })
+                                    }
+                                    (KeyCode::Left | KeyCode::Right | KeyCode::Char('h') | KeyCode::Char('l') | KeyCode::Enter, 2) => {
+                                        Some(ChannelSettings { swap: !channels.swap, ..channels })
+                                    }
+                                    _ => None,
+                                };
+                                if let Some(channels) = changed {
+                                    // Round away float drift so the center is reachable again
+                                    let balance = (channels.balance * 10.0).round() / 10.0;
+                                    let command = Command::SetChannels {
+                                        balance: Some(balance),
+                                        mono: Some(channels.mono),
+                                        swap: Some(channels.swap),
+                                    };
+                                    if let Ok(Response::Ok) = IpcClient::send_command(command).await {
+                                        status.channels = ChannelSettings { balance, ..channels };
+                                    }
+                                }
+                            }
                             AppMode::FileBrowser => {
                                 match key.code {
                                     KeyCode::Char('q') | KeyCode::Esc => {
@@ -562,7 +926,25 @@ impl Tui {
             // Render UI
             match mode {
                 AppMode::Player => {
-                    if let Err(e) = self.terminal.draw(|f| ui_player(f, &status, &self.theme)) {
+                    if let Err(e) = self.terminal.draw(|f| ui_player(f, &status, visual_style, &visual, &self.theme)) {
+                        eprintln!("Render error: {}", e);
+                        break;
+                    }
+                }
+                AppMode::Equalizer => {
+                    if let Err(e) = self.terminal.draw(|f| ui_equalizer(f, &status, eq.as_ref(), eq_selected, &self.theme)) {
+                        eprintln!("Render error: {}", e);
+                        break;
+                    }
+                }
+                AppMode::Devices => {
+                    if let Err(e) = self.terminal.draw(|f| ui_devices(f, devices.as_ref(), device_selected, &self.theme)) {
+                        eprintln!("Render error: {}", e);
+                        break;
+                    }
+                }
+                AppMode::Audio => {
+                    if let Err(e) = self.terminal.draw(|f| ui_audio(f, &status, audio_selected, &self.theme)) {
                         eprintln!("Render error: {}", e);
                         break;
                     }
@@ -604,14 +986,47 @@ impl Drop for Tui {
     }
 }
 
-fn ui_player(frame: &mut Frame, status: &PlayerStatus, theme: &ThemeStyle) {
+/// Name shown for the current track: the file name, or for a radio
+/// stream the title on air, falling back to its URL
+fn track_name(status: &PlayerStatus) -> Option<&str> {
+    let track = status.current_track.as_deref()?;
+    if stream::is_url(track) {
+        return Some(status.stream_title.as_deref().unwrap_or(track));
+    }
+    Some(Path::new(track).file_name().and_then(|n| n.to_str()).unwrap_or(track))
+}
+
+fn volume_text(status: &PlayerStatus) -> String {
+    if status.muted {
+        format!("Muted ({}%)", status.volume)
+    } else {
+        format!("{}%", status.volume)
+    }
+}
+
+fn sleep_timer_text(timer: &SleepTimerStatus) -> String {
+    match (timer.mode, timer.remaining) {
+        (_, Some(left)) => format!("{}:{:02}", left as u64 / 60, left as u64 % 60),
+        (SleepTimerMode::EndOfPlaylist, None) => "end of playlist".to_string(),
+        (_, None) => "end of track".to_string(),
+    }
+}
+
+fn ui_player(frame: &mut Frame, status: &PlayerStatus, style: VisualStyle, visual: &Visualization, theme: &ThemeStyle) {
     let size = frame.area();
 
+    // Chapters and the visualization sit below the two lines of track info
+    let chapter_rows = status.chapters.len().min(CHAPTER_ROWS as usize) as u16;
+    let now_playing_height = match style {
+        VisualStyle::Off if chapter_rows == 0 => 3,
+        style => 4 + chapter_rows + style.height(),
+    };
+
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
             Constraint::Length(3),
-            Constraint::Length(6),
+            Constraint::Length(now_playing_height + 3),
             Constraint::Min(0),
             Constraint::Length(3),
         ])
@@ -623,7 +1038,22 @@ fn ui_player(frame: &mut Frame, status: &PlayerStatus, theme: &ThemeStyle) {
         PlaybackState::Stopped => "? STOPPED",
     };
 
-    let status_text = format!("{} | Volume: {}% | Tracks: {} | Theme: {}", state_text, status.volume, status.playlist_length, theme.theme.name());
+    let crossfade_text = if status.crossfade > 0.0 {
+        format!("{:.0}s", status.crossfade)
+    } else {
+        "Off".to_string()
+    };
+
+    let mut status_text = format!("{} | Volume: {} | Crossfade: {} | RG: {:?} | Tracks: {} | Theme: {}", state_text, volume_text(status), crossfade_text, status.replaygain, status.playlist_length, theme.theme.name());
+    if status.decode_errors > 0 {
+        status_text.push_str(&format!(" | Damaged: {}", status.decode_errors));
+    }
+    if status.silence != SilenceMode::Off {
+        status_text.push_str(&format!(" | Silence: {:?}", status.silence));
+    }
+    if let Some(timer) = &status.sleep_timer {
+        status_text.push_str(&format!(" | Sleep: {}", sleep_timer_text(timer)));
+    }
     frame.render_widget(
         Paragraph::new(status_text)
             .wrap(Wrap { trim: true })
@@ -633,30 +1063,43 @@ fn ui_player(frame: &mut Frame, status: &PlayerStatus, theme: &ThemeStyle) {
 
     let now_playing_chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Length(3), Constraint::Length(1), Constraint::Length(2)])
+        .constraints([Constraint::Length(now_playing_height), Constraint::Length(1), Constraint::Length(2)])
         .split(chunks[1]);
 
-    let track_name = status.current_track
-        .as_ref()
-        .map(|t| {
-            std::path::Path::new(t)
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or(t)
-        })
-        .unwrap_or("No track selected");
+    let track_name = track_name(status).unwrap_or("No track selected");
 
-    let time_text = if status.duration > 0.0 {
+    let time_text = if status.duration > 0.0 && status.speed != 1.0 {
+        format!("{:.0}s / {:.0}s ({:.0}s left at {:.2}x)", status.position, status.duration, status.remaining, status.speed)
+    } else if status.duration > 0.0 {
         format!("{:.0}s / {:.0}s", status.position, status.duration)
+    } else if status.current_track.as_deref().is_some_and(stream::is_url) {
+        format!("Live | {:.0}s", status.position)
     } else {
         "".to_string()
     };
+    let time_text = match (status.loop_a, status.loop_b) {
+        (Some(a), Some(b)) => format!("{} | Loop {:.0}s–{:.0}s", time_text, a, b),
+        (Some(a), None) => format!("{} | Loop from {:.0}s", time_text, a),
+        _ => time_text,
+    };
 
-    frame.render_widget(
-        Paragraph::new(format!("{}\n{}", track_name, time_text))
-            .block(Block::default().borders(Borders::ALL).title("Now Playing").style(theme.now_playing_style())),
-        now_playing_chunks[0]
-    );
+    let now_playing_block = Block::default().borders(Borders::ALL).title("Now Playing").style(theme.now_playing_style());
+    let inner = now_playing_block.inner(now_playing_chunks[0]);
+    frame.render_widget(now_playing_block, now_playing_chunks[0]);
+
+    let inner_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(2), Constraint::Length(chapter_rows), Constraint::Min(0)])
+        .split(inner);
+
+    frame.render_widget(Paragraph::new(format!("{}\n{}", track_name, time_text)), inner_chunks[0]);
+
+    if chapter_rows > 0 {
+        frame.render_widget(Paragraph::new(chapter_lines(status, chapter_rows as usize, theme)), inner_chunks[1]);
+    }
+    if style != VisualStyle::Off {
+        render_visualization(frame, inner_chunks[2], style, visual, theme);
+    }
 
     let progress = if status.duration > 0.0 {
         ((status.position / status.duration) * 100.0) as u16
@@ -664,14 +1107,26 @@ fn ui_player(frame: &mut Frame, status: &PlayerStatus, theme: &ThemeStyle) {
         0
     };
 
+    let progress_block = Block::default().title("Progress");
+    let bar = progress_block.inner(now_playing_chunks[2]);
     frame.render_widget(
         Gauge::default()
-            .block(Block::default().title("Progress"))
+            .block(progress_block)
             .gauge_style(theme.gauge_style())
             .percent(progress),
         now_playing_chunks[2]
     );
 
+    // Loop points on top of the bar
+    if status.duration > 0.0 && bar.width > 0 && bar.height > 0 {
+        for (label, point) in [("A", status.loop_a), ("B", status.loop_b)] {
+            if let Some(point) = point {
+                let offset = ((point / status.duration).clamp(0.0, 1.0) * (bar.width - 1) as f64) as u16;
+                frame.buffer_mut().set_string(bar.x + offset, bar.y, label, theme.highlight_style());
+            }
+        }
+    }
+
     let playlist_items: Vec<ListItem> = status.playlist
         .iter()
         .enumerate()
@@ -696,7 +1151,7 @@ fn ui_player(frame: &mut Frame, status: &PlayerStatus, theme: &ThemeStyle) {
     }
     frame.render_stateful_widget(playlist, chunks[2], &mut state);
 
-    let help_text = "[Space] Play/Pause | [S] Stop | [N/?] Next | [B/?] Prev | [+/-] Volume | [F] Files | [Q/Ctrl+D] Quit";
+    let help_text = "[Space] Play/Pause | [S] Stop | [N/?] Next | [B/?] Prev | [,/.] Seek | [</>] Chapter | [[/]] Speed | [+/-] Volume | [M] Mute | [V] Visualizer | [L] A-B Loop | [X] Crossfade | [G] ReplayGain | [Z] Silence | [E] Equalizer | [O] Output | [C] Channels | [F] Files | [Q/Ctrl+D] Quit";
     frame.render_widget(
         Paragraph::new(help_text)
             .wrap(Wrap { trim: true })
@@ -705,6 +1160,273 @@ fn ui_player(frame: &mut Frame, status: &PlayerStatus, theme: &ThemeStyle) {
     );
 }
 
+/// `rows` chapters around the one playing, which is highlighted
+fn chapter_lines(status: &PlayerStatus, rows: usize, theme: &ThemeStyle) -> Vec<Line<'static>> {
+    let current = status.chapter.unwrap_or(0);
+    let first = current.saturating_sub(rows / 2).min(status.chapters.len().saturating_sub(rows));
+    status.chapters
+        .iter()
+        .enumerate()
+        .skip(first)
+        .take(rows)
+        .map(|(i, chapter)| {
+            let start = chapter.start as u64;
+            let time = match start / 3600 {
+                0 => format!("{}:{:02}", start / 60, start % 60),
+                hours => format!("{}:{:02}:{:02}", hours, start / 60 % 60, start % 60),
+            };
+            let text = format!("{} {:>8}  {}", if status.chapter == Some(i) { "?" } else { " " }, time, chapter.title);
+            if status.chapter == Some(i) {
+                Line::styled(text, theme.highlight_style())
+            } else {
+                Line::raw(text)
+            }
+        })
+        .collect()
+}
+
+fn render_visualization(frame: &mut Frame, area: Rect, style: VisualStyle, visual: &Visualization, theme: &ThemeStyle) {
+    let meter_area = if style == VisualStyle::Spectrum {
+        let split = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(SPECTRUM_HEIGHT), Constraint::Length(METER_HEIGHT)])
+            .split(area);
+        frame.render_widget(Paragraph::new(spectrum_lines(&visual.bands, split[0])).style(theme.gauge_style()), split[0]);
+        split[1]
+    } else {
+        area
+    };
+
+    // Label and dB readout take 12 columns
+    let width = meter_area.width.saturating_sub(12) as usize;
+    let lines: Vec<Line> = ["L", "R"]
+        .iter()
+        .enumerate()
+        .map(|(channel, label)| {
+            let (peak, rms) = (visual.peak[channel], visual.rms[channel]);
+            let position = |db: f32| ((1.0 - db / FLOOR_DB).clamp(0.0, 1.0) * width as f32) as usize;
+            let filled = position(rms);
+            let peak_at = position(peak).max(filled);
+
+            let mut bar = "█".repeat(filled);
+            bar.push_str(&" ".repeat(peak_at - filled));
+            if peak > FLOOR_DB && peak_at < width {
+                bar.push('▏');
+            }
+            let readout = if peak > FLOOR_DB { format!("{:6.1} dB", peak) } else { "   -∞ dB".to_string() };
+            let color = if peak > CLIP_DB { Color::Red } else { theme.theme.gauge_color() };
+
+            Line::from(vec![
+                Span::raw(format!("{} ", label)),
+                Span::styled(format!("{:<width$}", bar, width = width), Style::default().fg(color)),
+                Span::raw(format!(" {}", readout)),
+            ])
+        })
+        .collect();
+    frame.render_widget(Paragraph::new(lines), meter_area);
+}
+
+/// Draws the bands as columns of block characters, with eighths at the top.
+fn spectrum_lines(bands: &[f32], area: Rect) -> Vec<Line<'static>> {
+    const PARTIAL: [char; 8] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇'];
+
+    if bands.is_empty() {
+        return Vec::new();
+    }
+    let column_width = (area.width as usize / bands.len()).max(1);
+    let bar_width = column_width.saturating_sub(1).max(1);
+    let height = area.height as usize;
+
+    (0..height)
+        .map(|row| {
+            // Eighths of a row filled below this one
+            let below = (height - 1 - row) * 8;
+            let line: String = bands
+                .iter()
+                .take(area.width as usize / column_width)
+                .map(|band| {
+                    let eighths = (band.clamp(0.0, 1.0) * (height * 8) as f32) as usize;
+                    let cell = match eighths.saturating_sub(below) {
+                        0 => ' ',
+                        filled if filled >= 8 => '█',
+                        filled => PARTIAL[filled],
+                    };
+                    let mut column = cell.to_string().repeat(bar_width);
+                    column.push_str(&" ".repeat(column_width - bar_width));
+                    column
+                })
+                .collect();
+            Line::from(line)
+        })
+        .collect()
+}
+
+fn ui_equalizer(frame: &mut Frame, status: &PlayerStatus, eq: Option<&EqStatus>, selected: usize, theme: &ThemeStyle) {
+    let size = frame.area();
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0), Constraint::Length(3)])
+        .split(size);
+
+    let Some(eq) = eq else {
+        frame.render_widget(
+            Paragraph::new("Equalizer unavailable - is the daemon running?")
+                .block(Block::default().borders(Borders::ALL).title("Equalizer").style(theme.status_style())),
+            chunks[0]
+        );
+        return;
+    };
+
+    let track_name = track_name(status).unwrap_or("No track");
+    let header = format!(
+        "{} | Preset: {} | {}",
+        if eq.enabled { "ON" } else { "OFF (bypassed)" },
+        eq.preset.as_deref().unwrap_or("custom"),
+        track_name
+    );
+    frame.render_widget(
+        Paragraph::new(header)
+            .block(Block::default().borders(Borders::ALL).title("Equalizer").style(theme.status_style())),
+        chunks[0]
+    );
+
+    // Horizontal slider per band, centered on 0 dB
+    let half = 12;
+    let items: Vec<ListItem> = eq.bands
+        .iter()
+        .map(|band| {
+            let steps = ((band.gain / EQ_RANGE) * half as f32).round() as i32;
+            let bar: String = (-half..=half)
+                .map(|i| match i {
+                    0 => '|',
+                    i if (steps < 0 && i >= steps && i < 0) || (steps > 0 && i <= steps && i > 0) => '#',
+                    _ => '-',
+                })
+                .collect();
+            ListItem::new(format!("{:>6}  {}  {:+5.1} dB", band.label, bar, band.gain))
+        })
+        .collect();
+
+    let bands = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Bands").style(theme.playlist_style()))
+        .highlight_style(theme.highlight_style())
+        .highlight_symbol("> ");
+
+    let mut state = ListState::default();
+    state.select(Some(selected.min(eq.bands.len().saturating_sub(1))));
+    frame.render_stateful_widget(bands, chunks[1], &mut state);
+
+    let help_text = "[?/?/h/l] Band | [?/?/k/j] Gain | [0] Reset band | [P] Next preset | [O] On/Off | [Space] Play/Pause | [E/Q] Back";
+    frame.render_widget(
+        Paragraph::new(help_text)
+            .wrap(Wrap { trim: true })
+            .block(Block::default().borders(Borders::ALL).title("Controls").style(Style::default().fg(Color::Magenta))),
+        chunks[2]
+    );
+}
+
+fn ui_devices(frame: &mut Frame, devices: Option<&OutputDeviceList>, selected: usize, theme: &ThemeStyle) {
+    let size = frame.area();
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0), Constraint::Length(3)])
+        .split(size);
+
+    let Some(list) = devices else {
+        frame.render_widget(
+            Paragraph::new("Output devices unavailable - is the daemon running?")
+                .block(Block::default().borders(Borders::ALL).title("Output").style(theme.status_style())),
+            chunks[0]
+        );
+        return;
+    };
+
+    let current = list.selected.as_deref().unwrap_or("System default");
+    frame.render_widget(
+        Paragraph::new(format!("Playing on: {}", current))
+            .block(Block::default().borders(Borders::ALL).title("Output").style(theme.status_style())),
+        chunks[0]
+    );
+
+    let in_use = selected_device_row(list);
+    let default = format!("System default ({})", list.default.as_deref().unwrap_or("unknown"));
+    let items: Vec<ListItem> = std::iter::once(default.as_str())
+        .chain(list.devices.iter().map(String::as_str))
+        .enumerate()
+        .map(|(i, name)| {
+            let marker = if i == in_use { "* " } else { "  " };
+            ListItem::new(format!("{}{}", marker, name))
+        })
+        .collect();
+
+    let device_list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Devices").style(theme.playlist_style()))
+        .highlight_style(theme.highlight_style())
+        .highlight_symbol("> ");
+
+    let mut state = ListState::default();
+    state.select(Some(selected.min(list.devices.len())));
+    frame.render_stateful_widget(device_list, chunks[1], &mut state);
+
+    let help_text = "[?/?/k/j] Select | [Enter] Switch | [O/Q] Back";
+    frame.render_widget(
+        Paragraph::new(help_text)
+            .wrap(Wrap { trim: true })
+            .block(Block::default().borders(Borders::ALL).title("Controls").style(Style::default().fg(Color::Magenta))),
+        chunks[2]
+    );
+}
+
+fn ui_audio(frame: &mut Frame, status: &PlayerStatus, selected: usize, theme: &ThemeStyle) {
+    let size = frame.area();
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0), Constraint::Length(3)])
+        .split(size);
+
+    let channels = &status.channels;
+    frame.render_widget(
+        Paragraph::new("Output channel processing, applied to stereo tracks")
+            .block(Block::default().borders(Borders::ALL).title("Audio Settings").style(theme.status_style())),
+        chunks[0]
+    );
+
+    // Marker on a 21-position track from left to right
+    let position = ((channels.balance + 1.0) * 10.0).round().clamp(0.0, 20.0) as usize;
+    let track: String = (0..=20).map(|i| if i == position { '|' } else { '-' }).collect();
+    let balance = match channels.balance {
+        b if b < 0.0 => format!("L {:.0}%", -b * 100.0),
+        b if b > 0.0 => format!("R {:.0}%", b * 100.0),
+        _ => "Center".to_string(),
+    };
+    let on_off = |enabled: bool| if enabled { "On" } else { "Off" };
+
+    let items = vec![
+        ListItem::new(format!("Balance       L {} R   {}", track, balance)),
+        ListItem::new(format!("Mono          {}", on_off(channels.mono))),
+        ListItem::new(format!("Swap L/R      {}", on_off(channels.swap))),
+    ];
+    let settings = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Channels").style(theme.playlist_style()))
+        .highlight_style(theme.highlight_style())
+        .highlight_symbol("> ");
+
+    let mut state = ListState::default();
+    state.select(Some(selected.min(AUDIO_ROWS - 1)));
+    frame.render_stateful_widget(settings, chunks[1], &mut state);
+
+    let help_text = "[↑/↓/k/j] Select | [←/→/h/l] Adjust | [Enter] Toggle/Center | [0] Center | [C/Q] Back";
+    frame.render_widget(
+        Paragraph::new(help_text)
+            .wrap(Wrap { trim: true })
+            .block(Block::default().borders(Borders::ALL).title("Controls").style(Style::default().fg(Color::Magenta))),
+        chunks[2]
+    );
+}
+
 fn ui_file_browser(frame: &mut Frame, status: &PlayerStatus, browser: &FileBrowser, theme: &ThemeStyle) {
     let size = frame.area();
 
@@ -793,19 +1515,11 @@ fn ui_file_browser(frame: &mut Frame, status: &PlayerStatus, browser: &FileBrows
         PlaybackState::Stopped => "? STOPPED",
     };
     
-    let track_name = status.current_track
-        .as_ref()
-        .map(|t| {
-            std::path::Path::new(t)
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or(t)
-        })
-        .unwrap_or("No track");
+    let track_name = track_name(status).unwrap_or("No track");
 
     frame.render_widget(
-        Paragraph::new(format!("{}\n{}\nVolume: {}%\nTracks: {}",
-            state_text, track_name, status.volume, status.playlist_length))
+        Paragraph::new(format!("{}\n{}\nVolume: {}\nTracks: {}",
+            state_text, track_name, volume_text(status), status.playlist_length))
             .wrap(Wrap { trim: true })
             .block(Block::default().borders(Borders::ALL).title("Player Status").style(theme.controls_style())),
         right_chunks[0]
@@ -857,7 +1571,7 @@ pub async fn run_tui() -> Result<()> {
             std::thread::spawn(move || {
                 let rt = tokio::runtime::Runtime::new().unwrap();
                 rt.block_on(async {
-                    if let Err(e) = crate::daemon::start().await {
+                    if let Err(e) = crate::daemon::start(None).await {
                         // Failed to start daemon
                     }
                 });
@@ -0,0 +1,213 @@
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::dsp;
+use crate::ipc::Visualization;
+
+/// Bars in the spectrum, log-spaced between the two frequencies below
+pub const SPECTRUM_BANDS: usize = 32;
+const MIN_FREQUENCY: f32 = 40.0;
+const MAX_FREQUENCY: f32 = 16000.0;
+
+/// Levels at or below this count as silence
+pub const FLOOR_DB: f32 = -70.0;
+
+// Frames kept for analysis, a power of two for the FFT
+const WINDOW: usize = 2048;
+
+// The level meter looks at the most recent 50 ms
+const METER_WINDOW: Duration = Duration::from_millis(50);
+
+/// The last `WINDOW` frames sent to the output, as left/right pairs. The
+/// audio thread writes without ever waiting; a reader racing it may see a
+/// few frames from the next round, which a display won't mind.
+pub struct SampleTap {
+    frames: Box<[AtomicU32]>,
+    written: AtomicUsize,
+    sample_rate: AtomicU32,
+}
+
+impl Default for SampleTap {
+    fn default() -> Self {
+        Self {
+            frames: (0..WINDOW).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            sample_rate: AtomicU32::new(44100),
+        }
+    }
+}
+
+impl SampleTap {
+    fn push(&self, left: i16, right: i16) {
+        let index = self.written.fetch_add(1, Ordering::AcqRel);
+        let packed = ((left as u16 as u32) << 16) | right as u16 as u32;
+        self.frames[index % WINDOW].store(packed, Ordering::Relaxed);
+    }
+
+    /// The buffered frames, oldest first, scaled to -1.0..1.0
+    fn frames(&self) -> Vec<[f32; 2]> {
+        let written = self.written.load(Ordering::Acquire);
+        (written..written + WINDOW)
+            .map(|index| {
+                let packed = self.frames[index % WINDOW].load(Ordering::Relaxed);
+                let left = (packed >> 16) as u16 as i16;
+                let right = packed as u16 as i16;
+                [left as f32 / 32768.0, right as f32 / 32768.0]
+            })
+            .collect()
+    }
+
+    /// Spectrum and levels of what was played last.
+    pub fn analyze(&self) -> Visualization {
+        let frames = self.frames();
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed).max(1) as f32;
+
+        // Level meter: peak and RMS per channel over the most recent frames
+        let recent = ((METER_WINDOW.as_secs_f32() * sample_rate) as usize).clamp(1, WINDOW);
+        let mut peak = [0.0f32; 2];
+        let mut power = [0.0f32; 2];
+        for frame in &frames[WINDOW - recent..] {
+            for channel in 0..2 {
+                peak[channel] = peak[channel].max(frame[channel].abs());
+                power[channel] += frame[channel] * frame[channel];
+            }
+        }
+        let rms = power.map(|p| (p / recent as f32).sqrt());
+
+        // Spectrum of the mid signal through a Hann window
+        let mut re: Vec<f32> = frames
+            .iter()
+            .enumerate()
+            .map(|(n, frame)| {
+                let window = 0.5 - 0.5 * (2.0 * std::f32::consts::PI * n as f32 / WINDOW as f32).cos();
+                (frame[0] + frame[1]) / 2.0 * window
+            })
+            .collect();
+        let mut im = vec![0.0; WINDOW];
+        dsp::fft(&mut re, &mut im);
+
+        // Amplitude relative to a full-scale sine, the window taking half
+        let magnitude = |bin: usize| (re[bin] * re[bin] + im[bin] * im[bin]).sqrt() * 4.0 / WINDOW as f32;
+        let bin_width = sample_rate / WINDOW as f32;
+        let ratio = (MAX_FREQUENCY / MIN_FREQUENCY).powf(1.0 / SPECTRUM_BANDS as f32);
+        let bands = (0..SPECTRUM_BANDS)
+            .map(|band| {
+                let low = MIN_FREQUENCY * ratio.powi(band as i32);
+                let first = ((low / bin_width) as usize).clamp(1, WINDOW / 2 - 1);
+                let last = ((low * ratio / bin_width) as usize).clamp(first, WINDOW / 2 - 1);
+                let amplitude = (first..=last).map(magnitude).fold(0.0, f32::max);
+                normalize(to_db(amplitude))
+            })
+            .collect();
+
+        Visualization {
+            bands,
+            peak: peak.map(to_db),
+            rms: rms.map(to_db),
+        }
+    }
+}
+
+fn to_db(amplitude: f32) -> f32 {
+    (20.0 * amplitude.max(1e-9).log10()).max(FLOOR_DB)
+}
+
+/// Maps FLOOR_DB..0 dB onto 0.0..1.0
+fn normalize(db: f32) -> f32 {
+    ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0)
+}
+
+impl Visualization {
+    pub fn silent() -> Self {
+        Self {
+            bands: vec![0.0; SPECTRUM_BANDS],
+            peak: [FLOOR_DB; 2],
+            rms: [FLOOR_DB; 2],
+        }
+    }
+}
+
+/// Copies every frame passing through into a `SampleTap`. Mono is shown
+/// on both sides; channels beyond the first two are left out.
+pub struct Tap<S> {
+    inner: S,
+    tap: Arc<SampleTap>,
+    channels: usize,
+    channel: usize,
+    frame_left: usize,
+    left: i16,
+}
+
+impl<S: Source<Item = i16>> Tap<S> {
+    pub fn new(inner: S, tap: Arc<SampleTap>) -> Self {
+        Self {
+            inner,
+            tap,
+            channels: 0,
+            channel: 0,
+            frame_left: 0,
+            left: 0,
+        }
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Tap<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.frame_left == 0 {
+            // Sources without frames never change format
+            self.frame_left = self.inner.current_frame_len().unwrap_or(usize::MAX).max(1);
+            self.channels = self.inner.channels().max(1) as usize;
+            self.channel = 0;
+            self.tap.sample_rate.store(self.inner.sample_rate(), Ordering::Relaxed);
+        }
+        self.frame_left -= 1;
+
+        let sample = self.inner.next()?;
+        match self.channel {
+            0 => self.left = sample,
+            1 => self.tap.push(self.left, sample),
+            _ => {}
+        }
+        if self.channels == 1 {
+            self.tap.push(sample, sample);
+        }
+        self.channel = (self.channel + 1) % self.channels;
+
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Tap<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        // Frames start over at the new position
+        self.frame_left = 0;
+        Ok(())
+    }
+}
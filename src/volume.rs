@@ -0,0 +1,189 @@
+use rodio::source::SeekError;
+use rodio::Source;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Highest volume level, reachable with boost enabled
+pub const MAX_VOLUME: u8 = 150;
+
+/// Highest level without boost, 0 dB
+pub const UNITY_VOLUME: u8 = 100;
+
+// Range covered by levels 1..=100, level 1 being this far below 0 dB
+const RANGE_DB: f32 = 40.0;
+
+// Gain at MAX_VOLUME
+const BOOST_DB: f32 = 12.0;
+
+// The limiter keeps peaks below this, a little under full scale
+const LIMIT: f32 = 0.95 * i16::MAX as f32;
+
+// Time for the limiter to recover after a peak, and for gain changes to settle
+const RELEASE: Duration = Duration::from_millis(100);
+const SMOOTHING: Duration = Duration::from_millis(10);
+
+/// Gain in dB for a volume level, `None` for silence. Levels up to 100 span
+/// `RANGE_DB` evenly, so every step sounds about the same; levels above that
+/// boost up to `BOOST_DB`.
+pub fn level_to_db(level: u8) -> Option<f32> {
+    match level.min(MAX_VOLUME) {
+        0 => None,
+        level if level <= UNITY_VOLUME => Some((level as f32 / UNITY_VOLUME as f32 - 1.0) * RANGE_DB),
+        level => Some((level - UNITY_VOLUME) as f32 / (MAX_VOLUME - UNITY_VOLUME) as f32 * BOOST_DB),
+    }
+}
+
+/// Linear amplitude for a volume level
+pub fn level_to_gain(level: u8) -> f32 {
+    level_to_db(level).map_or(0.0, |db| 10f32.powf(db / 20.0))
+}
+
+/// Output gain shared between the `Player` and the audio thread.
+#[derive(Debug)]
+pub struct VolumeControl {
+    gain: AtomicU32,
+}
+
+impl VolumeControl {
+    pub fn new(gain: f32) -> Self {
+        Self {
+            gain: AtomicU32::new(gain.to_bits()),
+        }
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, gain: f32) {
+        self.gain.store(gain.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Applies the output volume. Gains above 1.0 go through a peak limiter
+/// (instant attack, smooth release) instead of clipping.
+pub struct Volume<S> {
+    inner: S,
+    control: Arc<VolumeControl>,
+    // Gain being applied, gliding towards the control's
+    gain: f32,
+    // Limiter gain reduction, 1.0 when idle
+    envelope: f32,
+    // Per-sample coefficients for the current format
+    release: f32,
+    smoothing: f32,
+    samples_per_second: u32,
+}
+
+impl<S: Source<Item = i16>> Volume<S> {
+    pub fn new(inner: S, control: Arc<VolumeControl>) -> Self {
+        Self {
+            gain: control.get(),
+            inner,
+            control,
+            envelope: 1.0,
+            release: 0.0,
+            smoothing: 0.0,
+            samples_per_second: 0,
+        }
+    }
+
+    fn update_coefficients(&mut self) {
+        let samples_per_second = self.inner.sample_rate() * self.inner.channels() as u32;
+        if samples_per_second == self.samples_per_second {
+            return;
+        }
+        self.samples_per_second = samples_per_second;
+        let coefficient = |time: Duration| (-1.0 / (time.as_secs_f32() * samples_per_second.max(1) as f32)).exp();
+        self.release = coefficient(RELEASE);
+        self.smoothing = coefficient(SMOOTHING);
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Volume<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.inner.next()?;
+        self.update_coefficients();
+
+        let target = self.control.get();
+        self.gain = target + (self.gain - target) * self.smoothing;
+        let amplified = sample as f32 * self.gain;
+
+        // Recover towards no reduction, but never let a boosted peak through
+        self.envelope = 1.0 - (1.0 - self.envelope) * self.release;
+        let peak = amplified.abs();
+        if self.gain > 1.0 && peak * self.envelope > LIMIT {
+            self.envelope = LIMIT / peak;
+        }
+
+        Some((amplified * self.envelope).clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Volume<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn levels_map_to_the_documented_gains() {
+        assert_eq!(level_to_db(0), None);
+        assert_eq!(level_to_gain(0), 0.0);
+        assert_eq!(level_to_db(UNITY_VOLUME), Some(0.0));
+        assert_eq!(level_to_gain(UNITY_VOLUME), 1.0);
+        assert_eq!(level_to_db(MAX_VOLUME), Some(BOOST_DB));
+        assert_eq!(level_to_db(1), Some(-RANGE_DB + RANGE_DB / 100.0));
+        // Out of range levels stop at the boost limit
+        assert_eq!(level_to_db(u8::MAX), Some(BOOST_DB));
+    }
+
+    #[test]
+    fn unity_gain_passes_samples_through() {
+        let samples: Vec<i16> = (0..4410).map(|i| ((i * 37) % 65536 - 32768) as i16).collect();
+        let control = Arc::new(VolumeControl::new(1.0));
+        let output: Vec<i16> = Volume::new(SamplesBuffer::new(2, 44100, samples.clone()), control).collect();
+        assert_eq!(output, samples);
+    }
+
+    #[test]
+    fn boost_is_limited_below_full_scale() {
+        // Full scale square wave, boosted by 12 dB
+        let samples: Vec<i16> = (0..44100).map(|i| if i / 50 % 2 == 0 { i16::MAX } else { -i16::MAX }).collect();
+        let control = Arc::new(VolumeControl::new(level_to_gain(MAX_VOLUME)));
+        let output: Vec<i16> = Volume::new(SamplesBuffer::new(1, 44100, samples), control).collect();
+
+        let peak = output.iter().map(|sample| (*sample as i32).abs()).max().unwrap();
+        assert!(peak as f32 <= LIMIT, "peak {} above the limit", peak);
+        assert!(peak as f32 > LIMIT - 2.0, "peak {} held too far down", peak);
+    }
+}